@@ -0,0 +1,123 @@
+use std::time::Duration;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// How reconnection attempts are spaced out. The delay grows by `multiplier` after every failed attempt up to
+/// `max_delay_ms`, then a random part of it, up to `jitter`, is taken off so a fleet of gateways doesn't reconnect in
+/// lockstep
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ReconnectPolicy {
+    #[serde(default = "default_initial_delay_ms")]
+    pub initial_delay_ms: u64,
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u64,
+    #[serde(default = "default_multiplier")]
+    pub multiplier: f64,
+    /// 0 is no jitter, 1 is anywhere between no delay and the full delay
+    #[serde(default = "default_jitter")]
+    pub jitter: f64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay_ms: default_initial_delay_ms(),
+            max_delay_ms: default_max_delay_ms(),
+            multiplier: default_multiplier(),
+            jitter: default_jitter(),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    pub fn backoff(&self) -> Backoff {
+        Backoff {
+            policy: self.clone(),
+            attempt: 0,
+        }
+    }
+}
+
+/// The delays between the attempts of one reconnection
+pub struct Backoff {
+    policy: ReconnectPolicy,
+    attempt: u32,
+}
+
+impl Backoff {
+    /// How long to wait before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let ReconnectPolicy {
+            initial_delay_ms,
+            max_delay_ms,
+            multiplier,
+            jitter,
+        } = self.policy;
+        let delay_ms = (initial_delay_ms as f64 * multiplier.max(1.0).powi(self.attempt as i32))
+            .min(max_delay_ms as f64);
+        let jitter_ms = delay_ms * jitter.clamp(0.0, 1.0) * rand::thread_rng().gen::<f64>();
+        self.attempt = self.attempt.saturating_add(1);
+
+        Duration::from_millis((delay_ms - jitter_ms) as u64)
+    }
+
+    /// Attempts made since the last [Backoff::reset]
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// After a successful connection, the next delay is the initial one again
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+fn default_initial_delay_ms() -> u64 {
+    1000
+}
+
+fn default_max_delay_ms() -> u64 {
+    60_000
+}
+
+fn default_multiplier() -> f64 {
+    2.0
+}
+
+fn default_jitter() -> f64 {
+    0.5
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grows_to_max_with_jitter() {
+        let policy = ReconnectPolicy {
+            initial_delay_ms: 100,
+            max_delay_ms: 1000,
+            multiplier: 2.0,
+            jitter: 0.0,
+        };
+        let mut backoff = policy.backoff();
+        let delays: Vec<u64> = (0..6)
+            .map(|_| backoff.next_delay().as_millis() as u64)
+            .collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+
+        let mut backoff = ReconnectPolicy {
+            jitter: 0.5,
+            ..policy
+        }
+        .backoff();
+        for _ in 0..20 {
+            let delay = backoff.next_delay().as_millis();
+            assert!((50..=100).contains(&delay), "{delay}");
+            backoff.reset();
+        }
+    }
+}
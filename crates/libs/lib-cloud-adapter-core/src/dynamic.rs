@@ -0,0 +1,120 @@
+use std::{future::Future, pin::Pin};
+
+use async_trait::async_trait;
+use data_source_core::{MsgBusData, MsgId};
+use tokio::sync::{broadcast, watch};
+
+use crate::{
+    CloudAdapterTrait, Command, ConnectionError, ConnectionLost, DeliveryError, TokenConnection,
+    TokenDelivery,
+};
+
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send + 'static>>;
+
+/// Object-safe companion of [CloudAdapterTrait], with the tokens boxed. Every adapter has it, so adapters with
+/// different token types can be kept together as a [BoxCloudAdapter]
+pub trait DynCloudAdapter: Send {
+    fn publish_boxed(&mut self, msg: MsgBusData) -> BoxDeliveryToken;
+    #[allow(clippy::type_complexity)]
+    fn connect_boxed(
+        &mut self,
+    ) -> Pin<
+        Box<
+            dyn Future<
+                    Output = Result<
+                        TokenConnection<
+                            BoxFuture<Result<watch::Receiver<ConnectionLost>, ConnectionError>>,
+                        >,
+                        ConnectionError,
+                    >,
+                > + '_,
+        >,
+    >;
+    fn disconnect_boxed(
+        &mut self,
+    ) -> Result<BoxFuture<Result<(), ConnectionError>>, ConnectionError>;
+    fn commands_boxed(&self) -> Option<broadcast::Receiver<Command>>;
+}
+
+/// Any adapter, picked at runtime
+pub type BoxCloudAdapter = Box<dyn DynCloudAdapter>;
+
+pub struct BoxDeliveryToken(BoxFuture<Result<MsgId, DeliveryError>>);
+
+#[async_trait]
+impl TokenDelivery for BoxDeliveryToken {
+    async fn wait_for_ack(self) -> Result<MsgId, DeliveryError> {
+        self.0.await
+    }
+}
+
+impl<T> DynCloudAdapter for T
+where
+    T: CloudAdapterTrait + Send,
+{
+    fn publish_boxed(&mut self, msg: MsgBusData) -> BoxDeliveryToken {
+        BoxDeliveryToken(self.publish(msg).wait_for_ack())
+    }
+
+    fn connect_boxed(
+        &mut self,
+    ) -> Pin<
+        Box<
+            dyn Future<
+                    Output = Result<
+                        TokenConnection<
+                            BoxFuture<Result<watch::Receiver<ConnectionLost>, ConnectionError>>,
+                        >,
+                        ConnectionError,
+                    >,
+                > + '_,
+        >,
+    > {
+        Box::pin(async move {
+            let token = self.connect().await?;
+            let future: BoxFuture<_> = Box::pin(token);
+            Ok(TokenConnection { future })
+        })
+    }
+
+    fn disconnect_boxed(
+        &mut self,
+    ) -> Result<BoxFuture<Result<(), ConnectionError>>, ConnectionError> {
+        let future: BoxFuture<_> = Box::pin(self.disconnect()?);
+        Ok(future)
+    }
+
+    fn commands_boxed(&self) -> Option<broadcast::Receiver<Command>> {
+        self.commands()
+    }
+}
+
+impl CloudAdapterTrait for BoxCloudAdapter {
+    fn publish(&mut self, msg: MsgBusData) -> impl TokenDelivery + Send + 'static {
+        (**self).publish_boxed(msg)
+    }
+
+    async fn connect(
+        &mut self,
+    ) -> Result<
+        TokenConnection<
+            impl Future<Output = Result<watch::Receiver<ConnectionLost>, ConnectionError>>
+                + Send
+                + 'static,
+        >,
+        ConnectionError,
+    > {
+        (**self).connect_boxed().await
+    }
+
+    fn disconnect(
+        &mut self,
+    ) -> Result<impl Future<Output = Result<(), ConnectionError>> + Send + 'static, ConnectionError>
+    {
+        (**self).disconnect_boxed()
+    }
+
+    fn commands(&self) -> Option<broadcast::Receiver<Command>> {
+        (**self).commands_boxed()
+    }
+}
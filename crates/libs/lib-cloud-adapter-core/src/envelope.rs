@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use data_source_core::{MsgBusData, MsgId};
+use serde::Serialize;
+use serde_json::Value;
+
+/// A msg and its context as one json object, for adapters that write msgs as json such as `webhook` and `file`
+#[derive(Serialize)]
+pub struct Envelope<'a> {
+    id: MsgId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topic: Option<&'a str>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    metadata: &'a HashMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<&'a str>,
+    /// When the data source took the msg in, milliseconds since the unix epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<u64>,
+    /// Json payloads are embedded as is, UTF-8 text as a string and anything else base64 encoded
+    payload: Value,
+    /// "base64" when the payload had to be encoded, binary payloads such as Sparkplug B would be corrupted as text
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_encoding: Option<&'static str>,
+}
+
+impl<'a> Envelope<'a> {
+    pub fn new(msg: &'a MsgBusData) -> Envelope<'a> {
+        let (payload, payload_encoding) = match serde_json::from_slice(&msg.payload) {
+            Ok(json) => (json, None),
+            Err(_) => match std::str::from_utf8(&msg.payload) {
+                Ok(text) => (Value::from(text), None),
+                Err(_) => (Value::from(STANDARD.encode(&msg.payload)), Some("base64")),
+            },
+        };
+        Envelope {
+            id: msg.id,
+            topic: msg.topic.as_deref(),
+            metadata: &msg.metadata,
+            source: msg.source.as_deref(),
+            timestamp: (msg.timestamp != 0).then_some(msg.timestamp),
+            payload,
+            payload_encoding,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data_source_core::Bytes;
+    use serde_json::json;
+
+    use super::*;
+
+    fn encoded(payload: &'static [u8]) -> Value {
+        let msg = MsgBusData {
+            id: 1,
+            payload: Bytes::from_static(payload),
+            ..Default::default()
+        };
+        serde_json::to_value(Envelope::new(&msg)).unwrap()
+    }
+
+    #[test]
+    fn payload_kept_intact() {
+        assert_eq!(
+            encoded(br#"{"a":1}"#),
+            json!({"id": 1, "payload": {"a": 1}})
+        );
+        assert_eq!(encoded(b"temp=20"), json!({"id": 1, "payload": "temp=20"}));
+        assert_eq!(
+            encoded(&[0x08, 0xff, 0xfe, 0x00]),
+            json!({"id": 1, "payload": "CP/+AA==", "payload_encoding": "base64"})
+        );
+    }
+}
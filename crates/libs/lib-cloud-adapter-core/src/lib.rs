@@ -1,10 +1,17 @@
-use std::future::Future;
+mod backoff;
+mod dynamic;
+mod envelope;
+use std::{future::Future, time::Duration};
 
 use async_trait::async_trait;
-use data_source_core::MsgBusData;
+use data_source_core::{MsgBusData, MsgId};
 use pin_project::pin_project;
 use thiserror::Error;
-use tokio::sync::watch;
+use tokio::sync::{broadcast, watch};
+
+pub use backoff::{Backoff, ReconnectPolicy};
+pub use dynamic::{BoxCloudAdapter, BoxDeliveryToken, BoxFuture, DynCloudAdapter};
+pub use envelope::Envelope;
 
 pub type DeliveryToken = Box<dyn Future<Output = Result<(), DeliveryError>>>;
 // Not possible to alias impl yet, but it's being worked on https://github.com/rust-lang/rust/issues/63063
@@ -18,8 +25,18 @@ pub enum Error {
 
 #[derive(Debug)]
 pub struct DeliveryError {
-    pub msg_id: u32,
-    pub reason: String,
+    pub msg_id: MsgId,
+    pub reason: DeliveryFailure,
+}
+
+#[derive(Debug, Error)]
+pub enum DeliveryFailure {
+    /// Refused by the cloud, or lost along with the connection
+    #[error("{0}")]
+    Nack(String),
+    /// No ack came in time
+    #[error("no ack within [{0:?}]")]
+    Timeout(Duration),
 }
 //pub enum DeliveryError {
 //Failure(u32, String), // (msg_id, reason)
@@ -43,6 +60,17 @@ pub enum ConnectionLost {
     Uncategorized(String),
 }
 
+/// A command received from the cloud
+#[derive(Clone, Debug)]
+pub enum Command {
+    /// Restart the bridge
+    Reboot,
+    /// Restart the bridge with its config fetched again
+    ReloadConfig,
+    /// For the data source, the msg topic is the name of the command
+    Southbound(MsgBusData),
+}
+
 pub struct Credentials {
     pub username: String,
     pub password: String,
@@ -70,12 +98,16 @@ pub trait CloudAdapterTrait {
     fn disconnect(
         &mut self,
     ) -> Result<impl Future<Output = Result<(), ConnectionError>> + Send + 'static, ConnectionError>;
+    /// Receives the commands the cloud sends from here on. None for adapters that don't receive commands
+    fn commands(&self) -> Option<broadcast::Receiver<Command>> {
+        None
+    }
 }
 
 #[async_trait]
 pub trait TokenDelivery {
     //fn wait_for_ack(self) -> impl std::future::Future<Output = Result<(), DeliveryError>> + Send;
-    async fn wait_for_ack(self) -> Result<u32, DeliveryError>;
+    async fn wait_for_ack(self) -> Result<MsgId, DeliveryError>;
 }
 
 #[pin_project]
@@ -5,20 +5,20 @@ use cloud_adapter_core::{
     CloudAdapterTrait, ConnectionError, ConnectionLost, TokenConnection, TokenDelivery,
     TokenDisconnect,
 };
-use data_source_core::MsgBusData;
+use data_source_core::{MsgBusData, MsgId};
 use tokio::{
     spawn,
-    sync::{broadcast, watch},
+    sync::watch,
     time::{sleep, Duration, Sleep},
 };
 use tracing::debug;
 
-pub struct Dev {
-    tx_conn_status: broadcast::Sender<bool>,
-}
+#[derive(Default)]
+pub struct Dev {}
 
 pub struct DeliverContext {
-    id: String,
+    /// Id of the published msg, acked once `future` is done
+    msg_id: MsgId,
     future: Option<Sleep>,
 }
 
@@ -27,17 +27,16 @@ pub struct ConnectToken {
 }
 
 impl Dev {
-    pub fn new(tx_conn_status: broadcast::Sender<bool>) -> Self {
-        Self { tx_conn_status }
+    pub fn new() -> Self {
+        Self {}
     }
 }
 
 #[async_trait]
 impl TokenDelivery for DeliverContext {
-    async fn wait_for_ack(mut self) -> Result<u32, cloud_adapter_core::DeliveryError> {
-        let msg_id = self.future.take().unwrap().await;
-        let msg_id = 0;
-        Ok(msg_id)
+    async fn wait_for_ack(mut self) -> Result<MsgId, cloud_adapter_core::DeliveryError> {
+        self.future.take().unwrap().await;
+        Ok(self.msg_id)
     }
 }
 
@@ -61,7 +60,7 @@ impl CloudAdapterTrait for Dev {
         );
 
         DeliverContext {
-            id: "dev".to_string(),
+            msg_id: msg.id,
             future: Some(sleep(Duration::from_millis(100))),
         }
         //tokio::time::sleep(tokio::time::Duration::from_millis(100))
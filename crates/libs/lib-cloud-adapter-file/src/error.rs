@@ -0,0 +1,13 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum FileSinkError {
+    #[error("initialization: [{0}]")]
+    Init(String),
+}
+
+impl From<FileSinkError> for cloud_adapter_core::Error {
+    fn from(value: FileSinkError) -> Self {
+        cloud_adapter_core::Error::Initialization(value.to_string())
+    }
+}
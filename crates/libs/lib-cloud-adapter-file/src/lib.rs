@@ -0,0 +1,283 @@
+//! # File Sink Adapter
+//!
+//! For sites without connectivity. Msgs are written to rolling files on disk, to be collected by hand, and acked once
+//! they're synced to disk. Files are written with a `.part` suffix that's dropped when the file is rotated
+//!
+mod error;
+mod tokens;
+mod writer;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+pub use crate::tokens::DeliveryToken;
+use crate::writer::{Pending, Settings, Sink};
+pub use error::FileSinkError;
+
+use cloud_adapter_core::{
+    CloudAdapterTrait, ConnectionError, ConnectionLost, Error, TokenConnection, TokenDelivery,
+    TokenDisconnect,
+};
+use data_source_core::MsgBusData;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{oneshot, watch},
+    time::Duration,
+};
+use tracing::{debug, trace};
+
+pub struct FileSink {
+    /// Dropped first, which stops the writer
+    tx_pending: Option<mpsc::SyncSender<Pending>>,
+    /// Never sent on, the files are always there
+    tx_conn_lost: watch::Sender<ConnectionLost>,
+    writer_handle: Option<JoinHandle<()>>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Config {
+    /// Directory the files are written to
+    #[serde(default = "default_dir")]
+    pub dir: String,
+    #[serde(default = "default_file_prefix")]
+    pub file_prefix: String,
+    /// A file is rotated once it's grown to this size on disk
+    #[serde(default = "default_max_file_size_kb")]
+    pub max_file_size_kb: u64,
+    /// or once it's been open this long
+    pub max_file_age_secs: Option<u64>,
+    #[serde(default)]
+    pub gzip: bool,
+    #[serde(default)]
+    pub framing: Framing,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Framing {
+    /// One json object per line, `{"id", "topic", "metadata", "source", "timestamp", "payload"}`
+    #[default]
+    Ndjson,
+    /// The payload only, after its length as a big-endian u32
+    LengthPrefixed,
+}
+
+impl FileSink {
+    pub fn new(config: &str) -> Result<Self, Error> {
+        let config = serde_json::from_str::<Config>(config)
+            .map_err(|err| FileSinkError::Init(err.to_string()))?;
+        debug!("File sink writing to [{}]", config.dir);
+
+        let sink = Sink::new(Settings {
+            dir: PathBuf::from(config.dir),
+            file_prefix: config.file_prefix,
+            max_file_bytes: config.max_file_size_kb * 1024,
+            max_file_age: config.max_file_age_secs.map(Duration::from_secs),
+            gzip: config.gzip,
+            framing: config.framing,
+        })
+        .map_err(|err| FileSinkError::Init(err.to_string()))?;
+
+        let (tx_pending, rx_pending) = mpsc::sync_channel(1000);
+        let writer_handle = thread::Builder::new()
+            .name("file sink".to_string())
+            .spawn(move || writer::run(sink, rx_pending))
+            .map_err(|err| FileSinkError::Init(err.to_string()))?;
+        let (tx_conn_lost, _) = watch::channel(ConnectionLost::Uncategorized("init".to_string()));
+
+        Ok(Self {
+            tx_pending: Some(tx_pending),
+            tx_conn_lost,
+            writer_handle: Some(writer_handle),
+        })
+    }
+}
+
+impl CloudAdapterTrait for FileSink {
+    fn publish(&mut self, msg: MsgBusData) -> impl TokenDelivery + Send + 'static {
+        trace!("Queueing [{}] to be written", msg.id);
+        let msg_id = msg.id;
+        let (tx_ack, rx_ack) = oneshot::channel();
+        let ack = match &self.tx_pending {
+            Some(tx_pending) => tx_pending
+                .try_send(Pending { msg, tx_ack })
+                .map(|()| rx_ack)
+                .map_err(|err| format!("could not queue msg to be written: [{}]", err)),
+            None => Err("file sink is stopped".to_string()),
+        };
+
+        DeliveryToken { msg_id, ack }
+    }
+
+    /// Always succeeds, there's nothing to connect to
+    async fn connect(
+        &mut self,
+    ) -> Result<
+        TokenConnection<
+            impl Future<Output = Result<watch::Receiver<ConnectionLost>, ConnectionError>>
+                + Send
+                + 'static,
+        >,
+        ConnectionError,
+    > {
+        let rx_conn_lost = self.tx_conn_lost.subscribe();
+        let token = TokenConnection {
+            future: async move { Ok(rx_conn_lost) },
+        };
+
+        Ok(token)
+    }
+
+    fn disconnect(
+        &mut self,
+    ) -> Result<impl Future<Output = Result<(), ConnectionError>> + Send + 'static, ConnectionError>
+    {
+        let token = TokenDisconnect {
+            future: async move { Ok(()) },
+        };
+        Ok(token)
+    }
+}
+
+impl Drop for FileSink {
+    /// Waits for the writer to write what's queued and complete the current file
+    fn drop(&mut self) {
+        trace!("Dropping FileSink, waiting for the writer..");
+        self.tx_pending.take();
+        if let Some(writer_handle) = self.writer_handle.take() {
+            let _ = writer_handle.join();
+        }
+    }
+}
+
+fn default_dir() -> String {
+    "rolling-files".to_string()
+}
+
+fn default_file_prefix() -> String {
+    "msgs".to_string()
+}
+
+fn default_max_file_size_kb() -> u64 {
+    10 * 1024
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::Read,
+        path::Path,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use flate2::read::GzDecoder;
+
+    use data_source_core::{Bytes, MsgId};
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        std::env::temp_dir().join(format!("file-sink-{}-{}", name, nanos))
+    }
+
+    /// Names of the files in `dir`, sorted
+    fn files(dir: &Path) -> Vec<String> {
+        let mut files: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        files.sort();
+        files
+    }
+
+    fn msg(id: MsgId, payload: &[u8]) -> MsgBusData {
+        MsgBusData {
+            id,
+            payload: Bytes::copy_from_slice(payload),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn ndjson_rotates_by_size() {
+        let dir = temp_dir("ndjson");
+        let config = serde_json::json!({ "dir": dir, "max_file_size_kb": 1 });
+        let mut sink = FileSink::new(&config.to_string()).unwrap();
+        assert!(sink.connect().await.unwrap().await.is_ok());
+
+        // Each line is a little over 500 bytes, so every other ack rotates a file
+        let payload = format!("\"{}\"", "x".repeat(500));
+        for id in 0..3 {
+            let token = sink.publish(msg(id, payload.as_bytes()));
+            assert_eq!(token.wait_for_ack().await.unwrap(), id);
+        }
+        let written = files(&dir);
+        assert_eq!(written.len(), 2);
+        let complete = written
+            .iter()
+            .find(|file| file.ends_with(".ndjson"))
+            .unwrap();
+        assert!(written.iter().any(|file| file.ends_with(".ndjson.part")));
+
+        let lines = fs::read_to_string(dir.join(complete)).unwrap();
+        let first: serde_json::Value = serde_json::from_str(lines.lines().next().unwrap()).unwrap();
+        assert_eq!(first["id"], 0);
+        assert_eq!(first["payload"], "x".repeat(500));
+
+        // Dropping completes the file being written
+        drop(sink);
+        assert!(files(&dir).iter().all(|file| file.ends_with(".ndjson")));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn ndjson_binary_payload_as_base64() {
+        let dir = temp_dir("binary");
+        let config = serde_json::json!({ "dir": dir });
+        let mut sink = FileSink::new(&config.to_string()).unwrap();
+
+        // Not UTF-8, as Sparkplug B protobuf often isn't. These files may be the only copy of it
+        let payload = [0x08, 0xff, 0xfe, 0x00];
+        sink.publish(msg(1, &payload)).wait_for_ack().await.unwrap();
+        drop(sink);
+
+        let written = files(&dir);
+        let line = fs::read_to_string(dir.join(&written[0])).unwrap();
+        let envelope: serde_json::Value = serde_json::from_str(line.trim_end()).unwrap();
+        assert_eq!(envelope["payload_encoding"], "base64");
+        assert_eq!(envelope["payload"], "CP/+AA==");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn gzip_length_prefixed_is_readable_before_rotation() {
+        let dir = temp_dir("gzip");
+        let config = serde_json::json!({
+            "dir": dir,
+            "gzip": true,
+            "framing": "length-prefixed",
+        });
+        let mut sink = FileSink::new(&config.to_string()).unwrap();
+        for (id, payload) in [(1, &b"first"[..]), (2, &b"second"[..])] {
+            sink.publish(msg(id, payload)).wait_for_ack().await.unwrap();
+        }
+
+        // Acked means synced, the data is there even though the gzip isn't finished
+        let written = files(&dir);
+        assert_eq!(written.len(), 1);
+        assert!(written[0].ends_with(".bin.gz.part"));
+        let mut data = Vec::new();
+        let _ =
+            GzDecoder::new(fs::File::open(dir.join(&written[0])).unwrap()).read_to_end(&mut data);
+        assert_eq!(data, b"\0\0\0\x05first\0\0\0\x06second");
+
+        drop(sink);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
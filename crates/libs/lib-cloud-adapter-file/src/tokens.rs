@@ -0,0 +1,28 @@
+use async_trait::async_trait;
+use cloud_adapter_core::{DeliveryError, DeliveryFailure, TokenDelivery};
+use data_source_core::MsgId;
+use tokio::sync::oneshot;
+
+pub struct DeliveryToken {
+    pub msg_id: MsgId,
+    /// Err when the msg could not be queued for the writer. Otherwise resolves once the msg is synced to disk, or failed to be
+    pub ack: Result<oneshot::Receiver<Result<(), String>>, String>,
+}
+
+#[async_trait]
+impl TokenDelivery for DeliveryToken {
+    async fn wait_for_ack(self) -> Result<MsgId, DeliveryError> {
+        let msg_id = self.msg_id;
+        let nack = |reason| DeliveryError {
+            msg_id,
+            reason: DeliveryFailure::Nack(reason),
+        };
+        self.ack
+            .map_err(nack)?
+            .await
+            .map_err(|_| nack("writer stopped before the msg was written".to_string()))?
+            .map_err(nack)?;
+
+        Ok(msg_id)
+    }
+}
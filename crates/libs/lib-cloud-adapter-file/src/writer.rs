@@ -0,0 +1,292 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{Receiver, RecvTimeoutError},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use cloud_adapter_core::Envelope;
+use data_source_core::MsgBusData;
+use flate2::{write::GzEncoder, Compression};
+use tokio::sync::oneshot;
+use tracing::{debug, error, info, trace};
+
+use crate::Framing;
+
+/// Suffix of the file being written, it's dropped once the file is rotated so collectors know which files are complete
+pub const PART_SUFFIX: &str = ".part";
+/// Most msgs written and synced together
+const MAX_BATCH: usize = 1000;
+
+/// A msg waiting to be written
+pub struct Pending {
+    pub msg: MsgBusData,
+    pub tx_ack: oneshot::Sender<Result<(), String>>,
+}
+
+pub struct Settings {
+    pub dir: PathBuf,
+    pub file_prefix: String,
+    pub max_file_bytes: u64,
+    pub max_file_age: Option<Duration>,
+    pub gzip: bool,
+    pub framing: Framing,
+}
+
+enum Writer {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+struct OpenFile {
+    /// Where it's written, with the `.part` suffix
+    path: PathBuf,
+    writer: Writer,
+    opened_at: Instant,
+}
+
+/// Writes msgs to the current file, rotating it by size and age
+pub struct Sink {
+    settings: Settings,
+    file: Option<OpenFile>,
+}
+
+/// Runs on its own thread since file IO blocks. Every msg received is written, then synced, then acked
+pub fn run(mut sink: Sink, rx_pending: Receiver<Pending>) {
+    loop {
+        let first = match sink.rotate_in() {
+            Some(timeout) => rx_pending.recv_timeout(timeout),
+            None => rx_pending
+                .recv()
+                .map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let batch = match first {
+            Ok(first) => {
+                let mut batch = vec![first];
+                batch.extend(rx_pending.try_iter().take(MAX_BATCH - 1));
+                batch
+            }
+            // The file is old enough, don't leave it open until the next msg
+            Err(RecvTimeoutError::Timeout) => {
+                sink.rotate();
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        let result = sink.write(&batch).map_err(|err| {
+            error!("Could not write [{}] msgs to file. [{}]", batch.len(), err);
+            err.to_string()
+        });
+        for pending in batch {
+            let _ = pending.tx_ack.send(result.clone());
+        }
+    }
+
+    sink.rotate();
+    debug!("Exiting file sink writer");
+}
+
+impl Sink {
+    pub fn new(settings: Settings) -> io::Result<Sink> {
+        fs::create_dir_all(&settings.dir)?;
+        // A previous run stopped without rotating these, they hold everything that was acked
+        for entry in fs::read_dir(&settings.dir)? {
+            let path = entry?.path();
+            if let Some(complete) = path
+                .to_str()
+                .and_then(|path| path.strip_suffix(PART_SUFFIX))
+            {
+                info!(
+                    "Completing [{}] left behind by a previous run",
+                    path.display()
+                );
+                fs::rename(&path, complete)?;
+            }
+        }
+
+        Ok(Sink {
+            settings,
+            file: None,
+        })
+    }
+
+    fn write(&mut self, batch: &[Pending]) -> io::Result<()> {
+        if self.rotate_in() == Some(Duration::ZERO) {
+            self.rotate();
+        }
+        if self.file.is_none() {
+            self.file = Some(self.open()?);
+        }
+        let Some(file) = &mut self.file else {
+            unreachable!("opened above");
+        };
+
+        let result = write_batch(&mut file.writer, batch, self.settings.framing);
+        match result.and_then(|()| file.writer.sync()) {
+            Ok(()) => {
+                trace!("Synced [{}] msgs to [{}]", batch.len(), file.path.display());
+                if file.writer.len()? >= self.settings.max_file_bytes {
+                    self.rotate();
+                }
+                Ok(())
+            }
+            // Start over with a new file, whatever made it into this one is left as is
+            Err(err) => {
+                self.rotate();
+                Err(err)
+            }
+        }
+    }
+
+    /// How long until the current file is old enough to rotate
+    fn rotate_in(&self) -> Option<Duration> {
+        let file = self.file.as_ref()?;
+        let max_file_age = self.settings.max_file_age?;
+        Some(max_file_age.saturating_sub(file.opened_at.elapsed()))
+    }
+
+    /// Completes the current file, the next write opens a new one
+    fn rotate(&mut self) {
+        let Some(file) = self.file.take() else {
+            return;
+        };
+        let complete = file
+            .path
+            .to_str()
+            .and_then(|path| path.strip_suffix(PART_SUFFIX))
+            .map(PathBuf::from)
+            .unwrap_or_else(|| file.path.clone());
+        match file
+            .writer
+            .finish()
+            .and_then(|()| fs::rename(&file.path, &complete))
+        {
+            Ok(()) => info!("Rotated [{}]", complete.display()),
+            Err(err) => error!("Could not complete [{}]. [{}]", file.path.display(), err),
+        }
+    }
+
+    fn open(&self) -> io::Result<OpenFile> {
+        let extension = match (self.settings.framing, self.settings.gzip) {
+            (Framing::Ndjson, false) => "ndjson",
+            (Framing::Ndjson, true) => "ndjson.gz",
+            (Framing::LengthPrefixed, false) => "bin",
+            (Framing::LengthPrefixed, true) => "bin.gz",
+        };
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        // Rotating twice within a millisecond would reuse the name
+        let path = (0..1000)
+            .map(|count| {
+                let name = match count {
+                    0 => format!("{}-{}.{}", self.settings.file_prefix, millis, extension),
+                    count => format!(
+                        "{}-{}-{}.{}",
+                        self.settings.file_prefix, millis, count, extension
+                    ),
+                };
+                self.settings.dir.join(name)
+            })
+            .find(|path| !exists(path) && !exists(&part(path)))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::AlreadyExists, "no free file name"))?;
+        let path = part(&path);
+        debug!("Writing msgs to [{}]", path.display());
+
+        let file = BufWriter::new(File::create(&path)?);
+        let writer = match self.settings.gzip {
+            true => Writer::Gzip(GzEncoder::new(file, Compression::default())),
+            false => Writer::Plain(file),
+        };
+        Ok(OpenFile {
+            path,
+            writer,
+            opened_at: Instant::now(),
+        })
+    }
+}
+
+impl Writer {
+    /// Everything written so far is on disk once this returns. A gzip file is readable up to here, even if it's never finished
+    fn sync(&mut self) -> io::Result<()> {
+        match self {
+            Writer::Plain(file) => {
+                file.flush()?;
+                file.get_ref().sync_data()
+            }
+            Writer::Gzip(encoder) => {
+                encoder.flush()?;
+                encoder.get_ref().get_ref().sync_data()
+            }
+        }
+    }
+
+    /// Size on disk
+    fn len(&self) -> io::Result<u64> {
+        let file = match self {
+            Writer::Plain(file) => file.get_ref(),
+            Writer::Gzip(encoder) => encoder.get_ref().get_ref(),
+        };
+        Ok(file.metadata()?.len())
+    }
+
+    fn finish(self) -> io::Result<()> {
+        let file = match self {
+            Writer::Plain(file) => file,
+            Writer::Gzip(encoder) => encoder.finish()?,
+        };
+        file.into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()
+    }
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Writer::Plain(file) => file.write(buf),
+            Writer::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Writer::Plain(file) => file.flush(),
+            Writer::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// * NDJSON - one [Envelope] per line, `{"id", "topic", "metadata", "source", "timestamp", "payload", "payload_encoding"}`
+/// * Length prefixed - the payload only, after its length as a big-endian u32
+fn write_batch(writer: &mut Writer, batch: &[Pending], framing: Framing) -> io::Result<()> {
+    for Pending { msg, .. } in batch {
+        match framing {
+            Framing::Ndjson => {
+                serde_json::to_writer(&mut *writer, &Envelope::new(msg))?;
+                writer.write_all(b"\n")?;
+            }
+            Framing::LengthPrefixed => {
+                let len = u32::try_from(msg.payload.len()).map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "payload is over 4GB")
+                })?;
+                writer.write_all(&len.to_be_bytes())?;
+                writer.write_all(&msg.payload)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn part(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(PART_SUFFIX);
+    PathBuf::from(part)
+}
+
+fn exists(path: &Path) -> bool {
+    path.try_exists().unwrap_or(true)
+}
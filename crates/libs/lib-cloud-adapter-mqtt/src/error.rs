@@ -0,0 +1,15 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum MqttError {
+    #[error("initialization: [{0}]")]
+    Init(String),
+    #[error("tls: [{0}]")]
+    Tls(String),
+}
+
+impl From<MqttError> for cloud_adapter_core::Error {
+    fn from(value: MqttError) -> Self {
+        cloud_adapter_core::Error::Initialization(value.to_string())
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+
+use cloud_adapter_core::{Backoff, ConnectionLost};
+use rumqttc::{
+    ConnAck, ConnectReturnCode, Event, EventLoop, Outgoing, Packet, PubAck, PubComp, QoS,
+};
+use tokio::{
+    select, spawn,
+    sync::{broadcast, oneshot, watch},
+    task::JoinHandle,
+    time::sleep,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, trace, warn};
+
+use crate::PendingAcks;
+
+/// Polls the event_loop while `rx_run` is true, matching acks from the broker to the tokens of the msgs published
+/// * `tx_connect` - result of each connection attempt
+/// * `backoff` - how long to wait before polling again after the connection failed, polling reconnects
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_looper(
+    mut event_loop: EventLoop,
+    mut rx_run: watch::Receiver<bool>,
+    tx_connect: broadcast::Sender<Result<(), String>>,
+    pending_acks: PendingAcks,
+    qos: QoS,
+    tx_conn_lost: watch::Sender<ConnectionLost>,
+    mut backoff: Backoff,
+    shutdown: CancellationToken,
+) -> JoinHandle<EventLoop> {
+    spawn(async move {
+        // Tokens waiting on a PubAck (QoS 1) or PubComp (QoS 2), by packet id
+        let mut ack_map: HashMap<u16, oneshot::Sender<()>> = HashMap::new();
+        let mut connected = false;
+        // Set after a manual disconnect went out, the event_loop stays blocked until the next connect
+        let mut paused = false;
+
+        loop {
+            // The event_loop reconnects on its own as long as it's polled, so it's only polled while rusty-bridge wants a connection
+            if paused || !*rx_run.borrow_and_update() {
+                debug!("Blocking event_loop for disconnection");
+                select! {
+                    _ = shutdown.cancelled() => break,
+                    changed = rx_run.changed() => match changed {
+                        Ok(_) => paused = false,
+                        Err(_) => break,
+                    },
+                }
+                continue;
+            }
+
+            select! {
+                _ = shutdown.cancelled() => break,
+                changed = rx_run.changed() => if changed.is_err() {
+                    break
+                },
+                event = event_loop.poll() => match event {
+                    Ok(Event::Incoming(Packet::ConnAck(ConnAck { code, .. }))) => {
+                        connected = code == ConnectReturnCode::Success;
+                        if connected {
+                            backoff.reset();
+                        }
+                        let result = match code {
+                            ConnectReturnCode::Success => Ok(()),
+                            code => Err(format!("{:?} code: [{}]", code, code as u8)),
+                        };
+                        let _ = tx_connect.send(result);
+                    }
+                    Ok(Event::Incoming(Packet::PubAck(PubAck { pkid })))
+                    | Ok(Event::Incoming(Packet::PubComp(PubComp { pkid }))) => {
+                        match ack_map.remove(&pkid) {
+                            Some(tx_ack) => {
+                                if tx_ack.send(()).is_err() {
+                                    debug!("Ack for pkid [{}] arrived after its token was dropped", pkid)
+                                }
+                            }
+                            None => warn!("pkid [{}] was not in the ack map", pkid),
+                        }
+                    }
+                    Ok(Event::Outgoing(Outgoing::Publish(pkid))) => {
+                        // Publishes go out in the order they were queued, and so do their tokens
+                        let Some(tx_ack) = pending_acks.lock().expect("poisoned lock").pop_front() else {
+                            warn!("Publish [{}] went out without a token waiting on it", pkid);
+                            continue;
+                        };
+                        match qos {
+                            // Nothing more will come back from the broker, it's delivered as far as we can tell
+                            QoS::AtMostOnce => {
+                                let _ = tx_ack.send(());
+                            }
+                            QoS::AtLeastOnce | QoS::ExactlyOnce => {
+                                ack_map.insert(pkid, tx_ack);
+                            }
+                        }
+                    }
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                        info!("Disconnected from the broker");
+                        fail_in_flight(&mut event_loop, &mut ack_map, &pending_acks);
+                        connected = false;
+                        paused = true;
+                        tx_conn_lost.send_replace(ConnectionLost::ManualDisconnect);
+                    }
+                    Ok(event) => trace!("Event: [{:?}]", event),
+                    Err(err) => {
+                        fail_in_flight(&mut event_loop, &mut ack_map, &pending_acks);
+                        if connected {
+                            warn!("Connection lost: [{}]", err);
+                            connected = false;
+                            match err {
+                                rumqttc::ConnectionError::NetworkTimeout => {
+                                    tx_conn_lost.send_replace(ConnectionLost::Timeout)
+                                }
+                                err => tx_conn_lost
+                                    .send_replace(ConnectionLost::Uncategorized(err.to_string())),
+                            };
+                        } else {
+                            let _ = tx_connect.send(Err(err.to_string()));
+                        }
+                        sleep(backoff.next_delay()).await;
+                    }
+                }
+            }
+        }
+
+        event_loop
+    })
+}
+
+/// Drops every token waiting on an ack, which nacks their msgs. Nothing is resent by the event_loop after a reconnect,
+/// the nacked msgs are persisted and replayed by rusty-bridge instead, so the tokens can't get mixed up with resent publishes
+fn fail_in_flight(
+    event_loop: &mut EventLoop,
+    ack_map: &mut HashMap<u16, oneshot::Sender<()>>,
+    pending_acks: &PendingAcks,
+) {
+    ack_map.clear();
+    // Held while the requests are dropped, so no publish can be queued without its token in between
+    let mut pending_acks = pending_acks.lock().expect("poisoned lock");
+    event_loop.clean();
+    event_loop.pending.clear();
+    pending_acks.clear();
+}
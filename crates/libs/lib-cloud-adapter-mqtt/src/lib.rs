@@ -0,0 +1,491 @@
+//! # MQTT Adapter
+//!
+//! Publishes msgs to any MQTT 3.1.1 broker. The connection, authentication and the topic msgs are published to all
+//! come from the config, and the payload is published as is
+//!
+mod error;
+mod event_loop;
+mod tokens;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use crate::event_loop::spawn_looper;
+pub use crate::tokens::DeliveryToken;
+pub use error::MqttError;
+
+use cloud_adapter_core::{
+    CloudAdapterTrait, ConnectionError, ConnectionLost, Error, ReconnectPolicy, TokenConnection,
+    TokenDelivery, TokenDisconnect,
+};
+use data_source_core::MsgBusData;
+use rumqttc::{
+    tokio_rustls::rustls::{ClientConfig, RootCertStore},
+    AsyncClient, EventLoop, MqttOptions, QoS, TlsConfiguration, Transport,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{broadcast, oneshot, watch},
+    task::JoinHandle,
+    time::Duration,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace};
+
+/// Tokens of the msgs queued in the client, in the order they were queued. The event_loop pairs them with packet ids
+/// as the publishes go out
+type PendingAcks = Arc<Mutex<VecDeque<oneshot::Sender<()>>>>;
+
+pub struct Mqtt {
+    client: AsyncClient,
+    client_id: String,
+    topic: TopicTemplate,
+    qos: QoS,
+    retain: bool,
+    pending_acks: PendingAcks,
+    tx_connect: broadcast::Sender<Result<(), String>>,
+    rx_conn_lost: watch::Receiver<ConnectionLost>,
+    /// Blocks or unblocks the event_loop because event_loop will always reconnect
+    tx_run: watch::Sender<bool>,
+    event_handle: JoinHandle<EventLoop>,
+    /// To shutdown the event_loop
+    shutdown: CancellationToken,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Config {
+    pub host: String,
+    /// Defaults to 1883 for tcp, 8883 for tls and 443 for wss
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub transport: TransportKind,
+    /// Path of the websocket endpoint, only used by wss
+    #[serde(default = "default_ws_path")]
+    pub ws_path: String,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// PEM file of the CA to trust. The system's certificates are trusted when not set
+    pub ca_cert: Option<String>,
+    /// PEM files of the client certificate and its key, for brokers that authenticate with certificates
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    /// Topic msgs are published to. `{client_id}`, `{msg_id}`, `{topic}`, the topic the msg arrived on, and `{source}`,
+    /// the data source it came in through, are filled in, any other `{name}` with the msg's metadata of that name
+    #[serde(default = "default_topic")]
+    pub topic: String,
+    /// 0, 1 or 2. Msgs are acked once the broker has them, with 0 as soon as they are sent
+    #[serde(default = "default_qos")]
+    pub qos: u8,
+    #[serde(default)]
+    pub retain: bool,
+    #[serde(default = "default_keep_alive_secs")]
+    pub keep_alive_secs: u64,
+    /// Delays between reconnection attempts
+    #[serde(default)]
+    pub reconnect: ReconnectPolicy,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    #[default]
+    Tcp,
+    Tls,
+    Wss,
+}
+
+impl Mqtt {
+    pub fn new(config: &str) -> Result<Self, Error> {
+        let config = serde_json::from_str::<Config>(config)
+            .map_err(|err| MqttError::Init(err.to_string()))?;
+        let qos = rumqttc::qos(config.qos).map_err(|err| MqttError::Init(err.to_string()))?;
+        let topic = TopicTemplate::parse(&config.topic);
+
+        let port = config.port.unwrap_or(match config.transport {
+            TransportKind::Tcp => 1883,
+            TransportKind::Tls => 8883,
+            TransportKind::Wss => 443,
+        });
+        let mut mqtt_options = match config.transport {
+            TransportKind::Tcp | TransportKind::Tls => {
+                MqttOptions::new(&config.client_id, &config.host, port)
+            }
+            // The websocket transport connects to a url instead of a host
+            TransportKind::Wss => MqttOptions::new(
+                &config.client_id,
+                format!("wss://{}:{}{}", config.host, port, config.ws_path),
+                port,
+            ),
+        };
+        match config.transport {
+            TransportKind::Tcp => (),
+            TransportKind::Tls => {
+                mqtt_options.set_transport(Transport::Tls(tls(&config)?));
+            }
+            TransportKind::Wss => {
+                mqtt_options.set_transport(Transport::Wss(tls(&config)?));
+            }
+        };
+        if let Some(username) = &config.username {
+            mqtt_options.set_credentials(username, config.password.clone().unwrap_or_default());
+        }
+        mqtt_options.set_clean_session(true);
+        mqtt_options.set_keep_alive(Duration::from_secs(config.keep_alive_secs));
+        mqtt_options.set_manual_acks(false);
+        debug!(
+            "MQTT adapter for [{}:{}], publishing to [{}]",
+            config.host, port, config.topic
+        );
+
+        let (client, event_loop) = AsyncClient::new(mqtt_options, 100);
+        let pending_acks = PendingAcks::default();
+        let (tx_connect, _) = broadcast::channel(1);
+        let (tx_run, rx_run) = watch::channel(false);
+        let (tx_conn_lost, rx_conn_lost) =
+            watch::channel(ConnectionLost::Uncategorized("init".to_string()));
+
+        let shutdown = CancellationToken::new();
+        let event_handle = spawn_looper(
+            event_loop,
+            rx_run,
+            tx_connect.clone(),
+            pending_acks.clone(),
+            qos,
+            tx_conn_lost,
+            config.reconnect.backoff(),
+            shutdown.child_token(),
+        );
+
+        Ok(Self {
+            client,
+            client_id: config.client_id,
+            topic,
+            qos,
+            retain: config.retain,
+            pending_acks,
+            tx_connect,
+            rx_conn_lost,
+            tx_run,
+            event_handle,
+            shutdown,
+        })
+    }
+}
+
+impl CloudAdapterTrait for Mqtt {
+    fn publish(&mut self, msg: MsgBusData) -> impl TokenDelivery + Send + 'static {
+        trace!("Publishing [{}]", msg.id);
+        let topic = self.topic.render(&msg, &self.client_id);
+
+        // The token is queued while the lock is held, so tokens are in the same order as the publishes in the client
+        let (tx_ack, rx_ack) = oneshot::channel();
+        let mut pending_acks = self.pending_acks.lock().expect("poisoned lock");
+        let ack = match self
+            .client
+            .try_publish(topic, self.qos, self.retain, msg.payload)
+        {
+            Ok(()) => {
+                pending_acks.push_back(tx_ack);
+                Ok(rx_ack)
+            }
+            Err(err) => Err(format!("could not queue publish: [{}]", err)),
+        };
+
+        DeliveryToken {
+            msg_id: msg.id,
+            ack,
+        }
+    }
+
+    async fn connect(
+        &mut self,
+    ) -> Result<
+        TokenConnection<
+            impl Future<Output = Result<watch::Receiver<ConnectionLost>, ConnectionError>>
+                + Send
+                + 'static,
+        >,
+        ConnectionError,
+    > {
+        debug!("Connecting to the broker");
+        // Subscribe before unblocking the event_loop so the result can't be missed
+        let mut rx_connect = self.tx_connect.subscribe();
+        self.tx_run.send_replace(true);
+
+        let mut rx_conn_lost = self.rx_conn_lost.clone();
+        let token = TokenConnection {
+            future: async move {
+                rx_connect
+                    .recv()
+                    .await
+                    .map_err(|err| ConnectionError::Failure(err.to_string()))?
+                    .map_err(ConnectionError::Failure)?;
+
+                // Only losses from here on count
+                rx_conn_lost.borrow_and_update();
+                Ok(rx_conn_lost)
+            },
+        };
+
+        Ok(token)
+    }
+
+    fn disconnect(
+        &mut self,
+    ) -> Result<impl Future<Output = Result<(), ConnectionError>> + Send + 'static, ConnectionError>
+    {
+        self.client
+            .try_disconnect()
+            .map_err(|err| ConnectionError::Failure(err.to_string()))?;
+
+        let token = TokenDisconnect {
+            future: async move { Ok(()) },
+        };
+        Ok(token)
+    }
+}
+
+impl Drop for Mqtt {
+    fn drop(&mut self) {
+        trace!("Dropping Mqtt adapter");
+        let _ = self.client.try_disconnect();
+        self.shutdown.cancel();
+        self.event_handle.abort();
+    }
+}
+
+/// Trusts `ca_cert` if it's set, otherwise the system's certificates
+fn tls(config: &Config) -> Result<TlsConfiguration, MqttError> {
+    let client_auth = match (&config.client_cert, &config.client_key) {
+        (Some(cert), Some(key)) => Some((read_pem(cert)?, read_pem(key)?)),
+        (None, None) => None,
+        _ => {
+            return Err(MqttError::Tls(
+                "client_cert and client_key must be set together".to_string(),
+            ))
+        }
+    };
+
+    if let Some(ca) = &config.ca_cert {
+        return Ok(TlsConfiguration::Simple {
+            ca: read_pem(ca)?,
+            alpn: None,
+            client_auth,
+        });
+    }
+
+    let mut root_cert_store = RootCertStore::empty();
+    root_cert_store.add_parsable_certificates(
+        rustls_native_certs::load_native_certs().map_err(|err| MqttError::Tls(err.to_string()))?,
+    );
+    let builder = ClientConfig::builder().with_root_certificates(root_cert_store);
+    let client_config = match client_auth {
+        Some((cert, key)) => {
+            let certs = rustls_pemfile::certs(&mut cert.as_slice())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|err| MqttError::Tls(err.to_string()))?;
+            let key = rustls_pemfile::private_key(&mut key.as_slice())
+                .map_err(|err| MqttError::Tls(err.to_string()))?
+                .ok_or_else(|| MqttError::Tls("no private key in client_key".to_string()))?;
+            builder
+                .with_client_auth_cert(certs, key)
+                .map_err(|err| MqttError::Tls(err.to_string()))?
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(TlsConfiguration::Rustls(Arc::new(client_config)))
+}
+
+fn read_pem(path: &str) -> Result<Vec<u8>, MqttError> {
+    std::fs::read(path).map_err(|err| MqttError::Tls(format!("[{}]: {}", path, err)))
+}
+
+/// A topic with `{name}` placeholders, parsed once so publishing doesn't have to
+struct TopicTemplate {
+    parts: Vec<TopicPart>,
+}
+
+enum TopicPart {
+    Text(String),
+    ClientId,
+    MsgId,
+    Topic,
+    Source,
+    Metadata(String),
+}
+
+impl TopicTemplate {
+    fn parse(template: &str) -> TopicTemplate {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let Some(len) = rest[start..].find('}') else {
+                break;
+            };
+            if start > 0 {
+                parts.push(TopicPart::Text(rest[..start].to_string()));
+            }
+            parts.push(match &rest[start + 1..start + len] {
+                "client_id" => TopicPart::ClientId,
+                "msg_id" => TopicPart::MsgId,
+                "topic" => TopicPart::Topic,
+                "source" => TopicPart::Source,
+                name => TopicPart::Metadata(name.to_string()),
+            });
+            rest = &rest[start + len + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(TopicPart::Text(rest.to_string()));
+        }
+
+        TopicTemplate { parts }
+    }
+
+    /// Placeholders the msg has no value for are left empty
+    fn render(&self, msg: &MsgBusData, client_id: &str) -> String {
+        let mut topic = String::new();
+        for part in &self.parts {
+            match part {
+                TopicPart::Text(text) => topic.push_str(text),
+                TopicPart::ClientId => topic.push_str(client_id),
+                TopicPart::MsgId => topic.push_str(&msg.id.to_string()),
+                TopicPart::Topic => topic.push_str(msg.topic.as_deref().unwrap_or_default()),
+                TopicPart::Source => topic.push_str(msg.source.as_deref().unwrap_or_default()),
+                TopicPart::Metadata(name) => {
+                    topic.push_str(msg.metadata.get(name).map_or("", String::as_str))
+                }
+            }
+        }
+        topic
+    }
+}
+
+fn default_ws_path() -> String {
+    "/mqtt".to_string()
+}
+
+fn default_client_id() -> String {
+    "rusty-bridge".to_string()
+}
+
+fn default_topic() -> String {
+    "rusty-bridge/{client_id}/data".to_string()
+}
+
+fn default_qos() -> u8 {
+    1
+}
+
+fn default_keep_alive_secs() -> u64 {
+    30
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    use rumqttd::{Broker, Notification};
+    use tokio::time::timeout;
+
+    use data_source_core::Bytes;
+
+    use super::*;
+
+    #[test]
+    fn topic_template() {
+        let template = TopicTemplate::parse("site/{site}/{client_id}/{source}/{topic}/{msg_id}");
+        let msg = MsgBusData {
+            id: 7,
+            topic: Some("sensors/temp".to_string()),
+            metadata: HashMap::from([("site".to_string(), "north".to_string())]),
+            source: Some("mqtt".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            template.render(&msg, "edge"),
+            "site/north/edge/mqtt/sensors/temp/7"
+        );
+        assert_eq!(
+            template.render(&MsgBusData::default(), "edge"),
+            "site//edge///0"
+        );
+    }
+
+    #[tokio::test]
+    async fn publish_to_local_broker() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let broker_config = serde_json::json!({
+            "id": 0,
+            "router": {
+                "max_connections": 10,
+                "max_outgoing_packet_count": 200,
+                "max_segment_size": 1048576,
+                "max_segment_count": 10,
+            },
+            "v4": {
+                "1": {
+                    "name": "v4-1",
+                    "listen": format!("127.0.0.1:{}", port),
+                    "next_connection_delay_ms": 1,
+                    "connections": {
+                        "connection_timeout_ms": 60000,
+                        "max_payload_size": 20480,
+                        "max_inflight_count": 100,
+                        "dynamic_filters": true,
+                    },
+                },
+            },
+        });
+        let mut broker = Broker::new(serde_json::from_value(broker_config).unwrap());
+        let (mut link_tx, mut link_rx) = broker.link("test").unwrap();
+        thread::spawn(move || broker.start().unwrap());
+        link_tx.subscribe("#").unwrap();
+        // The broker listens from its own thread, connecting any sooner is refused
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let config = serde_json::json!({
+            "host": "127.0.0.1",
+            "port": port,
+            "topic": "{client_id}/{topic}",
+        });
+        let mut adapter = Mqtt::new(&config.to_string()).unwrap();
+        let rx_conn_lost = timeout(Duration::from_secs(5), adapter.connect().await.unwrap())
+            .await
+            .unwrap();
+        assert!(rx_conn_lost.is_ok());
+
+        let msg = MsgBusData {
+            id: 3,
+            payload: Bytes::from_static(b"hello"),
+            topic: Some("sensors/temp".to_string()),
+            ..Default::default()
+        };
+        let ack = timeout(Duration::from_secs(5), adapter.publish(msg).wait_for_ack())
+            .await
+            .unwrap();
+        assert_eq!(ack.unwrap(), 3);
+
+        let forward = tokio::task::spawn_blocking(move || loop {
+            if let Ok(Some(Notification::Forward(forward))) = link_rx.recv() {
+                return forward;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(forward.publish.topic, "rusty-bridge/sensors/temp");
+        assert_eq!(forward.publish.payload, "hello");
+    }
+}
@@ -1,6 +1,7 @@
 use std::time::Duration;
 
 use cloud_adapter_core::CloudAdapterTrait;
+use cloud_adapter_core::ReconnectPolicy;
 use cloud_adapter_core::TokenDelivery;
 use data_source_core::MsgBusDataFactory;
 use special_hivemq::Config;
@@ -18,6 +19,8 @@ async fn main() {
         password: "password".to_string(),
         ana_endpoint: "https://www.myanawebsite.com/api/v1/hivemq".to_string(),
         mqtt_endpoint: "wss://mqtt.placeholder.com:443/mqtt".to_string(),
+        death_will: true,
+        reconnect: ReconnectPolicy::default(),
     };
     // TODO - trying to figure out how to maintain concrete types for rusty edge to pas
     let config = serde_json::to_string(&config).unwrap();
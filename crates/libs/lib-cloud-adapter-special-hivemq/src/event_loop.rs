@@ -1,8 +1,15 @@
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
 
-use cloud_adapter_core::ConnectionLost;
-use rumqttc::{AsyncClient, ConnAck, ConnectReturnCode, EventLoop, PubAck, SubAck};
+use cloud_adapter_core::{Backoff, Command, ConnectionLost};
+use data_source_core::{MsgBusData, MsgId};
+use rumqttc::{
+    AsyncClient, ConnAck, ConnectReturnCode, EventLoop, PubAck, Publish, QoS, Request, SubAck,
+};
 use special_ana::SpecialAnA;
+use special_hivemq_transform::{node_commands, NodeCommand, TransformSpecialHiveMQ};
 use tokio::{
     select, spawn,
     sync::{broadcast, mpsc, oneshot, watch},
@@ -15,15 +22,21 @@ pub fn spawn_looper(
     mut event_loop: EventLoop,
     mut rx_run: watch::Receiver<bool>,
     tx_connect: broadcast::Sender<ConnectReturnCode>,
-    mut rx_ack_oneshot: mpsc::Receiver<(u32, oneshot::Sender<u32>)>,
+    mut rx_ack_oneshot: mpsc::Receiver<(MsgId, oneshot::Sender<MsgId>)>,
     tx_conn_lost: watch::Sender<ConnectionLost>,
+    tx_commands: broadcast::Sender<Command>,
     credentials: crate::Credentials,
+    transform: Arc<Mutex<TransformSpecialHiveMQ>>,
     ana: SpecialAnA,
     client: AsyncClient,
+    mut backoff: Backoff,
     shutdown: CancellationToken,
 ) -> JoinHandle<EventLoop> {
     let event_handle = spawn(async move {
-        let mut ack_map: HashMap<u16, (u32, oneshot::Sender<u32>)> = HashMap::new();
+        let mut ack_map: HashMap<u16, (MsgId, oneshot::Sender<MsgId>)> = HashMap::new();
+        // NBIRTH and NDEATH are published from here, they don't have a oneshot from publish()
+        let mut session_publishes: usize = 0;
+        let mut session_pkids: HashSet<u16> = HashSet::new();
         //let mut password_expiration = ana.get_expiration_future().await;
         let mut password_renewal = ana.get_token_renewal();
         // Before starting the event_loop get a password..
@@ -67,6 +80,10 @@ pub fn spawn_looper(
                             let username = credentials.username.clone();
                             trace!("New password. username: {} password: {}", credentials.username, new_password);
                             event_loop.mqtt_options.set_credentials(username, new_password);
+                            // The will only goes out when the connection is lost
+                            let death = transform.lock().expect("poisoned lock").death();
+                            publish_first(&mut event_loop, &credentials.topic_death, death);
+                            session_publishes += 1;
                             // Do not use client.disconnect() as that will cause a deadlock while the .await waits for the event_loop to move. Or spawn a task..
                             client.try_disconnect().unwrap();
                         },
@@ -98,6 +115,9 @@ pub fn spawn_looper(
                             rumqttc::Event::Incoming(inc) => {
                                 debug!("Incoming: [{:?}]", inc);
                                 match inc {
+                                    rumqttc::Packet::PubAck(PubAck { pkid }) if session_pkids.remove(&pkid) => {
+                                        trace!("Sparkplug session msg [{}] acked", pkid)
+                                    }
                                     // We should only ever receive a PubAck after a Publish, which should only occur if a oneshot channel is registered to the pkid
                                     rumqttc::Packet::PubAck(PubAck { pkid }) => match ack_map.remove(&pkid)
                                     {
@@ -112,6 +132,13 @@ pub fn spawn_looper(
                                         session_present: _,
                                         code,
                                     }) => {
+                                        if code == ConnectReturnCode::Success {
+                                            backoff.reset();
+                                            // Every session starts with an NBIRTH, ahead of any NDATA
+                                            let birth = transform.lock().expect("poisoned lock").birth();
+                                            publish_first(&mut event_loop, &credentials.topic_birth, birth);
+                                            session_publishes += 1;
+                                        }
                                         let res = tx_connect.send(code);
                                         println!("res {:?}", res);
                                     }
@@ -119,7 +146,36 @@ pub fn spawn_looper(
                                         info!("SubAck: id: [{}], code: [{:?}]", pkid, return_codes)
                                     }
                                     rumqttc::Packet::Connect(_) => todo!(),
-                                    rumqttc::Packet::Publish(_) => todo!(),
+                                    rumqttc::Packet::Publish(publish) if publish.topic == credentials.topic_cmd => {
+                                        match node_commands(&publish.payload) {
+                                            Ok(commands) => for command in commands {
+                                                debug!("NCMD: [{:?}]", command);
+                                                let command = match command {
+                                                    // A host application asks for the NBIRTH again, it restarts seq as well
+                                                    NodeCommand::Rebirth => {
+                                                        let birth = transform.lock().expect("poisoned lock").birth();
+                                                        publish_first(&mut event_loop, &credentials.topic_birth, birth);
+                                                        session_publishes += 1;
+                                                        continue;
+                                                    }
+                                                    NodeCommand::Reboot => Command::Reboot,
+                                                    NodeCommand::ReloadConfig => Command::ReloadConfig,
+                                                    NodeCommand::Write { name, value } => Command::Southbound(MsgBusData {
+                                                        payload: value.into(),
+                                                        topic: Some(name),
+                                                        ..Default::default()
+                                                    }),
+                                                };
+                                                if let Err(err) = tx_commands.send(command) {
+                                                    warn!("No one is handling commands, dropping [{:?}]", err.0);
+                                                }
+                                            },
+                                            Err(err) => warn!("Could not decode NCMD. [{}]", err),
+                                        }
+                                    }
+                                    rumqttc::Packet::Publish(publish) => {
+                                        warn!("Publish on unexpected topic [{}]", publish.topic)
+                                    }
                                     rumqttc::Packet::PubRec(_) => todo!(),
                                     rumqttc::Packet::PubRel(_) => todo!(),
                                     rumqttc::Packet::PubComp(_) => todo!(),
@@ -135,6 +191,10 @@ pub fn spawn_looper(
                             rumqttc::Event::Outgoing(out) => {
                                 debug!("Outgoing: [{:?}]", out);
                                 match out {
+                                    rumqttc::Outgoing::Publish(pkt_id) if session_publishes > 0 => {
+                                        session_publishes -= 1;
+                                        session_pkids.insert(pkt_id);
+                                    }
                                     rumqttc::Outgoing::Publish(pkt_id) => match rx_ack_oneshot.try_recv() {
                                         Ok(oneshot) => match ack_map.insert(pkt_id, oneshot) {
                                             Some(oneshot) => drop(oneshot),
@@ -146,7 +206,8 @@ pub fn spawn_looper(
                                     }, //todo! add this id to a hashmap to help with token delivery?
                                     rumqttc::Outgoing::Subscribe(_) => (),
                                     rumqttc::Outgoing::Unsubscribe(_) => todo!(),
-                                    rumqttc::Outgoing::PubAck(_) => todo!(),
+                                    // Acks for the NCMD msgs
+                                    rumqttc::Outgoing::PubAck(_) => (),
                                     rumqttc::Outgoing::PubRec(_) => todo!(),
                                     rumqttc::Outgoing::PubRel(_) => todo!(),
                                     rumqttc::Outgoing::PubComp(_) => todo!(),
@@ -159,7 +220,19 @@ pub fn spawn_looper(
                         },
                         Err(err) => {
                             warn!("Connection lost: [{}]", err);
-                            ack_map.clear();
+                            // The next session publishes its own NBIRTH, with the next bdSeq, and its NDATA starts over at seq 1
+                            fail_in_flight(&mut event_loop, &mut ack_map);
+                            session_publishes = 0;
+                            session_pkids.clear();
+                            {
+                                let mut transform = transform.lock().expect("poisoned lock");
+                                transform.next_session();
+                                if credentials.death_will {
+                                    event_loop
+                                        .mqtt_options
+                                        .set_last_will(crate::death_last_will(&credentials.topic_death, &transform));
+                                }
+                            }
                             match err {
                                 rumqttc::ConnectionError::MqttState(_) => {
                                     tx_conn_lost.send_replace(ConnectionLost::ManualDisconnect)
@@ -170,7 +243,7 @@ pub fn spawn_looper(
                                 err => tx_conn_lost
                                     .send_replace(ConnectionLost::Uncategorized(err.to_string())),
                             };
-                            tokio::time::sleep(Duration::from_millis(1000)).await;
+                            tokio::time::sleep(backoff.next_delay()).await;
                         }
                     }
                 }
@@ -184,4 +257,25 @@ pub fn spawn_looper(
     event_handle
 }
 
+/// Drops every token waiting on an ack, which nacks their msgs, along with everything the event_loop would resend after
+/// a reconnect. NDATA is encoded with the `seq` of the session it was published in, so the nacked msgs are persisted and
+/// replayed by rusty-bridge, encoded again in the next session, instead of going out of order after its NBIRTH
+fn fail_in_flight(
+    event_loop: &mut EventLoop,
+    ack_map: &mut HashMap<u16, (MsgId, oneshot::Sender<MsgId>)>,
+) {
+    ack_map.clear();
+    event_loop.clean();
+    event_loop.pending.clear();
+}
+
+/// Publishes ahead of anything queued, NBIRTH has to be the first msg of a session
+fn publish_first(event_loop: &mut EventLoop, topic: &str, payload: Vec<u8>) {
+    event_loop.pending.push_front(Request::Publish(Publish::new(
+        topic,
+        QoS::AtLeastOnce,
+        payload,
+    )));
+}
+
 //fn handle_event(&ack_map: HashMap)
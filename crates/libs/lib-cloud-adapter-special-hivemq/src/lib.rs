@@ -6,19 +6,21 @@ mod error;
 mod event_loop;
 mod tokens;
 use std::future::Future;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use crate::event_loop::spawn_looper;
 pub use crate::tokens::DeliveryToken;
 pub use error::SpecialHiveMQError;
 
 use cloud_adapter_core::{
-    CloudAdapterTrait, ConnectionError, ConnectionLost, TokenDelivery, TokenDisconnect,
+    CloudAdapterTrait, Command, ConnectionError, ConnectionLost, ReconnectPolicy, TokenDelivery,
+    TokenDisconnect,
 };
 use cloud_adapter_core::{Error, TokenConnection};
-use data_source_core::MsgBusData;
+use data_source_core::{MsgBusData, MsgId};
 use rumqttc::{
-    AsyncClient, ConnectReturnCode, EventLoop, MqttOptions, TlsConfiguration, Transport,
+    AsyncClient, ConnectReturnCode, EventLoop, LastWill, MqttOptions, QoS, TlsConfiguration,
+    Transport,
 };
 use serde::{Deserialize, Serialize};
 use special_ana::SpecialAnA;
@@ -31,7 +33,7 @@ use tokio::{
     time::Duration,
 };
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, trace};
+use tracing::{debug, trace, warn};
 
 pub struct SpecialHiveMQ {
     client: AsyncClient,
@@ -40,15 +42,18 @@ pub struct SpecialHiveMQ {
     // because publish() doesn't return the pktid...
     /// Sends a oneshot::Sender that will return the msg.id, as well as the msg.id itself
     /// This is needed because client.publish() does not return an id
-    tx_ack_channel: tokio::sync::mpsc::Sender<(u32, oneshot::Sender<u32>)>,
+    tx_ack_channel: tokio::sync::mpsc::Sender<(MsgId, oneshot::Sender<MsgId>)>,
     tx_connect_ack: broadcast::Sender<ConnectReturnCode>,
+    /// NCMD metrics the adapter doesn't handle itself
+    tx_commands: broadcast::Sender<Command>,
     rx_conn_lost: watch::Receiver<ConnectionLost>,
     /// Blocks or unblocks the event_loop because event_loop will always reconnect
     tx_run: watch::Sender<bool>,
     // poller handling
     event_handle: JoinHandle<EventLoop>,
-    /// Transforms a &[u8] to a sparkplugb message
-    transform: TransformSpecialHiveMQ,
+    /// Transforms a &[u8] to a sparkplugb message. Shared with the event_loop, which publishes the NBIRTH of each session.
+    /// Not a `MsgTransform`, the `seq` it encodes has to follow the NBIRTH of the session the msg is published in
+    transform: Arc<Mutex<TransformSpecialHiveMQ>>,
     /// To shutdown the event_loop
     shutdown: CancellationToken,
 }
@@ -58,6 +63,10 @@ struct Credentials {
     username: String,
     topic_data: String,
     topic_cmd: String,
+    topic_birth: String,
+    topic_death: String,
+    /// Register the NDEATH as the will when connecting
+    death_will: bool,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -66,6 +75,12 @@ pub struct Config {
     pub password: String,
     pub ana_endpoint: String,
     pub mqtt_endpoint: String,
+    /// HiveMQ has to allow the will on the NDEATH topic, otherwise connecting fails with "notauthorized"
+    #[serde(default = "default_death_will")]
+    pub death_will: bool,
+    /// Delays between reconnection attempts
+    #[serde(default)]
+    pub reconnect: ReconnectPolicy,
 }
 
 impl SpecialHiveMQ {
@@ -79,6 +94,8 @@ impl SpecialHiveMQ {
             password,
             ana_endpoint,
             mqtt_endpoint,
+            death_will,
+            reconnect,
         } = config;
         // Parse the custom field of the Credentials struct to get the ana endpoint and hivemq endpoint
         let ana = SpecialAnA::new_mqtt(&ana_endpoint, username.clone(), password, true)
@@ -112,20 +129,26 @@ impl SpecialHiveMQ {
         mqtt_options.set_transport(Transport::Wss(TlsConfiguration::Rustls(Arc::new(
             client_config,
         ))));
-        // Do not set LastWill unless it is supported in HiveMQ first. Otherwise connetions will result in "notauthorized"
-        //let last_will = LastWill::new("last_will_topic", "x.x", rumqttc::QoS::ExactlyOnce, true);
-        //mqtt_options.set_last_will(last_will);
 
         let topic_cmd = format!("spBv1.0/{}/NCMD/{}", group_id, edge_node_id);
         let topic_data = format!("spBv1.0/{}/NDATA/{}", group_id, edge_node_id);
+        let topic_birth = format!("spBv1.0/{}/NBIRTH/{}", group_id, edge_node_id);
+        let topic_death = format!("spBv1.0/{}/NDEATH/{}", group_id, edge_node_id);
         trace!(
             "Command topic: [{}]. Data topic: [{}]",
             topic_cmd,
             topic_data
         );
 
+        let transform = TransformSpecialHiveMQ::new();
+        if death_will {
+            mqtt_options.set_last_will(death_last_will(&topic_death, &transform));
+        }
+        let transform = Arc::new(Mutex::new(transform));
+
         let (tx_ack_channel, rx_ack_channel) = mpsc::channel(10);
         let (tx_connect_ack, _) = tokio::sync::broadcast::channel(1);
+        let (tx_commands, _) = broadcast::channel(16);
         let (tx_run, rx_run) = watch::channel(false);
         tx_run.send_replace(false);
 
@@ -138,6 +161,9 @@ impl SpecialHiveMQ {
             username,
             topic_data,
             topic_cmd,
+            topic_birth,
+            topic_death,
+            death_will,
         };
 
         let shutdown = CancellationToken::new();
@@ -147,9 +173,12 @@ impl SpecialHiveMQ {
             tx_connect_ack.clone(),
             rx_ack_channel,
             tx_conn_lost,
+            tx_commands.clone(),
             credentials.clone(),
+            transform.clone(),
             ana,
             client.clone(),
+            reconnect.backoff(),
             shutdown.child_token(),
         );
 
@@ -157,10 +186,11 @@ impl SpecialHiveMQ {
             client,
             credentials: credentials,
             tx_connect_ack,
+            tx_commands,
             tx_ack_channel,
             rx_conn_lost,
             tx_run,
-            transform: TransformSpecialHiveMQ::new(),
+            transform,
             event_handle,
             shutdown,
         })
@@ -177,7 +207,12 @@ impl CloudAdapterTrait for SpecialHiveMQ {
         let client_clone = self.client.clone();
         let tx_publish_reqst_clone = self.tx_ack_channel.clone();
 
-        let msg = self.transform.transform(msg.payload);
+        let msg = self
+            .transform
+            .lock()
+            .expect("poisoned lock")
+            // Only copies when another adapter still holds the payload
+            .transform(msg.payload.into());
 
         let topic_data = self.credentials.topic_data.clone();
 
@@ -260,6 +295,20 @@ impl CloudAdapterTrait for SpecialHiveMQ {
     fn disconnect(
         &mut self,
     ) -> Result<impl Future<Output = Result<(), ConnectionError>> + 'static, ConnectionError> {
+        // The will only goes out when the connection is lost, a clean disconnect has to publish the NDEATH itself
+        let death = self.transform.lock().expect("poisoned lock").death();
+        let (tx_ack, _) = oneshot::channel();
+        // Queue a oneshot like publish() does, so the event_loop pairs the acks of the msgs after it correctly
+        if self.tx_ack_channel.try_send((0, tx_ack)).is_ok() {
+            if let Err(err) = self.client.try_publish(
+                self.credentials.topic_death.clone(),
+                QoS::AtLeastOnce,
+                false,
+                death,
+            ) {
+                warn!("Could not publish NDEATH before disconnecting. [{}]", err);
+            }
+        }
         self.client
             .try_disconnect()
             .map_err(|err| ConnectionError::Failure(format!("{}", err)))?;
@@ -273,6 +322,20 @@ impl CloudAdapterTrait for SpecialHiveMQ {
         };
         Ok(token)
     }
+
+    /// Commands sent through NCMD. Node Control/Rebirth is handled by the adapter, it doesn't show up here
+    fn commands(&self) -> Option<broadcast::Receiver<Command>> {
+        Some(self.tx_commands.subscribe())
+    }
+}
+
+/// NDEATH of the current session, for the broker to publish if the connection is lost
+fn death_last_will(topic_death: &str, transform: &TransformSpecialHiveMQ) -> LastWill {
+    LastWill::new(topic_death, transform.death(), QoS::AtLeastOnce, false)
+}
+
+fn default_death_will() -> bool {
+    true
 }
 
 impl Drop for SpecialHiveMQ {
@@ -0,0 +1,15 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SpecialIoTHubError {
+    #[error("initialization: [{0}]")]
+    Init(String),
+    #[error("connection string: [{0}]")]
+    ConnectionString(String),
+}
+
+impl From<SpecialIoTHubError> for cloud_adapter_core::Error {
+    fn from(value: SpecialIoTHubError) -> Self {
+        cloud_adapter_core::Error::Initialization(value.to_string())
+    }
+}
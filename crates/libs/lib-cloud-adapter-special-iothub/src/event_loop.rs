@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+
+use cloud_adapter_core::{Backoff, Command, ConnectionLost};
+use data_source_core::MsgBusData;
+use percent_encoding::percent_decode_str;
+use rumqttc::{
+    AsyncClient, ConnAck, ConnectReturnCode, Event, EventLoop, Outgoing, Packet, PubAck, Publish,
+    QoS,
+};
+use tokio::{
+    select, spawn,
+    sync::{broadcast, oneshot, watch},
+    task::JoinHandle,
+    time::{sleep, sleep_until, Duration, Instant},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, trace, warn};
+
+use crate::{sas::ConnectionString, CloudToDevice, PendingAcks};
+
+pub struct Looper {
+    pub event_loop: EventLoop,
+    pub client: AsyncClient,
+    pub rx_run: watch::Receiver<bool>,
+    pub tx_connect: broadcast::Sender<Result<(), String>>,
+    pub pending_acks: PendingAcks,
+    pub tx_conn_lost: watch::Sender<ConnectionLost>,
+    pub tx_cloud_to_device: broadcast::Sender<CloudToDevice>,
+    pub tx_commands: broadcast::Sender<Command>,
+    pub connection_string: ConnectionString,
+    pub token_ttl: Duration,
+    /// How long to wait before polling again after the connection failed, polling reconnects
+    pub backoff: Backoff,
+    pub shutdown: CancellationToken,
+}
+
+/// Polls the event_loop while `rx_run` is true, matching PubAcks to the tokens of the msgs published.
+/// The SAS token is renewed before it expires, the connection is then remade with it once no acks are outstanding,
+/// or when the old token expires at the latest
+pub fn spawn_looper(looper: Looper) -> JoinHandle<EventLoop> {
+    let Looper {
+        mut event_loop,
+        client,
+        mut rx_run,
+        tx_connect,
+        pending_acks,
+        tx_conn_lost,
+        tx_cloud_to_device,
+        tx_commands,
+        connection_string,
+        token_ttl,
+        mut backoff,
+        shutdown,
+    } = looper;
+    let topic_cloud_to_device = format!(
+        "devices/{}/messages/devicebound/#",
+        connection_string.device_id
+    );
+    // The token in use is renewed after 4/5 of its lifetime
+    let renew_after = token_ttl * 4 / 5;
+
+    spawn(async move {
+        let mut ack_map: HashMap<u16, oneshot::Sender<()>> = HashMap::new();
+        let mut connected = false;
+        // Set after a manual disconnect went out, the event_loop stays blocked until the next connect
+        let mut paused = false;
+        let mut renew_at = Instant::now() + renew_after;
+        // When the token the connection was made with expires, set while a reconnect with a renewed token is due
+        let mut reconnect_by: Option<Instant> = None;
+
+        loop {
+            if paused || !*rx_run.borrow_and_update() {
+                debug!("Blocking event_loop for disconnection");
+                select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = sleep_until(renew_at) => {
+                        renew_token(&mut event_loop, &connection_string, token_ttl);
+                        renew_at = Instant::now() + renew_after;
+                    }
+                    changed = rx_run.changed() => match changed {
+                        Ok(_) => paused = false,
+                        Err(_) => break,
+                    },
+                }
+                continue;
+            }
+
+            select! {
+                _ = shutdown.cancelled() => break,
+                changed = rx_run.changed() => if changed.is_err() {
+                    break
+                },
+                _ = sleep_until(renew_at) => {
+                    renew_token(&mut event_loop, &connection_string, token_ttl);
+                    reconnect_by.get_or_insert(renew_at + (token_ttl - renew_after));
+                    renew_at = Instant::now() + renew_after;
+                }
+                _ = sleep_until(reconnect_by.unwrap_or_else(Instant::now)), if reconnect_by.is_some() => {
+                    info!("SAS token expired with acks outstanding, reconnecting with the renewed token");
+                    fail_in_flight(&mut event_loop, &mut ack_map, &pending_acks);
+                    reconnect_by = None;
+                }
+                event = event_loop.poll() => match event {
+                    Ok(Event::Incoming(Packet::ConnAck(ConnAck { code, .. }))) => {
+                        connected = code == ConnectReturnCode::Success;
+                        let result = match code {
+                            ConnectReturnCode::Success => {
+                                // The connection is made with the latest token
+                                reconnect_by = None;
+                                backoff.reset();
+                                if let Err(err) = client.try_subscribe(&topic_cloud_to_device, QoS::AtLeastOnce) {
+                                    warn!("Could not subscribe to cloud to device msgs: [{}]", err);
+                                }
+                                Ok(())
+                            }
+                            code => Err(format!("{:?} code: [{}]", code, code as u8)),
+                        };
+                        let _ = tx_connect.send(result);
+                    }
+                    Ok(Event::Incoming(Packet::PubAck(PubAck { pkid }))) => {
+                        match ack_map.remove(&pkid) {
+                            Some(tx_ack) => {
+                                if tx_ack.send(()).is_err() {
+                                    debug!("Ack for pkid [{}] arrived after its token was dropped", pkid)
+                                }
+                            }
+                            None => warn!("pkid [{}] was not in the ack map", pkid),
+                        }
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        let msg = cloud_to_device(publish);
+                        debug!("Cloud to device msg: [{:?}]", msg.properties);
+                        if tx_commands.send(southbound(&msg)).is_err() {
+                            trace!("Nothing is listening for commands");
+                        }
+                        if tx_cloud_to_device.send(msg).is_err() {
+                            trace!("Nothing is listening for cloud to device msgs");
+                        }
+                    }
+                    Ok(Event::Outgoing(Outgoing::Publish(pkid))) => {
+                        // Publishes go out in the order they were queued, and so do their tokens
+                        match pending_acks.lock().expect("poisoned lock").pop_front() {
+                            Some(tx_ack) => {
+                                ack_map.insert(pkid, tx_ack);
+                            }
+                            None => warn!("Publish [{}] went out without a token waiting on it", pkid),
+                        }
+                    }
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                        info!("Disconnected from IoT Hub");
+                        fail_in_flight(&mut event_loop, &mut ack_map, &pending_acks);
+                        connected = false;
+                        paused = true;
+                        tx_conn_lost.send_replace(ConnectionLost::ManualDisconnect);
+                    }
+                    Ok(event) => trace!("Event: [{:?}]", event),
+                    Err(err) => {
+                        fail_in_flight(&mut event_loop, &mut ack_map, &pending_acks);
+                        if connected {
+                            warn!("Connection lost: [{}]", err);
+                            connected = false;
+                            match err {
+                                rumqttc::ConnectionError::NetworkTimeout => {
+                                    tx_conn_lost.send_replace(ConnectionLost::Timeout)
+                                }
+                                err => tx_conn_lost
+                                    .send_replace(ConnectionLost::Uncategorized(err.to_string())),
+                            };
+                        } else {
+                            let _ = tx_connect.send(Err(err.to_string()));
+                        }
+                        sleep(backoff.next_delay()).await;
+                    }
+                }
+            }
+
+            // Reconnect with the renewed token as soon as nothing would be nacked by it
+            if reconnect_by.is_some()
+                && ack_map.is_empty()
+                && pending_acks.lock().expect("poisoned lock").is_empty()
+            {
+                debug!("Reconnecting with the renewed SAS token");
+                fail_in_flight(&mut event_loop, &mut ack_map, &pending_acks);
+                reconnect_by = None;
+            }
+        }
+
+        event_loop
+    })
+}
+
+/// Takes effect on the next connection
+fn renew_token(event_loop: &mut EventLoop, connection_string: &ConnectionString, ttl: Duration) {
+    debug!("Renewing the SAS token");
+    event_loop.mqtt_options.set_credentials(
+        connection_string.username(),
+        connection_string.sas_token(ttl),
+    );
+}
+
+/// The topic is `devices/{id}/messages/devicebound/{property_bag}`
+fn cloud_to_device(publish: Publish) -> CloudToDevice {
+    let property_bag = publish
+        .topic
+        .split_once("/messages/devicebound/")
+        .map_or("", |(_, property_bag)| property_bag);
+    let properties = property_bag
+        .split('&')
+        .filter(|property| !property.is_empty())
+        .map(|property| {
+            let (name, value) = property.split_once('=').unwrap_or((property, ""));
+            let decode = |part| percent_decode_str(part).decode_utf8_lossy().to_string();
+            (decode(name), decode(value))
+        })
+        .collect();
+
+    CloudToDevice {
+        properties,
+        payload: publish.payload.clone(),
+    }
+}
+
+/// Every cloud-to-device msg goes southbound, named by its `command` application property
+fn southbound(msg: &CloudToDevice) -> Command {
+    Command::Southbound(MsgBusData {
+        topic: msg.properties.get("command").cloned(),
+        metadata: msg.properties.clone(),
+        payload: msg.payload.clone(),
+        ..Default::default()
+    })
+}
+
+/// Drops every token waiting on an ack, which nacks their msgs. Nothing is resent by the event_loop after a reconnect,
+/// the nacked msgs are persisted and replayed by rusty-bridge instead, so the tokens can't get mixed up with resent publishes
+fn fail_in_flight(
+    event_loop: &mut EventLoop,
+    ack_map: &mut HashMap<u16, oneshot::Sender<()>>,
+    pending_acks: &PendingAcks,
+) {
+    ack_map.clear();
+    // Held while the requests are dropped, so no publish can be queued without its token in between
+    let mut pending_acks = pending_acks.lock().expect("poisoned lock");
+    event_loop.clean();
+    event_loop.pending.clear();
+    pending_acks.clear();
+}
@@ -1,90 +1,439 @@
-//use std::future::Future;
-
-//use async_trait::async_trait;
-//use cloud_adapter_core::{
-//CloudAdapterTrait, ConnectionError, TokenConnection, TokenDelivery, TokenDisconnect,
-//};
-//use msg_bus_core::MsgBusData;
-//use tokio::{
-//sync::broadcast,
-//time::{sleep, Duration, Sleep},
-//};
+//! # Special IoT Hub Adapter
+//!
+//! Publishes msgs to Azure IoT Hub as device-to-cloud telemetry over MQTT, authenticating with a SAS token made from the
+//! device connection string. Cloud-to-device msgs are passed on to [SpecialIoTHub::cloud_to_device] subscribers
+//!
+mod error;
+mod event_loop;
+mod sas;
+mod tokens;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
 
+use crate::event_loop::{spawn_looper, Looper};
+use crate::sas::{ConnectionString, URL_ENCODE};
+pub use crate::tokens::DeliveryToken;
+pub use error::SpecialIoTHubError;
+
+use cloud_adapter_core::{
+    CloudAdapterTrait, Command, ConnectionError, ConnectionLost, Error, ReconnectPolicy,
+    TokenConnection, TokenDelivery, TokenDisconnect,
+};
+use data_source_core::{Bytes, MsgBusData};
+use percent_encoding::utf8_percent_encode;
+use rumqttc::{
+    tokio_rustls::rustls::{ClientConfig, RootCertStore},
+    AsyncClient, EventLoop, MqttOptions, QoS, TlsConfiguration, Transport,
+};
 use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{broadcast, oneshot, watch},
+    task::JoinHandle,
+    time::Duration,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace};
+
+/// Tokens of the msgs queued in the client, in the order they were queued. The event_loop pairs them with packet ids
+/// as the publishes go out
+type PendingAcks = Arc<Mutex<VecDeque<oneshot::Sender<()>>>>;
+
+pub struct SpecialIoTHub {
+    client: AsyncClient,
+    topic_events: String,
+    pending_acks: PendingAcks,
+    tx_connect: broadcast::Sender<Result<(), String>>,
+    rx_conn_lost: watch::Receiver<ConnectionLost>,
+    tx_cloud_to_device: broadcast::Sender<CloudToDevice>,
+    tx_commands: broadcast::Sender<Command>,
+    /// Blocks or unblocks the event_loop because event_loop will always reconnect
+    tx_run: watch::Sender<bool>,
+    event_handle: JoinHandle<EventLoop>,
+    /// To shutdown the event_loop
+    shutdown: CancellationToken,
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct Config {
-    _reserved: u32,
+    /// Device connection string from the hub, `HostName=..;DeviceId=..;SharedAccessKey=..`
+    pub connection_string: String,
+    /// How long each SAS token is valid for, a new one is made before it expires
+    #[serde(default = "default_token_ttl_secs")]
+    pub token_ttl_secs: u64,
+    #[serde(default)]
+    pub transport: TransportKind,
+    /// Defaults to 8883 for tls and 443 for wss
+    pub port: Option<u16>,
+    /// Delays between reconnection attempts
+    #[serde(default)]
+    pub reconnect: ReconnectPolicy,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    #[default]
+    Tls,
+    Wss,
+    /// Only for a local broker standing in for the hub
+    Tcp,
+}
+
+/// A msg sent to this device from the hub
+#[derive(Clone, Debug)]
+pub struct CloudToDevice {
+    /// System properties, ie. `$.mid`, and the application properties of the msg
+    pub properties: HashMap<String, String>,
+    pub payload: Bytes,
+}
+
+impl SpecialIoTHub {
+    pub fn new(config: &str) -> Result<Self, Error> {
+        let config = serde_json::from_str::<Config>(config)
+            .map_err(|err| SpecialIoTHubError::Init(err.to_string()))?;
+        let connection_string = ConnectionString::parse(&config.connection_string)?;
+        let token_ttl = Duration::from_secs(config.token_ttl_secs);
+
+        let host = &connection_string.host_name;
+        let device_id = &connection_string.device_id;
+        let mut mqtt_options = match config.transport {
+            TransportKind::Tls => MqttOptions::new(device_id, host, config.port.unwrap_or(8883)),
+            TransportKind::Tcp => MqttOptions::new(device_id, host, config.port.unwrap_or(1883)),
+            // The websocket transport connects to a url instead of a host
+            TransportKind::Wss => {
+                let port = config.port.unwrap_or(443);
+                MqttOptions::new(
+                    device_id,
+                    format!("wss://{}:{}/$iothub/websocket", host, port),
+                    port,
+                )
+            }
+        };
+        match config.transport {
+            TransportKind::Tls => {
+                mqtt_options.set_transport(Transport::Tls(tls()?));
+            }
+            TransportKind::Wss => {
+                mqtt_options.set_transport(Transport::Wss(tls()?));
+            }
+            TransportKind::Tcp => (),
+        };
+        mqtt_options.set_credentials(
+            connection_string.username(),
+            connection_string.sas_token(token_ttl),
+        );
+        mqtt_options.set_clean_session(true);
+        // IoT Hub disconnects clients that are idle for longer than 29.45 minutes
+        mqtt_options.set_keep_alive(Duration::from_secs(60));
+        mqtt_options.set_manual_acks(false);
+
+        let topic_events = format!("devices/{}/messages/events/", device_id);
+        debug!(
+            "IoT Hub adapter for [{}], publishing to [{}]",
+            host, topic_events
+        );
+
+        let (client, event_loop) = AsyncClient::new(mqtt_options, 100);
+        let pending_acks = PendingAcks::default();
+        let (tx_connect, _) = broadcast::channel(1);
+        let (tx_cloud_to_device, _) = broadcast::channel(16);
+        let (tx_commands, _) = broadcast::channel(16);
+        let (tx_run, rx_run) = watch::channel(false);
+        let (tx_conn_lost, rx_conn_lost) =
+            watch::channel(ConnectionLost::Uncategorized("init".to_string()));
+
+        let shutdown = CancellationToken::new();
+        let event_handle = spawn_looper(Looper {
+            event_loop,
+            client: client.clone(),
+            rx_run,
+            tx_connect: tx_connect.clone(),
+            pending_acks: pending_acks.clone(),
+            tx_conn_lost,
+            tx_cloud_to_device: tx_cloud_to_device.clone(),
+            tx_commands: tx_commands.clone(),
+            connection_string,
+            token_ttl,
+            backoff: config.reconnect.backoff(),
+            shutdown: shutdown.child_token(),
+        });
+
+        Ok(Self {
+            client,
+            topic_events,
+            pending_acks,
+            tx_connect,
+            rx_conn_lost,
+            tx_cloud_to_device,
+            tx_commands,
+            tx_run,
+            event_handle,
+            shutdown,
+        })
+    }
+
+    /// Receives the cloud-to-device msgs that arrive from here on
+    pub fn cloud_to_device(&self) -> broadcast::Receiver<CloudToDevice> {
+        self.tx_cloud_to_device.subscribe()
+    }
 }
 
-//pub struct SpecialIoTHub {}
-
-//pub struct DeliverContext {
-//id: String,
-//future: Option<Sleep>,
-//}
-
-//pub struct ConnectToken {
-//future: Option<Sleep>,
-//}
-
-//impl SpecialIoTHub {
-//pub fn new(tx_conn_status: broadcast::Sender<bool>) -> Self {
-//Self {}
-//}
-//}
-
-//#[async_trait]
-//impl TokenDelivery for DeliverContext {
-//async fn wait_for_ack(mut self) -> Result<(), cloud_adapter_core::DeliveryError> {
-//self.future.take().unwrap().await;
-//Ok(())
-//}
-//}
-
-//#[async_trait]
-//impl TokenConnection for ConnectToken {
-//async fn wait_for_connect(mut self) -> Result<(), cloud_adapter_core::ConnectionError> {
-//self.future.take().unwrap().await;
-//Ok(())
-//}
-//}
-
-////#[async_trait]
-//impl CloudAdapterTrait for SpecialIoTHub {
-////fn publish(&self, msg: MsgBusData) -> Box<dyn TokenDelivery + Send> {
-//fn publish(&mut self, msg: MsgBusData) -> impl TokenDelivery + 'static {
-////self.client.publish();
-//println!("iothub publishing [{:?}]!", msg);
-//let token = DeliverContext {
-//id: "iothub".to_string(),
-//future: Some(sleep(Duration::from_millis(100))),
-//};
-//token
-////tokio::time::sleep(tokio::time::Duration::from_millis(100))
-//}
-
-//async fn connect(&mut self) -> impl cloud_adapter_core::TokenConnection + Send + 'static {
-////Box::new(ConnectToken {
-////future: Some(sleep(Duration::from_millis(500))),
-////})
-//ConnectToken {
-//future: Some(sleep(Duration::from_millis(500))),
-//}
-//}
-
-//fn disconnect(
-//&mut self,
-//) -> Result<impl Future<Output = Result<(), ConnectionError>>, ConnectionError> {
-//// First attempt at this, it doesn't do anything yet. This is cool!
-////todo!();
-//let (tx, rx) = tokio::sync::oneshot::channel::<()>();
-//let token = TokenDisconnect {
-//future: async move {
-//rx.await;
-//Ok(())
-//},
-//};
-//Ok(token)
-//}
-//}
+impl CloudAdapterTrait for SpecialIoTHub {
+    fn publish(&mut self, msg: MsgBusData) -> impl TokenDelivery + Send + 'static {
+        trace!("Publishing [{}]", msg.id);
+        // The msg metadata goes along as application properties, url encoded after the topic
+        let mut topic = self.topic_events.clone();
+        for (index, (name, value)) in msg.metadata.iter().enumerate() {
+            if index > 0 {
+                topic.push('&');
+            }
+            topic.push_str(&format!(
+                "{}={}",
+                utf8_percent_encode(name, URL_ENCODE),
+                utf8_percent_encode(value, URL_ENCODE)
+            ));
+        }
+
+        // The token is queued while the lock is held, so tokens are in the same order as the publishes in the client
+        let (tx_ack, rx_ack) = oneshot::channel();
+        let mut pending_acks = self.pending_acks.lock().expect("poisoned lock");
+        let ack = match self
+            .client
+            .try_publish(topic, QoS::AtLeastOnce, false, msg.payload)
+        {
+            Ok(()) => {
+                pending_acks.push_back(tx_ack);
+                Ok(rx_ack)
+            }
+            Err(err) => Err(format!("could not queue publish: [{}]", err)),
+        };
+
+        DeliveryToken {
+            msg_id: msg.id,
+            ack,
+        }
+    }
+
+    async fn connect(
+        &mut self,
+    ) -> Result<
+        TokenConnection<
+            impl Future<Output = Result<watch::Receiver<ConnectionLost>, ConnectionError>>
+                + Send
+                + 'static,
+        >,
+        ConnectionError,
+    > {
+        debug!("Connecting to IoT Hub");
+        // Subscribe before unblocking the event_loop so the result can't be missed
+        let mut rx_connect = self.tx_connect.subscribe();
+        self.tx_run.send_replace(true);
+
+        let mut rx_conn_lost = self.rx_conn_lost.clone();
+        let token = TokenConnection {
+            future: async move {
+                rx_connect
+                    .recv()
+                    .await
+                    .map_err(|err| ConnectionError::Failure(err.to_string()))?
+                    .map_err(ConnectionError::Failure)?;
+
+                // Only losses from here on count
+                rx_conn_lost.borrow_and_update();
+                Ok(rx_conn_lost)
+            },
+        };
+
+        Ok(token)
+    }
+
+    fn disconnect(
+        &mut self,
+    ) -> Result<impl Future<Output = Result<(), ConnectionError>> + Send + 'static, ConnectionError>
+    {
+        self.client
+            .try_disconnect()
+            .map_err(|err| ConnectionError::Failure(err.to_string()))?;
+
+        let token = TokenDisconnect {
+            future: async move { Ok(()) },
+        };
+        Ok(token)
+    }
+
+    /// Cloud-to-device msgs, as southbound commands named by their `command` property
+    fn commands(&self) -> Option<broadcast::Receiver<Command>> {
+        Some(self.tx_commands.subscribe())
+    }
+}
+
+impl Drop for SpecialIoTHub {
+    fn drop(&mut self) {
+        trace!("Dropping SpecialIoTHub");
+        let _ = self.client.try_disconnect();
+        self.shutdown.cancel();
+        self.event_handle.abort();
+    }
+}
+
+/// IoT Hub's certificates are trusted through the system's certificates
+fn tls() -> Result<TlsConfiguration, SpecialIoTHubError> {
+    let mut root_cert_store = RootCertStore::empty();
+    root_cert_store.add_parsable_certificates(
+        rustls_native_certs::load_native_certs()
+            .map_err(|err| SpecialIoTHubError::Init(err.to_string()))?,
+    );
+    let client_config = ClientConfig::builder()
+        .with_root_certificates(root_cert_store)
+        .with_no_client_auth();
+
+    Ok(TlsConfiguration::Rustls(Arc::new(client_config)))
+}
+
+fn default_token_ttl_secs() -> u64 {
+    3600
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    use data_source_core::MsgId;
+    use rumqttd::{local::LinkRx, local::LinkTx, Broker, Notification};
+    use tokio::time::{sleep, timeout};
+
+    use super::*;
+
+    /// A local broker standing in for the hub, returns its port
+    fn start_broker() -> (u16, LinkTx, LinkRx) {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let broker_config = serde_json::json!({
+            "id": 0,
+            "router": {
+                "max_connections": 10,
+                "max_outgoing_packet_count": 200,
+                "max_segment_size": 1048576,
+                "max_segment_count": 10,
+            },
+            "v4": {
+                "1": {
+                    "name": "v4-1",
+                    "listen": format!("127.0.0.1:{}", port),
+                    "next_connection_delay_ms": 1,
+                    "connections": {
+                        "connection_timeout_ms": 60000,
+                        "max_payload_size": 20480,
+                        "max_inflight_count": 100,
+                        "dynamic_filters": true,
+                    },
+                },
+            },
+        });
+        let mut broker = Broker::new(serde_json::from_value(broker_config).unwrap());
+        let (mut link_tx, link_rx) = broker.link("hub").unwrap();
+        thread::spawn(move || broker.start().unwrap());
+        link_tx.subscribe("devices/+/messages/events/#").unwrap();
+        // The broker listens from its own thread, connecting any sooner is refused
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        (port, link_tx, link_rx)
+    }
+
+    async fn connected_adapter(port: u16, token_ttl_secs: u64) -> SpecialIoTHub {
+        let config = serde_json::json!({
+            "connection_string": "HostName=127.0.0.1;DeviceId=dev-1;SharedAccessKey=c2VjcmV0LWtleQ==",
+            "transport": "tcp",
+            "port": port,
+            "token_ttl_secs": token_ttl_secs,
+        });
+        let mut adapter = SpecialIoTHub::new(&config.to_string()).unwrap();
+        let rx_conn_lost = timeout(Duration::from_secs(5), adapter.connect().await.unwrap())
+            .await
+            .unwrap();
+        assert!(rx_conn_lost.is_ok());
+        adapter
+    }
+
+    async fn publish(adapter: &mut SpecialIoTHub, msg: MsgBusData) -> MsgId {
+        timeout(Duration::from_secs(5), adapter.publish(msg).wait_for_ack())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn telemetry_and_cloud_to_device() {
+        let (port, mut link_tx, mut link_rx) = start_broker();
+        let mut adapter = connected_adapter(port, 3600).await;
+        let mut rx_cloud_to_device = adapter.cloud_to_device();
+        let mut rx_commands = adapter.commands().unwrap();
+
+        let msg = MsgBusData {
+            id: 9,
+            payload: Bytes::from_static(b"telemetry"),
+            metadata: HashMap::from([("level".to_string(), "high alarm".to_string())]),
+            ..Default::default()
+        };
+        assert_eq!(publish(&mut adapter, msg).await, 9);
+
+        let forward = tokio::task::spawn_blocking(move || loop {
+            if let Ok(Some(Notification::Forward(forward))) = link_rx.recv() {
+                return forward;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            forward.publish.topic,
+            "devices/dev-1/messages/events/level=high%20alarm"
+        );
+        assert_eq!(forward.publish.payload, "telemetry");
+
+        // The subscription is made right after the connection, give it a moment
+        sleep(Duration::from_millis(200)).await;
+        link_tx
+            .publish(
+                "devices/dev-1/messages/devicebound/%24.mid=42&command=reboot",
+                "now",
+            )
+            .unwrap();
+        let msg = timeout(Duration::from_secs(5), rx_cloud_to_device.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg.properties["$.mid"], "42");
+        assert_eq!(msg.properties["command"], "reboot");
+        assert_eq!(msg.payload, &b"now"[..]);
+        let Ok(Command::Southbound(command)) = rx_commands.try_recv() else {
+            panic!("cloud to device msg didn't go southbound");
+        };
+        assert_eq!(command.topic.as_deref(), Some("reboot"));
+        assert_eq!(command.payload, &b"now"[..]);
+    }
+
+    #[tokio::test]
+    async fn token_renewal_reconnects_quietly() {
+        let (port, _link_tx, _link_rx) = start_broker();
+        let mut adapter = connected_adapter(port, 1).await;
+        let rx_conn_lost = adapter.rx_conn_lost.clone();
+
+        // Renewed after 800ms, outlived twice over
+        sleep(Duration::from_millis(2000)).await;
+        let msg = MsgBusData {
+            id: 1,
+            ..Default::default()
+        };
+        assert_eq!(publish(&mut adapter, msg).await, 1);
+        assert!(!rx_conn_lost.has_changed().unwrap());
+    }
+}
@@ -0,0 +1,118 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use sha2::Sha256;
+
+use crate::SpecialIoTHubError;
+
+/// Characters left as is when url encoding, everything else is escaped
+pub const URL_ENCODE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// The parts of a device connection string, `HostName=..;DeviceId=..;SharedAccessKey=..`
+#[derive(Clone)]
+pub struct ConnectionString {
+    pub host_name: String,
+    pub device_id: String,
+    /// Decoded from the base64 in the connection string
+    shared_access_key: Vec<u8>,
+}
+
+impl ConnectionString {
+    pub fn parse(connection_string: &str) -> Result<ConnectionString, SpecialIoTHubError> {
+        let (mut host_name, mut device_id, mut shared_access_key) = (None, None, None);
+        for part in connection_string.split(';').filter(|part| !part.is_empty()) {
+            // The key is base64 and may end in '='
+            let Some((name, value)) = part.split_once('=') else {
+                return Err(SpecialIoTHubError::ConnectionString(format!(
+                    "[{}] is not name=value",
+                    part
+                )));
+            };
+            match name {
+                "HostName" => host_name = Some(value.to_string()),
+                "DeviceId" => device_id = Some(value.to_string()),
+                "SharedAccessKey" => shared_access_key = Some(value),
+                _ => (),
+            }
+        }
+
+        let missing = |name| SpecialIoTHubError::ConnectionString(format!("[{}] is missing", name));
+        let shared_access_key = STANDARD
+            .decode(shared_access_key.ok_or_else(|| missing("SharedAccessKey"))?)
+            .map_err(|err| {
+                SpecialIoTHubError::ConnectionString(format!("SharedAccessKey: {}", err))
+            })?;
+
+        Ok(ConnectionString {
+            host_name: host_name.ok_or_else(|| missing("HostName"))?,
+            device_id: device_id.ok_or_else(|| missing("DeviceId"))?,
+            shared_access_key,
+        })
+    }
+
+    /// MQTT username the hub expects
+    pub fn username(&self) -> String {
+        format!(
+            "{}/{}/?api-version=2021-04-12",
+            self.host_name, self.device_id
+        )
+    }
+
+    /// A SAS token, used as the MQTT password, that is valid for `ttl`
+    pub fn sas_token(&self, ttl: Duration) -> String {
+        let expiry = (SystemTime::now() + ttl)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.sas_token_expiring_at(expiry)
+    }
+
+    fn sas_token_expiring_at(&self, expiry: u64) -> String {
+        let resource = utf8_percent_encode(
+            &format!("{}/devices/{}", self.host_name, self.device_id),
+            URL_ENCODE,
+        )
+        .to_string();
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.shared_access_key)
+            .expect("hmac takes keys of any length");
+        mac.update(format!("{}\n{}", resource, expiry).as_bytes());
+        let signature = STANDARD.encode(mac.finalize().into_bytes());
+
+        format!(
+            "SharedAccessSignature sr={}&sig={}&se={}",
+            resource,
+            utf8_percent_encode(&signature, URL_ENCODE),
+            expiry
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sas_token() {
+        let connection_string = ConnectionString::parse(
+            "HostName=hub.azure-devices.net;DeviceId=dev-1;SharedAccessKey=c2VjcmV0LWtleQ==",
+        )
+        .unwrap();
+        assert_eq!(
+            connection_string.username(),
+            "hub.azure-devices.net/dev-1/?api-version=2021-04-12"
+        );
+        assert_eq!(
+            connection_string.sas_token_expiring_at(1700000000),
+            "SharedAccessSignature sr=hub.azure-devices.net%2Fdevices%2Fdev-1&sig=BRETTMM8F4rNKGqWi0e5erkyYtrLxa%2Fl65B9Mm5enOk%3D&se=1700000000"
+        );
+
+        assert!(ConnectionString::parse("HostName=hub;SharedAccessKey=c2VjcmV0").is_err());
+    }
+}
@@ -0,0 +1,26 @@
+use async_trait::async_trait;
+use cloud_adapter_core::{DeliveryError, DeliveryFailure, TokenDelivery};
+use data_source_core::MsgId;
+use tokio::sync::oneshot;
+
+pub struct DeliveryToken {
+    pub msg_id: MsgId,
+    /// Err when the publish could not be queued in the client. The sender is dropped if the connection is lost before the ack
+    pub ack: Result<oneshot::Receiver<()>, String>,
+}
+
+#[async_trait]
+impl TokenDelivery for DeliveryToken {
+    async fn wait_for_ack(self) -> Result<MsgId, DeliveryError> {
+        let rx_ack = self.ack.map_err(|reason| DeliveryError {
+            msg_id: self.msg_id,
+            reason: DeliveryFailure::Nack(reason),
+        })?;
+        rx_ack.await.map_err(|_| DeliveryError {
+            msg_id: self.msg_id,
+            reason: DeliveryFailure::Nack("connection lost before the ack".to_string()),
+        })?;
+
+        Ok(self.msg_id)
+    }
+}
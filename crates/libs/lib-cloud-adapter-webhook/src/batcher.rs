@@ -0,0 +1,153 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use cloud_adapter_core::{ConnectionLost, Envelope};
+use data_source_core::MsgBusData;
+use hmac::{Hmac, Mac};
+use reqwest::{header::CONTENT_TYPE, Client};
+use sha2::Sha256;
+use tokio::{
+    select, spawn,
+    sync::{mpsc, oneshot, watch},
+    task::JoinHandle,
+    time::{sleep_until, Duration, Instant},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace, warn};
+
+use crate::Auth;
+
+/// A msg waiting to go out in a batch
+pub struct Pending {
+    pub msg: MsgBusData,
+    pub tx_ack: oneshot::Sender<Result<(), String>>,
+}
+
+/// Everything a POST needs, shared by the batches in flight
+pub struct Poster {
+    pub client: Client,
+    pub url: String,
+    pub auth: Option<Auth>,
+    pub headers: HashMap<String, String>,
+    pub tx_conn_lost: watch::Sender<ConnectionLost>,
+    /// When the connection was last lost
+    pub lost_at: Arc<Mutex<Option<Instant>>>,
+}
+
+/// Collects msgs into a batch until it holds `max_count` msgs or `window` has passed since its first msg, then POSTs it.
+/// Batches are POSTed concurrently so a slow endpoint doesn't hold up the next batch
+pub fn spawn_batcher(
+    mut rx_pending: mpsc::Receiver<Pending>,
+    poster: Poster,
+    max_count: usize,
+    window: Duration,
+    shutdown: CancellationToken,
+) -> JoinHandle<()> {
+    let poster = Arc::new(poster);
+    spawn(async move {
+        loop {
+            let first = select! {
+                _ = shutdown.cancelled() => break,
+                pending = rx_pending.recv() => match pending {
+                    Some(pending) => pending,
+                    None => break,
+                },
+            };
+
+            let mut batch = vec![first];
+            let deadline = Instant::now() + window;
+            while batch.len() < max_count {
+                select! {
+                    _ = sleep_until(deadline) => break,
+                    pending = rx_pending.recv() => match pending {
+                        Some(pending) => batch.push(pending),
+                        None => break,
+                    },
+                }
+            }
+
+            spawn(poster.clone().post(batch));
+        }
+        debug!("Exiting webhook batcher");
+    })
+}
+
+impl Poster {
+    /// Every msg of the batch is acked by a 2xx, and nacked by anything else. Server errors and failed requests also
+    /// count as a lost connection, so the nacked msgs are replayed from persistence once it's made again
+    async fn post(self: Arc<Poster>, batch: Vec<Pending>) {
+        trace!("Posting a batch of [{}] msgs", batch.len());
+        let result = match self.request(&batch).send().await {
+            Ok(response) if response.status().is_success() => Ok(()),
+            Ok(response) => {
+                let status = response.status();
+                if status.is_server_error() {
+                    self.connection_lost(ConnectionLost::Uncategorized(status.to_string()));
+                }
+                Err(format!("webhook responded [{}]", status))
+            }
+            Err(err) if err.is_timeout() => {
+                self.connection_lost(ConnectionLost::Timeout);
+                Err("webhook timed out".to_string())
+            }
+            Err(err) => {
+                self.connection_lost(ConnectionLost::Uncategorized(err.to_string()));
+                Err(format!("webhook request failed: [{}]", err))
+            }
+        };
+
+        if let Err(reason) = &result {
+            warn!("Batch of [{}] msgs not delivered, {}", batch.len(), reason);
+        }
+        for pending in batch {
+            let _ = pending.tx_ack.send(result.clone());
+        }
+    }
+
+    fn request(&self, batch: &[Pending]) -> reqwest::RequestBuilder {
+        let envelopes: Vec<Envelope> = batch
+            .iter()
+            .map(|Pending { msg, .. }| Envelope::new(msg))
+            .collect();
+        // Serializing these can't fail, the keys are all strings
+        let body = serde_json::to_vec(&envelopes).unwrap_or_default();
+
+        let mut request = self
+            .client
+            .post(&self.url)
+            .header(CONTENT_TYPE, "application/json");
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+        match &self.auth {
+            Some(Auth::Bearer { token }) => request = request.bearer_auth(token),
+            Some(Auth::Hmac { secret, header }) => {
+                request = request.header(header, signature(secret, &body))
+            }
+            None => (),
+        }
+
+        request.body(body)
+    }
+
+    fn connection_lost(&self, reason: ConnectionLost) {
+        *self.lost_at.lock().expect("poisoned lock") = Some(Instant::now());
+        self.tx_conn_lost.send_replace(reason);
+    }
+}
+
+/// `sha256=` followed by the hex HMAC-SHA256 of the body
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac takes keys of any length");
+    mac.update(body);
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256={}", hex)
+}
@@ -0,0 +1,13 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum WebhookError {
+    #[error("initialization: [{0}]")]
+    Init(String),
+}
+
+impl From<WebhookError> for cloud_adapter_core::Error {
+    fn from(value: WebhookError) -> Self {
+        cloud_adapter_core::Error::Initialization(value.to_string())
+    }
+}
@@ -0,0 +1,363 @@
+//! # Webhook Adapter
+//!
+//! POSTs msgs in batches to a configured http(s) endpoint. A batch is a json array with one object per msg,
+//! `{"id", "topic", "metadata", "source", "timestamp", "payload"}`, and every msg in it is acked or nacked by the
+//! response status
+//!
+mod batcher;
+mod error;
+mod tokens;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use crate::batcher::{spawn_batcher, Pending, Poster};
+pub use crate::tokens::DeliveryToken;
+pub use error::WebhookError;
+
+use cloud_adapter_core::{
+    CloudAdapterTrait, ConnectionError, ConnectionLost, Error, TokenConnection, TokenDelivery,
+    TokenDisconnect,
+};
+use data_source_core::MsgBusData;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    task::JoinHandle,
+    time::{sleep_until, Duration, Instant},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace};
+
+pub struct Webhook {
+    tx_pending: mpsc::Sender<Pending>,
+    rx_conn_lost: watch::Receiver<ConnectionLost>,
+    /// When the connection was last lost, a connect waits out `retry_delay` from then
+    lost_at: Arc<Mutex<Option<Instant>>>,
+    retry_delay: Duration,
+    batcher_handle: JoinHandle<()>,
+    /// To shutdown the batcher
+    shutdown: CancellationToken,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Config {
+    pub url: String,
+    /// A batch is sent once it holds this many msgs
+    #[serde(default = "default_batch_max_count")]
+    pub batch_max_count: usize,
+    /// or once this long has passed since its first msg
+    #[serde(default = "default_batch_window_ms")]
+    pub batch_window_ms: u64,
+    /// A POST that takes longer nacks its batch
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// How long after a failed POST the connection is considered made again, and persisted msgs are replayed
+    #[serde(default = "default_retry_delay_ms")]
+    pub retry_delay_ms: u64,
+    pub auth: Option<Auth>,
+    /// Sent with every POST
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Auth {
+    /// `Authorization: Bearer {token}`
+    Bearer { token: String },
+    /// The body is signed with HMAC-SHA256, `{header}: sha256={hex}`
+    Hmac {
+        secret: String,
+        #[serde(default = "default_hmac_header")]
+        header: String,
+    },
+}
+
+impl Webhook {
+    pub fn new(config: &str) -> Result<Self, Error> {
+        let config = serde_json::from_str::<Config>(config)
+            .map_err(|err| WebhookError::Init(err.to_string()))?;
+        if config.batch_max_count == 0 {
+            return Err(
+                WebhookError::Init("batch_max_count must be at least 1".to_string()).into(),
+            );
+        }
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .build()
+            .map_err(|err| WebhookError::Init(err.to_string()))?;
+        debug!(
+            "Webhook adapter posting to [{}] in batches of up to [{}] msgs",
+            config.url, config.batch_max_count
+        );
+
+        let (tx_conn_lost, rx_conn_lost) =
+            watch::channel(ConnectionLost::Uncategorized("init".to_string()));
+        let lost_at = Arc::new(Mutex::new(None));
+        // A few batches can queue up behind the one being collected
+        let (tx_pending, rx_pending) = mpsc::channel(config.batch_max_count * 4);
+
+        let shutdown = CancellationToken::new();
+        let batcher_handle = spawn_batcher(
+            rx_pending,
+            Poster {
+                client,
+                url: config.url,
+                auth: config.auth,
+                headers: config.headers,
+                tx_conn_lost,
+                lost_at: lost_at.clone(),
+            },
+            config.batch_max_count,
+            Duration::from_millis(config.batch_window_ms),
+            shutdown.child_token(),
+        );
+
+        Ok(Self {
+            tx_pending,
+            rx_conn_lost,
+            lost_at,
+            retry_delay: Duration::from_millis(config.retry_delay_ms),
+            batcher_handle,
+            shutdown,
+        })
+    }
+}
+
+impl CloudAdapterTrait for Webhook {
+    fn publish(&mut self, msg: MsgBusData) -> impl TokenDelivery + Send + 'static {
+        trace!("Queueing [{}] for the next batch", msg.id);
+        let msg_id = msg.id;
+        let (tx_ack, rx_ack) = oneshot::channel();
+        let ack = self
+            .tx_pending
+            .try_send(Pending { msg, tx_ack })
+            .map(|()| rx_ack)
+            .map_err(|err| format!("could not queue msg for a batch: [{}]", err));
+
+        DeliveryToken { msg_id, ack }
+    }
+
+    /// There's no connection to make with http. After a failed POST the connection is made again once the retry
+    /// delay has passed
+    async fn connect(
+        &mut self,
+    ) -> Result<
+        TokenConnection<
+            impl Future<Output = Result<watch::Receiver<ConnectionLost>, ConnectionError>>
+                + Send
+                + 'static,
+        >,
+        ConnectionError,
+    > {
+        let retry_at = self
+            .lost_at
+            .lock()
+            .expect("poisoned lock")
+            .map(|lost_at| lost_at + self.retry_delay);
+        let mut rx_conn_lost = self.rx_conn_lost.clone();
+        let token = TokenConnection {
+            future: async move {
+                if let Some(retry_at) = retry_at {
+                    sleep_until(retry_at).await;
+                }
+                // Only losses from here on count
+                rx_conn_lost.borrow_and_update();
+                Ok(rx_conn_lost)
+            },
+        };
+
+        Ok(token)
+    }
+
+    fn disconnect(
+        &mut self,
+    ) -> Result<impl Future<Output = Result<(), ConnectionError>> + Send + 'static, ConnectionError>
+    {
+        let token = TokenDisconnect {
+            future: async move { Ok(()) },
+        };
+        Ok(token)
+    }
+}
+
+impl Drop for Webhook {
+    fn drop(&mut self) {
+        trace!("Dropping Webhook adapter");
+        self.shutdown.cancel();
+        self.batcher_handle.abort();
+    }
+}
+
+fn default_batch_max_count() -> usize {
+    100
+}
+
+fn default_batch_window_ms() -> u64 {
+    1000
+}
+
+fn default_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_retry_delay_ms() -> u64 {
+    5000
+}
+
+fn default_hmac_header() -> String {
+    "X-Signature-256".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        time::timeout,
+    };
+
+    use data_source_core::Bytes;
+
+    use super::*;
+
+    /// Answers each request with the next status, sending back the head and body of each
+    async fn serve(statuses: Vec<u16>) -> (String, mpsc::UnboundedReceiver<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/ingest", listener.local_addr().unwrap());
+        let (tx_request, rx_request) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                // Read the head, then as much body as it says there is
+                let (head, body) = loop {
+                    let read = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..read]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let len = head
+                            .lines()
+                            .find_map(|line| {
+                                line.to_lowercase()
+                                    .strip_prefix("content-length: ")
+                                    .map(str::to_string)
+                            })
+                            .map_or(0, |len| len.parse().unwrap());
+                        if body.len() >= len {
+                            break (head.to_string(), body.to_string());
+                        }
+                    }
+                };
+                let response = format!(
+                    "HTTP/1.1 {} X\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                tx_request.send((head, body)).unwrap();
+            }
+        });
+        (url, rx_request)
+    }
+
+    #[tokio::test]
+    async fn batch_by_count_and_sign() {
+        let (url, mut rx_request) = serve(vec![200]).await;
+        let config = serde_json::json!({
+            "url": url,
+            "batch_max_count": 2,
+            "batch_window_ms": 60_000,
+            "auth": { "type": "hmac", "secret": "shh" },
+        });
+        let mut adapter = Webhook::new(&config.to_string()).unwrap();
+
+        let first = adapter.publish(MsgBusData {
+            id: 1,
+            payload: Bytes::from_static(br#"{"temp": 20}"#),
+            ..Default::default()
+        });
+        let second = adapter.publish(MsgBusData {
+            id: 2,
+            payload: Bytes::from_static(b"not json"),
+            topic: Some("sensors".to_string()),
+            ..Default::default()
+        });
+        // The second msg fills the batch, the window never has to pass
+        assert_eq!(
+            timeout(Duration::from_secs(5), first.wait_for_ack())
+                .await
+                .unwrap()
+                .unwrap(),
+            1
+        );
+        assert_eq!(second.wait_for_ack().await.unwrap(), 2);
+
+        let (head, body) = rx_request.recv().await.unwrap();
+        assert_eq!(
+            body,
+            r#"[{"id":1,"payload":{"temp":20}},{"id":2,"topic":"sensors","payload":"not json"}]"#
+        );
+        let header = format!(
+            "x-signature-256: {}",
+            batcher::signature("shh", body.as_bytes())
+        );
+        assert!(head.lines().any(|line| line.to_lowercase() == header));
+    }
+
+    #[tokio::test]
+    async fn binary_payload_as_base64() {
+        let (url, mut rx_request) = serve(vec![200]).await;
+        let config = serde_json::json!({ "url": url, "batch_max_count": 1 });
+        let mut adapter = Webhook::new(&config.to_string()).unwrap();
+
+        // Not UTF-8, as Sparkplug B protobuf often isn't
+        let token = adapter.publish(MsgBusData {
+            id: 4,
+            payload: Bytes::from_static(&[0x08, 0xff, 0xfe, 0x00]),
+            ..Default::default()
+        });
+        timeout(Duration::from_secs(5), token.wait_for_ack())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let (_, body) = rx_request.recv().await.unwrap();
+        assert_eq!(
+            body,
+            r#"[{"id":4,"payload":"CP/+AA==","payload_encoding":"base64"}]"#
+        );
+    }
+
+    #[tokio::test]
+    async fn server_error_nacks_and_loses_connection() {
+        let (url, _rx_request) = serve(vec![503]).await;
+        let config = serde_json::json!({
+            "url": url,
+            "batch_window_ms": 10,
+            "retry_delay_ms": 100,
+            "auth": { "type": "bearer", "token": "abc" },
+        });
+        let mut adapter = Webhook::new(&config.to_string()).unwrap();
+        let rx_conn_lost = adapter.connect().await.unwrap().await.unwrap();
+
+        let token = adapter.publish(MsgBusData {
+            id: 5,
+            ..Default::default()
+        });
+        let nack = timeout(Duration::from_secs(5), token.wait_for_ack())
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(nack.msg_id, 5);
+        assert!(nack.reason.to_string().contains("503"));
+        assert!(rx_conn_lost.has_changed().unwrap());
+
+        // Made again once the retry delay has passed
+        let started = Instant::now();
+        adapter.connect().await.unwrap().await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+}
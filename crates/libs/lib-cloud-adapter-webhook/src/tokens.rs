@@ -0,0 +1,28 @@
+use async_trait::async_trait;
+use cloud_adapter_core::{DeliveryError, DeliveryFailure, TokenDelivery};
+use data_source_core::MsgId;
+use tokio::sync::oneshot;
+
+pub struct DeliveryToken {
+    pub msg_id: MsgId,
+    /// Err when the msg could not be queued for a batch. Otherwise resolves with the outcome of the batch's POST
+    pub ack: Result<oneshot::Receiver<Result<(), String>>, String>,
+}
+
+#[async_trait]
+impl TokenDelivery for DeliveryToken {
+    async fn wait_for_ack(self) -> Result<MsgId, DeliveryError> {
+        let msg_id = self.msg_id;
+        let nack = |reason| DeliveryError {
+            msg_id,
+            reason: DeliveryFailure::Nack(reason),
+        };
+        self.ack
+            .map_err(nack)?
+            .await
+            .map_err(|_| nack("batch was dropped before it was sent".to_string()))?
+            .map_err(nack)?;
+
+        Ok(msg_id)
+    }
+}
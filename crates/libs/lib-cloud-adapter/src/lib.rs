@@ -1,18 +1,21 @@
 // region:  --- Modules
 
-use std::future::Future;
-
-use cloud_adapter_core::{
-    CloudAdapterTrait, ConnectionError, ConnectionLost, TokenConnection, TokenDelivery,
-};
+use std::sync::RwLock;
+
+use cloud_adapter_core::BoxCloudAdapter;
+#[cfg(feature = "file")]
+use cloud_adapter_file::FileSink;
+#[cfg(feature = "mqtt")]
+use cloud_adapter_mqtt::Mqtt;
+#[cfg(feature = "webhook")]
+use cloud_adapter_webhook::Webhook;
 #[cfg(feature = "dev")]
 use connector_dev::Dev;
-use data_source_core::MsgBusData;
 #[cfg(feature = "special-hivemq")]
 use special_hivemq::SpecialHiveMQ;
 #[cfg(feature = "special-iothub")]
-use special_iothub::{DeliverContext, SpecialIoTHub};
-use tokio::sync::watch;
+use special_iothub::SpecialIoTHub;
+use thiserror::Error;
 
 // endregion
 
@@ -20,139 +23,111 @@ use tokio::sync::watch;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Debug)]
+#[derive(Debug, Error)]
 pub enum Error {
     /// * Adapter chosen
-    AdapterTypeNotFound(String),
+    /// * Adapters compiled into this binary
+    #[error("adapter type [{0}] not found, this build supports [{}]", .1.join(", "))]
+    AdapterTypeNotFound(String, Vec<&'static str>),
+    #[error("initialization [{0}]")]
     Initialization(String),
 }
 
-// TODO -- a macro could be written to apply to this enum.  All the code below this enum could then be auto generated
-// just like enum_dispatch. except the trait in a different library is still an issue
-// This will eventually be helpful because to support multiple different adapter types, an enum needs to be generated
-// for each token type as well
-//enum PublishReturnTypes {
-//Dev(cloud_adapter_core::TokenDelivery)
-//HiveMQ(special_hivemq::DeliveryToken),
-//IoTHub(special_iothub::DeliverContext),
-//}
-enum CloudAdapter {
-    #[cfg(feature = "dev")]
-    DevCloud(connector_dev::Dev),
-    #[cfg(feature = "special-hivemq")]
-    HiveMQ(special_hivemq::SpecialHiveMQ),
-    #[cfg(feature = "special-iothub")]
-    IoTHub(special_iothub::SpecialIoTHub),
-}
-
-// endregion
-
-// region:  --- Public Functions
+/// Constructs an adapter from its config
+pub type Constructor = fn(&str) -> Result<BoxCloudAdapter>;
+
+/// Adapters registered from outside this crate, see [register]
+static REGISTERED: RwLock<Vec<(&'static str, Constructor)>> = RwLock::new(Vec::new());
+
+/// Generates [adapter_types] and [new] from the adapters listed, each under the feature of the same name.
+/// Adding an adapter crate is a line in the list and a feature in Cargo.toml
+macro_rules! declare_adapters {
+    ($($adapter_type:literal => $constructor:expr),* $(,)?) => {
+        /// Names of the adapters compiled into this binary or registered, these are the valid `adapter_type`s for [new]
+        pub fn adapter_types() -> Vec<&'static str> {
+            let mut adapter_types = vec![$(#[cfg(feature = $adapter_type)] $adapter_type,)*];
+            let registered = REGISTERED.read().expect("poisoned lock");
+            adapter_types.extend(registered.iter().map(|(adapter_type, _)| *adapter_type));
+            adapter_types
+        }
 
-pub fn new(adapter_type: &str, credentials: &str) -> Result<impl CloudAdapterTrait> {
-    match adapter_type {
-        #[cfg(feature = "dev")]
-        "dev" => Ok(CloudAdapter::from(Dev::new())),
-        #[cfg(feature = "special-hivemq")]
-        "special-hivemq" => Ok(CloudAdapter::from(CloudAdapter::HiveMQ(
-            SpecialHiveMQ::new(credentials)
-                .map_err(|err| Error::Initialization(err.to_string()))?,
-        ))),
-        #[cfg(feature = "special-iothub")]
-        "special-iothub" => Ok(CloudAdapter::from(SpecialIoTHub::new())),
-        _ => Err(Error::AdapterTypeNotFound(adapter_type.to_string())),
-    }
+        pub fn new(adapter_type: &str, config: &str) -> Result<BoxCloudAdapter> {
+            match adapter_type {
+                $(
+                    #[cfg(feature = $adapter_type)]
+                    $adapter_type => {
+                        let adapter = ($constructor)(config)
+                            .map_err(|err| Error::Initialization(err.to_string()))?;
+                        Ok(Box::new(adapter))
+                    }
+                )*
+                _ => new_registered(adapter_type, config),
+            }
+        }
+    };
 }
 
 // endregion
 
-// region: --- Boilerplate
-
-// This is the manual way of being able to use a static dispatch in this way and the compiler will bring this down to a 0 cost abstraction.
-// It could be annoying to maintain, the `enum_dispatch` crate handles this generation for us. However I can't figure out the
-// cross library use of it, yet. It may be best to write my own
-impl CloudAdapterTrait for CloudAdapter {
-    fn publish(&mut self, msg: MsgBusData) -> impl TokenDelivery + 'static {
-        match self {
-            #[cfg(feature = "dev")]
-            CloudAdapter::DevCloud(inner) => inner.publish(msg),
-            #[cfg(feature = "special-hivemq")]
-            CloudAdapter::HiveMQ(inner) => inner.publish(msg),
-            #[cfg(feature = "special-iothub")]
-            CloudAdapter::IoTHub(inner) => inner.publish(msg),
-        }
-    }
-
-    async fn connect(
-        &mut self,
-    ) -> std::result::Result<
-        TokenConnection<
-            impl Future<
-                    Output = std::result::Result<watch::Receiver<ConnectionLost>, ConnectionError>,
-                > + 'static,
-        >,
-        ConnectionError,
-    > {
-        match self {
-            #[cfg(feature = "dev")]
-            CloudAdapter::DevCloud(inner) => inner.connect().await,
-            #[cfg(feature = "special-hivemq")]
-            CloudAdapter::HiveMQ(inner) => inner.connect().await,
-            #[cfg(feature = "special-iothub")]
-            CloudAdapter::IoTHub(inner) => inner.connect(),
-        }
-    }
+// region:  --- Public Functions
 
-    fn disconnect(
-        &mut self,
-    ) -> std::result::Result<
-        impl Future<Output = std::result::Result<(), ConnectionError>> + 'static,
-        ConnectionError,
-    > {
-        match self {
-            #[cfg(feature = "dev")]
-            CloudAdapter::DevCloud(inner) => inner.disconnect(),
-            #[cfg(feature = "special-hivemq")]
-            CloudAdapter::HiveMQ(inner) => inner.disconnect(),
-            #[cfg(feature = "special-iothub")]
-            CloudAdapter::IoTHub(inner) => inner.disconnect(),
-        }
-    }
+declare_adapters! {
+    "dev" => |_| Ok::<_, Error>(Dev::new()),
+    "mqtt" => Mqtt::new,
+    "webhook" => Webhook::new,
+    "file" => FileSink::new,
+    "special-hivemq" => SpecialHiveMQ::new,
+    "special-iothub" => SpecialIoTHub::new,
 }
 
-#[cfg(feature = "dev")]
-impl From<Dev> for CloudAdapter {
-    fn from(value: Dev) -> Self {
-        CloudAdapter::DevCloud(value)
-    }
+/// Makes an adapter that lives outside this crate available to [new]. Adapters compiled in take precedence
+pub fn register(adapter_type: &'static str, constructor: Constructor) {
+    REGISTERED
+        .write()
+        .expect("poisoned lock")
+        .push((adapter_type, constructor));
 }
 
-#[cfg(feature = "special-hivemq")]
-impl From<SpecialHiveMQ> for CloudAdapter {
-    fn from(value: SpecialHiveMQ) -> Self {
-        CloudAdapter::HiveMQ(value)
-    }
-}
+// endregion
 
-#[cfg(feature = "special-iothub")]
-impl From<SpecialHiveMQ> for CloudAdapter {
-    fn from(value: SpecialIoTHub) -> Self {
-        CloudAdapter::IoTHub(value)
+// region:  --- Private Functions
+
+fn new_registered(adapter_type: &str, config: &str) -> Result<BoxCloudAdapter> {
+    let constructor = REGISTERED
+        .read()
+        .expect("poisoned lock")
+        .iter()
+        .find(|(registered, _)| *registered == adapter_type)
+        .map(|(_, constructor)| *constructor);
+    match constructor {
+        Some(constructor) => constructor(config),
+        None => Err(Error::AdapterTypeNotFound(
+            adapter_type.to_string(),
+            adapter_types(),
+        )),
     }
 }
 
 // endregion
 
-// This is the idea of enum_dispatch
-// Any cloud adapter needs to be added to this enum
-// The impl of the enum can be completely negated in this case
-//#[enum_dispatch(CloudAdapter)]
-//enum CloudAdapterManifest {
-//SpecialHiveMQ,
-//SpecialIoTHub,
-//}
-
-//#[enum_dispatch]
-//pub trait CloudAdapter {
-//fn publish(&self) -> impl Future;
-//}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registered_adapter() {
+        register("failing", |_| {
+            Err(Error::Initialization("on purpose".to_string()))
+        });
+        assert!(adapter_types().contains(&"failing"));
+
+        let Err(Error::Initialization(reason)) = new("failing", "{}") else {
+            panic!("the registered constructor wasn't used");
+        };
+        assert_eq!(reason, "on purpose");
+        assert!(matches!(
+            new("nope", "{}"),
+            Err(Error::AdapterTypeNotFound(..))
+        ));
+    }
+}
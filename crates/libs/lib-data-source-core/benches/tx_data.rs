@@ -0,0 +1,69 @@
+//! The data source → main_loop path, with the payload as [Bytes] through [TxData] against the channel of Vec<u8> it
+//! used to be. Each msg is sent, received and handed to two adapters, as main_loop does with its fan out
+use bytes::Bytes;
+use std::{hint::black_box, time::Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use data_source_core::TxData;
+use tokio::runtime::Builder;
+
+const PAYLOAD_SIZES: [usize; 3] = [256, 4 * 1024, 64 * 1024];
+const ADAPTERS: usize = 2;
+
+/// MsgBusData as it was, with its payload copied in by TxData::send(&[u8])
+#[allow(dead_code)]
+#[derive(Clone)]
+struct VecMsg {
+    id: u32,
+    payload: Vec<u8>,
+}
+
+fn send_recv(c: &mut Criterion) {
+    let runtime = Builder::new_current_thread().build().unwrap();
+    let mut group = c.benchmark_group("send_recv");
+    for size in PAYLOAD_SIZES {
+        // What the data source got from its producer, such as an http body or mqtt publish
+        let data = Bytes::from(vec![7u8; size]);
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::new("vec_channel", size), &data, |b, data| {
+            let (tx, mut rx) = tokio::sync::mpsc::channel::<VecMsg>(100);
+            b.iter_custom(|iters| {
+                runtime.block_on(async {
+                    let start = Instant::now();
+                    for _ in 0..iters {
+                        let msg = VecMsg {
+                            id: 0,
+                            payload: data.to_vec(),
+                        };
+                        tx.send(msg).await.unwrap();
+                        let msg = rx.recv().await.unwrap();
+                        let copies: Vec<VecMsg> = (0..ADAPTERS).map(|_| msg.clone()).collect();
+                        black_box(copies);
+                    }
+                    start.elapsed()
+                })
+            });
+        });
+
+        group.bench_with_input(BenchmarkId::new("bytes_tx_data", size), &data, |b, data| {
+            let (tx, mut rx) = TxData::new();
+            b.iter_custom(|iters| {
+                runtime.block_on(async {
+                    let start = Instant::now();
+                    for _ in 0..iters {
+                        tx.send(data.clone()).await.unwrap();
+                        let msg = rx.recv().await.unwrap();
+                        let copies: Vec<_> = (0..ADAPTERS).map(|_| msg.clone()).collect();
+                        black_box(copies);
+                    }
+                    start.elapsed()
+                })
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, send_recv);
+criterion_main!(benches);
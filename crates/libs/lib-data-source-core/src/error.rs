@@ -6,6 +6,14 @@ pub enum Error {
     Initialize(String),
     #[error("publish")]
     Publish,
+    #[error("southbound [{0}]")]
+    Southbound(String),
+    /// The bridge is falling behind, the msg wasn't taken
+    #[error("overloaded")]
+    Overloaded,
+    /// The bridge has stopped taking msgs
+    #[error("closed")]
+    Closed,
     #[error("reserved {0}")]
     Reserved(String),
 }
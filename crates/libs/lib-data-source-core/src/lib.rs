@@ -10,30 +10,63 @@
 ///
 ///
 pub mod error;
-use std::sync::{
-    atomic::{AtomicU32, Ordering},
-    Arc,
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use async_trait::async_trait;
+pub use bytes::Bytes;
 use error::Error;
+use tokio::sync::mpsc::error::TrySendError;
 
-#[derive(Debug, Clone)]
+/// [boot epoch: u32][counter: u32]. The boot epoch is the seconds since the unix epoch when the bridge started, so ids
+/// keep growing across restarts, see [first_msg_id]
+pub type MsgId = u64;
+
+#[derive(Debug, Clone, Default)]
 pub struct MsgBusData {
-    pub id: u32,
-    // payload should be a &[u8] but for the ease of POC, leaving it as a Vec<u8> for now
-    pub payload: Vec<u8>,
+    pub id: MsgId,
+    /// Reference counted, so handing the msg to several adapters or to persistence doesn't copy it
+    pub payload: Bytes,
+    /// Failed delivery attempts, counted as the msg goes back to persistence after each
     pub retry_count: u32,
+    /// Topic the msg arrived on, for data sources that have topics
+    pub topic: Option<String>,
+    /// Key/values the data source received along with the msg, such as http or mqtt headers
+    pub metadata: HashMap<String, String>,
+    /// The data source the msg came in through
+    pub source: Option<String>,
+    /// When the data source took the msg in, milliseconds since the unix epoch
+    pub timestamp: u64,
+}
+
+/// What a data source knows about a msg besides its payload
+#[derive(Debug, Clone, Default)]
+pub struct MsgOrigin {
+    pub source: Option<String>,
+    pub topic: Option<String>,
+    pub metadata: HashMap<String, String>,
 }
-//pub struct MsgBusData<'a> {
-//pub data: &'a str,
-//}
 
-// Hopefully implement a ringbuffer with this at some point. For now it will just wrap a tokio channel
+/// The data source's end of the channel to the bridge. Payloads move through it without being copied, and the
+/// channel's capacity bounds how many msgs, and so how much memory, can be waiting for the bridge. Clones share the id
+/// sequence, data sources sending through clones of the same TxData never hand out the same id
 #[derive(Clone)]
 pub struct TxData {
     tx: tokio::sync::mpsc::Sender<MsgBusData>,
-    seq: Arc<AtomicU32>,
+    ids: MsgIds,
+}
+
+/// Hands out msg ids from the sequence of a [TxData], for msgs made after the data source, such as the parts of a
+/// split msg. Persistence, the journal and acks are keyed by id, so no two msgs may share one
+#[derive(Clone)]
+pub struct MsgIds {
+    seq: Arc<AtomicU64>,
 }
 
 /// Receiver of data originating from data source
@@ -41,9 +74,21 @@ pub struct RxData {
     rx: tokio::sync::mpsc::Receiver<MsgBusData>,
 }
 
+/// Sends commands from the cloud to the data source
+#[derive(Clone)]
+pub struct TxSouthbound {
+    tx: tokio::sync::mpsc::Sender<MsgBusData>,
+}
+
+/// Commands from the cloud, for the data source to deliver to its devices. The msg topic is the name of the command
+pub struct RxSouthbound {
+    rx: tokio::sync::mpsc::Receiver<MsgBusData>,
+}
+
 // Initialized so retry_count can be defined by an env variable. Maybe other things too? Like
 pub struct MsgBusDataFactory {
     retry_count: u32,
+    seq: AtomicU64,
 }
 
 #[derive(Clone)]
@@ -71,46 +116,163 @@ pub trait DataSourceInterface {
     // TODO - how can config type change to a concrete type instead of being an issue at run time if wrong?
     async fn new_data_source(tx_new_data: TxData, config: &str)
         -> Result<impl DataSourceInterface>;
+    /// Where commands from the cloud are sent. None for data sources that don't take commands
+    fn southbound(&self) -> Option<TxSouthbound> {
+        None
+    }
+}
+
+/// The first id of this boot. The boot epoch is the current time, or the one after the epoch of `last_id` when that's
+/// later, so ids stay ahead of the previous run even when the clock went back or it ran for less than a second
+/// * `last_id` - the highest id handed out before this boot, such as the newest msg in persistence
+pub fn first_msg_id(last_id: Option<MsgId>) -> MsgId {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    first_id_at(now, last_id)
+}
+
+fn first_id_at(now_secs: u64, last_id: Option<MsgId>) -> MsgId {
+    let boot_epoch = match last_id {
+        Some(last_id) => now_secs.max((last_id >> 32) + 1),
+        None => now_secs,
+    };
+    boot_epoch << 32
 }
 
 impl TxData {
     pub fn new() -> (TxData, RxData) {
-        let (tx, rx) = tokio::sync::mpsc::channel(100);
+        Self::with_capacity(100)
+    }
+
+    /// * `capacity` - msgs that can be waiting for the bridge before the data source is held up
+    pub fn with_capacity(capacity: usize) -> (TxData, RxData) {
+        Self::channel(capacity, first_msg_id(None))
+    }
+
+    /// Same as [TxData::new], with ids that come after `last_id`. See [first_msg_id]
+    pub fn resume_after(last_id: Option<MsgId>) -> (TxData, RxData) {
+        Self::with_capacity_after(100, last_id)
+    }
+
+    /// Same as [TxData::with_capacity], with ids that come after `last_id`. See [first_msg_id]
+    pub fn with_capacity_after(capacity: usize, last_id: Option<MsgId>) -> (TxData, RxData) {
+        Self::channel(capacity, first_msg_id(last_id))
+    }
+
+    fn channel(capacity: usize, first_id: MsgId) -> (TxData, RxData) {
+        let (tx, rx) = tokio::sync::mpsc::channel(capacity);
 
         let tx = TxData {
             tx,
-            seq: Arc::new(AtomicU32::new(0)),
+            ids: MsgIds {
+                seq: Arc::new(AtomicU64::new(first_id)),
+            },
         };
         let rx = RxData { rx };
         (tx, rx)
     }
 
-    /// TODO - should take in &[u8], copy it to a ring buffer I think, and then recv will convert it to MsgBusData?
-    pub fn send(&self, data: &[u8]) {
-        // TODO
-        // Copy to a ring buffer location
-        let payload = data.to_vec(); // do this instead for now
-                                     // Pass the pointer to the channel
-        let id = self.seq.fetch_add(1, Ordering::Relaxed);
-        let data = MsgBusData {
+    /// Waits while the bridge is falling behind, so the data source takes in data no faster than it's delivered.
+    /// `data` is taken without a copy when it's a [Bytes], a Vec<u8> or a String
+    pub async fn send(&self, data: impl Into<Bytes>) -> Result<()> {
+        self.send_with_metadata(data, None, HashMap::new()).await
+    }
+
+    /// Same as [TxData::send], for data sources that know the topic or metadata of the msg. Routing rules can match on these
+    pub async fn send_with_metadata(
+        &self,
+        data: impl Into<Bytes>,
+        topic: Option<String>,
+        metadata: HashMap<String, String>,
+    ) -> Result<()> {
+        let origin = MsgOrigin {
+            source: None,
+            topic,
+            metadata,
+        };
+        self.send_from(data, origin).await
+    }
+
+    /// Same as [TxData::send], with everything the data source knows about where the msg came from
+    pub async fn send_from(&self, data: impl Into<Bytes>, origin: MsgOrigin) -> Result<()> {
+        let permit = self.tx.reserve().await.map_err(|_| Error::Closed)?;
+        permit.send(self.msg(data.into(), origin));
+        Ok(())
+    }
+
+    /// Same as [TxData::send_from] without the wait, fails with [Error::Overloaded] instead. For data sources that can
+    /// tell their producers to come back later
+    pub fn try_send_from(&self, data: impl Into<Bytes>, origin: MsgOrigin) -> Result<()> {
+        let permit = self.tx.try_reserve().map_err(|err| match err {
+            TrySendError::Full(()) => Error::Overloaded,
+            TrySendError::Closed(()) => Error::Closed,
+        })?;
+        permit.send(self.msg(data.into(), origin));
+        Ok(())
+    }
+
+    /// The id sequence this and its clones take from
+    pub fn msg_ids(&self) -> MsgIds {
+        self.ids.clone()
+    }
+
+    /// Ids are only taken by msgs that made it into the channel
+    fn msg(&self, payload: Bytes, origin: MsgOrigin) -> MsgBusData {
+        let MsgOrigin {
+            source,
+            topic,
+            metadata,
+        } = origin;
+        let id = self.ids.next();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        MsgBusData {
             id,
             payload,
             retry_count: 0,
-        };
+            topic,
+            metadata,
+            source,
+            timestamp,
+        }
+    }
+}
 
-        // TODO - if this occurs there's a major issue.  This should either trigger a self-heal event or pass to a backup channel/storage
-        let _res = self
-            .tx
-            .try_send(data)
-            //.await
-            .map_err(|err| Error::Reserved(err.to_string()));
+impl MsgIds {
+    pub fn next(&self) -> MsgId {
+        self.seq.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+impl TxSouthbound {
+    pub fn new() -> (TxSouthbound, RxSouthbound) {
+        let (tx, rx) = tokio::sync::mpsc::channel(100);
+        (TxSouthbound { tx }, RxSouthbound { rx })
+    }
+
+    /// Fails if the data source isn't keeping up with the commands, or has stopped taking them
+    pub fn send(&self, command: MsgBusData) -> Result<()> {
+        self.tx
+            .try_send(command)
+            .map_err(|err| Error::Southbound(err.to_string()))
+    }
+}
+
+impl RxSouthbound {
+    pub async fn recv(&mut self) -> Option<MsgBusData> {
+        self.rx.recv().await
+    }
+
+    /// A command that has already arrived, if there is one
+    pub fn try_recv(&mut self) -> Option<MsgBusData> {
+        self.rx.try_recv().ok()
     }
 }
 
 impl RxData {
     pub async fn recv(&mut self) -> Option<MsgBusData> {
-        // todo
-        // Get the latest pointer to the ring buffer
         self.rx.recv().await
     }
 }
@@ -118,14 +280,36 @@ impl RxData {
 impl MsgBusDataFactory {
     pub fn new() -> MsgBusDataFactory {
         // TODO - fetch env varaible
-        MsgBusDataFactory { retry_count: 1 }
+        MsgBusDataFactory {
+            retry_count: 1,
+            seq: AtomicU64::new(first_msg_id(None)),
+        }
     }
 
     pub fn msg(&self, data: &[u8]) -> MsgBusData {
         MsgBusData {
-            id: 0,
-            payload: data.into(),
+            id: self.seq.fetch_add(1, Ordering::Relaxed),
+            payload: Bytes::copy_from_slice(data),
             retry_count: self.retry_count,
+            ..Default::default()
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_grow_across_boots() {
+        let now = 1_700_000_000;
+        let first = first_id_at(now, None);
+        assert_eq!(first >> 32, now);
+
+        // Restarted within the same second, or with the clock set back
+        let last = first + 41;
+        assert_eq!(first_id_at(now, Some(last)) >> 32, now + 1);
+        assert_eq!(first_id_at(now - 3600, Some(last)) >> 32, now + 1);
+        assert_eq!(first_id_at(now + 60, Some(last)) >> 32, now + 60);
+    }
+}
@@ -50,9 +50,10 @@ impl DataSourceInterface for DataSourceDev {
                 // rx is just the idea of picking up a message from source. like zmq, rabbitmq, http, etc
                 match rx.recv().await {
                     Some(msg) => {
-                        // Convert to MsgBusData
-                        let msg = msg.as_bytes();
-                        tx_new_data.send(msg);
+                        // The String becomes the payload without a copy
+                        if tx_new_data.send(msg).await.is_err() {
+                            break;
+                        }
                     }
                     None => break,
                 }
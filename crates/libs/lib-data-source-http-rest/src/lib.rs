@@ -1,4 +1,3 @@
-mod hardcoded_special_transform;
 use std::{borrow::Cow, collections::HashMap, sync::Arc, time::Duration};
 
 use axum::{
@@ -6,36 +5,48 @@ use axum::{
     async_trait,
     error_handling::HandleErrorLayer,
     extract::{self, State},
-    http::StatusCode,
-    response::IntoResponse,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
     routing::{get, post},
+    Json,
     Router,
 };
-use data_source_core::{DataSourceInterface, TxData};
+use data_source_core::{
+    error::Error, DataSourceInterface, MsgBusData, MsgOrigin, RxSouthbound, TxData, TxSouthbound,
+};
 use serde::{Deserialize, Serialize};
-use tokio::{spawn, task::JoinHandle};
+use tokio::{spawn, sync::Mutex, task::JoinHandle, time::timeout};
 use tower::{BoxError, ServiceBuilder};
 use tower_http::trace::TraceLayer;
-use tracing::{debug, error, info, trace};
-
-use crate::hardcoded_special_transform::SpecialEnvelope;
+use tracing::{debug, error, info, trace, warn};
 
 //type SharedState = Arc<RwLock<AppState>>;
 type SharedState = Arc<AppState>;
 
+/// How long a producer refused for overload is asked to wait
+const RETRY_AFTER_SECS: &str = "1";
+/// Source of the msgs taken in here
+const SOURCE: &str = "http-rest";
+
 #[derive(Serialize, Deserialize)]
 pub struct Config {
     bind_address: String,
+    /// How long a GET of /commands waits for a command to arrive. Keep it under the 10s request timeout
+    #[serde(default = "default_command_wait_ms")]
+    command_wait_ms: u64,
 }
 
 pub struct DataSourceHttpRest {
     server_handle: JoinHandle<()>,
+    tx_southbound: TxSouthbound,
 }
 
-#[derive(Clone)]
 pub struct AppState {
     // Any data that I want to access within a route call
     tx_to_mini_edge: TxData,
+    /// Commands from the cloud, waiting for a device to GET them
+    rx_southbound: Mutex<RxSouthbound>,
+    command_wait: Duration,
 }
 
 #[async_trait]
@@ -46,27 +57,18 @@ impl DataSourceInterface for DataSourceHttpRest {
     ) -> data_source_core::Result<DataSourceHttpRest> {
         let config = serde_json::from_str(config)
             .map_err(|err| data_source_core::error::Error::Initialize(err.to_string()))?;
-        let Config { bind_address } = config;
+        let Config {
+            bind_address,
+            command_wait_ms,
+        } = config;
 
+        let (tx_southbound, rx_southbound) = TxSouthbound::new();
         let shared_state = AppState {
             tx_to_mini_edge: tx_new_data,
+            rx_southbound: Mutex::new(rx_southbound),
+            command_wait: Duration::from_millis(command_wait_ms),
         };
-        let shared_state = Arc::new(shared_state);
-        // build our application with a route
-        let app = Router::new()
-            // `GET /` goes to `root`
-            .route("/", get(root))
-            .route("/health", get(health))
-            .route("/data_in", post(data_in))
-            .layer(
-                ServiceBuilder::new()
-                    .layer(HandleErrorLayer::new(handle_error))
-                    .load_shed()
-                    .concurrency_limit(1024)
-                    .timeout(Duration::from_secs(10))
-                    .layer(TraceLayer::new_for_http()),
-            )
-            .with_state(Arc::clone(&shared_state));
+        let app = app(Arc::new(shared_state));
 
         let listener = tokio::net::TcpListener::bind(&bind_address).await.unwrap();
 
@@ -78,10 +80,34 @@ impl DataSourceInterface for DataSourceHttpRest {
 
         let data_source = DataSourceHttpRest {
             server_handle: handle,
+            tx_southbound,
         };
 
         Ok(data_source)
     }
+
+    fn southbound(&self) -> Option<TxSouthbound> {
+        Some(self.tx_southbound.clone())
+    }
+}
+
+fn app(shared_state: SharedState) -> Router {
+    // build our application with a route
+    Router::new()
+        // `GET /` goes to `root`
+        .route("/", get(root))
+        .route("/health", get(health))
+        .route("/data_in", post(data_in))
+        .route("/commands", get(commands))
+        .layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(handle_error))
+                .load_shed()
+                .concurrency_limit(1024)
+                .timeout(Duration::from_secs(10))
+                .layer(TraceLayer::new_for_http()),
+        )
+        .with_state(shared_state)
 }
 
 async fn root(State(state): State<SharedState>) -> String {
@@ -105,22 +131,79 @@ pub struct DataIn {
     metadata: Option<HashMap<String, String>>,
 }
 
+/// 429 when the bridge is falling behind, the producer should send the data again later. 503 once the bridge has
+/// stopped taking data
 //#[debug_handler]
-async fn data_in(State(state): State<SharedState>, extract::Json(payload): extract::Json<DataIn>) {
+async fn data_in(
+    State(state): State<SharedState>,
+    extract::Json(payload): extract::Json<DataIn>,
+) -> Response {
     trace!("Data received at data-source");
     debug!("Data received at data-source [{:?}]", payload);
 
-    // hardcoding this to an special type for brian tucker and I's tech challenge. Eventually this should not transform into an special envelope type
-    let data = payload.data;
-    let data = match serde_json::to_string(&SpecialEnvelope::new(data)) {
-        Ok(data) => data,
+    let DataIn { data, metadata } = payload;
+
+    let origin = MsgOrigin {
+        source: Some(SOURCE.to_string()),
+        topic: None,
+        metadata: metadata.unwrap_or_default(),
+    };
+    match state.tx_to_mini_edge.try_send_from(data, origin) {
+        Ok(()) => StatusCode::OK.into_response(),
+        Err(Error::Overloaded) => {
+            warn!("Bridge is falling behind, refusing data");
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, RETRY_AFTER_SECS)],
+                "bridge is overloaded, try again later",
+            )
+                .into_response()
+        }
         Err(err) => {
-            error!("Could not convert envelope to string. [{}]", err);
-            return;
+            error!("Bridge is not taking data. [{}]", err);
+            StatusCode::SERVICE_UNAVAILABLE.into_response()
         }
+    }
+}
+
+/// A command from the cloud, as a device GETs it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CommandOut {
+    name: Option<String>,
+    metadata: HashMap<String, String>,
+    payload: String,
+}
+
+/// Long-polls for commands from the cloud. Answers with every command that has arrived, or 204 when none arrives
+/// within `command_wait_ms`
+async fn commands(State(state): State<SharedState>) -> Response {
+    let mut rx_southbound = state.rx_southbound.lock().await;
+    let first = match timeout(state.command_wait, rx_southbound.recv()).await {
+        Ok(Some(command)) => command,
+        Ok(None) | Err(_) => return StatusCode::NO_CONTENT.into_response(),
     };
+    let mut commands = vec![first];
+    while let Some(command) = rx_southbound.try_recv() {
+        commands.push(command);
+    }
+    debug!("Handing out [{}] commands", commands.len());
 
-    state.tx_to_mini_edge.send(data.as_bytes());
+    let commands: Vec<CommandOut> = commands
+        .into_iter()
+        .map(
+            |MsgBusData {
+                 topic,
+                 metadata,
+                 payload,
+                 ..
+             }| CommandOut {
+                name: topic,
+                metadata,
+                payload: String::from_utf8_lossy(&payload).to_string(),
+            },
+        )
+        .collect();
+    Json(commands).into_response()
 }
 
 async fn handle_error(error: BoxError) -> impl IntoResponse {
@@ -140,3 +223,76 @@ async fn handle_error(error: BoxError) -> impl IntoResponse {
         Cow::from(format!("Unhandled internal error: {error}")),
     )
 }
+
+fn default_command_wait_ms() -> u64 {
+    5000
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn long_poll_commands() {
+        let (tx_new_data, _rx_new_data) = TxData::new();
+        let (tx_southbound, rx_southbound) = TxSouthbound::new();
+        let app = app(Arc::new(AppState {
+            tx_to_mini_edge: tx_new_data,
+            rx_southbound: Mutex::new(rx_southbound),
+            command_wait: Duration::from_millis(50),
+        }));
+        let get_commands = || Request::get("/commands").body(Body::empty()).unwrap();
+
+        let response = app.clone().oneshot(get_commands()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        for (name, payload) in [("setpoint", "21.5"), ("mode", "heat")] {
+            tx_southbound
+                .send(MsgBusData {
+                    topic: Some(name.to_string()),
+                    payload: payload.into(),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        let response = app.oneshot(get_commands()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let commands: Vec<CommandOut> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[0].name.as_deref(), Some("setpoint"));
+        assert_eq!(commands[1].payload, "heat");
+    }
+
+    #[tokio::test]
+    async fn data_in_overloaded() {
+        let (tx_new_data, rx_new_data) = TxData::with_capacity(1);
+        let (_tx_southbound, rx_southbound) = TxSouthbound::new();
+        let app = app(Arc::new(AppState {
+            tx_to_mini_edge: tx_new_data,
+            rx_southbound: Mutex::new(rx_southbound),
+            command_wait: Duration::from_millis(50),
+        }));
+        let post_data = || {
+            Request::post("/data_in")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"data": "21.5"}"#))
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(post_data()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.clone().oneshot(post_data()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], RETRY_AFTER_SECS);
+
+        drop(rx_new_data);
+        let response = app.oneshot(post_data()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
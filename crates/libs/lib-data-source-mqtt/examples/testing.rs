@@ -23,7 +23,7 @@ pub async fn main() {
 
     // Create the data source (this is what we are trying to test/play with)
     let (tx, mut rx) = TxData::new();
-    let data_source = DataSourceMQTT::new_data_source(tx, "todo").await.unwrap();
+    let data_source = DataSourceMQTT::new_data_source(tx, "{}").await.unwrap();
 
     // Create a client, so we can see how the data source reacts
     let mut mqttoptions = MqttOptions::new("test-1", "localhost", 1883);
@@ -1,16 +1,21 @@
-mod hardcoded_special_transform;
-
 use async_trait::async_trait;
-use data_source_core::{DataSourceInterface, TxData};
-use rumqttd::{Broker, Config, Notification};
-use tracing::{debug, info};
+use data_source_core::{DataSourceInterface, MsgOrigin, RxSouthbound, TxData, TxSouthbound};
+use rumqttd::{local::LinkTx, Broker, Config, Notification};
+use serde::Deserialize;
+use tracing::{debug, info, warn};
 
 use std::thread;
 
-use crate::hardcoded_special_transform::SpecialEnvelope;
-
 pub struct DataSourceMQTT {
     reserved: u32,
+    tx_southbound: TxSouthbound,
+}
+
+#[derive(Deserialize)]
+struct SourceConfig {
+    /// Commands from the cloud are published on `{command_topic}/{command name}`. Msgs under it aren't sent north
+    #[serde(default = "default_command_topic")]
+    command_topic: String,
 }
 
 #[async_trait]
@@ -19,6 +24,9 @@ impl DataSourceInterface for DataSourceMQTT {
         tx_new_data: TxData,
         config: &str,
     ) -> data_source_core::Result<DataSourceMQTT> {
+        let SourceConfig { command_topic } = serde_json::from_str(config)
+            .map_err(|err| data_source_core::error::Error::Initialize(err.to_string()))?;
+
         // TODO -- change loading this mqttd to be read in through the config passed in here
         let config = config::Config::builder()
             .add_source(config::File::with_name("rumqttd.toml"))
@@ -34,6 +42,16 @@ impl DataSourceInterface for DataSourceMQTT {
         });
 
         link_tx.subscribe("#").unwrap();
+        let (tx_southbound, rx_southbound) = TxSouthbound::new();
+        let is_command = {
+            let command_topic = command_topic.clone();
+            move |topic: &str| {
+                topic
+                    .strip_prefix(command_topic.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            }
+        };
+        tokio::spawn(publish_commands(link_tx, rx_southbound, command_topic));
 
         let mut count = 0;
         let rx_handle = tokio::spawn(async move {
@@ -44,6 +62,9 @@ impl DataSourceInterface for DataSourceMQTT {
                 };
 
                 match notification {
+                    // The commands published for the devices
+                    Notification::Forward(forward)
+                        if is_command(&String::from_utf8_lossy(&forward.publish.topic)) => {}
                     Notification::Forward(forward) => {
                         count += 1;
                         debug!(
@@ -53,19 +74,19 @@ impl DataSourceInterface for DataSourceMQTT {
                             forward.publish.payload.len()
                         );
 
-                        // TODO - don't hardcode this
-                        // Need to hardcode the transform here for quick dev of this and showcasing mqtt works
-                        let data = SpecialEnvelope::new(
-                            String::from_utf8(forward.publish.payload.to_vec()).unwrap(),
-                        );
-                        let data = match serde_json::to_string(&data) {
-                            Ok(data) => data,
-                            Err(err) => {
-                                eprintln!("Could not convert envelope to string. [{}]", err);
-                                return;
-                            }
+                        let topic = String::from_utf8_lossy(&forward.publish.topic).to_string();
+                        // Waiting here holds up the broker, which slows down the devices publishing to it
+                        let origin = MsgOrigin {
+                            source: Some("mqtt".to_string()),
+                            topic: Some(topic),
+                            metadata: Default::default(),
                         };
-                        tx_new_data.send(data.as_bytes());
+                        if let Err(err) =
+                            tx_new_data.send_from(forward.publish.payload, origin).await
+                        {
+                            warn!("Bridge is not taking data, stopping. [{}]", err);
+                            return;
+                        }
                     }
                     v => {
                         debug!("Notification: {v:?}");
@@ -74,6 +95,35 @@ impl DataSourceInterface for DataSourceMQTT {
             }
         });
 
-        Ok(DataSourceMQTT { reserved: 0 })
+        Ok(DataSourceMQTT {
+            reserved: 0,
+            tx_southbound,
+        })
     }
+
+    fn southbound(&self) -> Option<TxSouthbound> {
+        Some(self.tx_southbound.clone())
+    }
+}
+
+/// Republishes the commands from the cloud to the devices on the broker
+async fn publish_commands(
+    mut link_tx: LinkTx,
+    mut rx_southbound: RxSouthbound,
+    command_topic: String,
+) {
+    while let Some(command) = rx_southbound.recv().await {
+        let topic = match &command.topic {
+            Some(name) => format!("{}/{}", command_topic, name),
+            None => command_topic.clone(),
+        };
+        debug!("Publishing command on [{}]", topic);
+        if let Err(err) = link_tx.try_publish(topic, command.payload) {
+            warn!("Could not publish command. [{}]", err);
+        }
+    }
+}
+
+fn default_command_topic() -> String {
+    "rusty-bridge/commands".to_string()
 }
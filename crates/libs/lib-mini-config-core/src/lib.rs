@@ -23,13 +23,38 @@ pub struct Credentials {
 //pub data_source: String,
 //}
 
+#[derive(Debug)]
+pub struct NorthAdapterConfig {
+    /// Which of the compiled in cloud adapters this is for, ie. "special-hivemq"
+    pub adapter_type: String,
+    pub config: String,
+}
+
+#[derive(Debug)]
+pub struct TransformConfig {
+    /// Which of the compiled in transforms this is, ie. "dev"
+    pub transform_type: String,
+    pub config: String,
+}
+
 #[derive(Debug)]
 pub struct ConfigData {
     pub data_source: String,
-    pub north_adapters: String,
+    /// Every msg is delivered to each of these
+    pub north_adapters: Vec<NorthAdapterConfig>,
+    /// Every msg goes through these in order before it's delivered, msgs are delivered as they came in without any
+    pub transforms: Vec<TransformConfig>,
     pub edge_reporter: String,
     pub metrics_server: String,
     pub persistence: String,
+    /// Which north adapters msgs are delivered to. Every msg goes to every adapter without it
+    pub routing: Option<String>,
+    /// How reconnection attempts are spaced out, shared by every north adapter
+    pub reconnect: Option<String>,
+    /// When an adapter counts as degraded or its circuit trips, shared by every north adapter
+    pub circuit_breaker: Option<String>,
+    /// How msgs are delivered by every north adapter, such as how long an ack is waited on
+    pub delivery: Option<String>,
     pub file_uploads: Option<String>,
 }
 
@@ -1,7 +1,9 @@
 use std::env;
 
 use async_trait::async_trait;
-use mini_config_core::{ConfigData, Error, MiniConfigInterface, Result};
+use mini_config_core::{
+    ConfigData, Error, MiniConfigInterface, NorthAdapterConfig, Result, TransformConfig,
+};
 use serde::{Deserialize, Serialize};
 use tracing::warn;
 
@@ -22,15 +24,37 @@ struct DataSourceSpecial {
 #[derive(Serialize, Deserialize)]
 struct DataSourceHttpRest {
     bind_address: String,
+    /// Anything else is passed on to the data source as is, ie. `command_wait_ms`
+    #[serde(flatten)]
+    other: serde_json::Map<String, serde_json::Value>,
 }
 
-// For special hivemq
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize)]
 struct NorthAdapter {
-    username: String,
-    password: String,
-    ana_endpoint: String,
-    mqtt_endpoint: String,
+    /// The cloud adapter to use, ie. "special-hivemq"
+    #[serde(rename = "type")]
+    adapter_type: String,
+    /// Everything else is handed to the adapter as its configuration
+    #[serde(flatten)]
+    config: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct Transform {
+    /// The transform to use, ie. "dev"
+    #[serde(rename = "type")]
+    transform_type: String,
+    /// Everything else is handed to the transform as its configuration
+    #[serde(flatten)]
+    config: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Either a single `[north_adapter]` table or an array of `[[north_adapter]]` tables
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NorthAdapters {
+    One(NorthAdapter),
+    Many(Vec<NorthAdapter>),
 }
 
 #[derive(Deserialize, Serialize)]
@@ -49,6 +73,16 @@ struct MetricsServer {
 struct Persistence {
     enabled: bool,
     highwater_mb: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    eviction_policy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    segment_size_kb: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    write_ahead: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    journal_path: Option<String>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -59,10 +93,17 @@ struct FileUploader {
 #[derive(Deserialize)]
 struct TomlData {
     data_source: DataSourceHttpRest,
-    north_adapter: NorthAdapter,
+    north_adapter: NorthAdapters,
+    /// `[[transform]]` tables, run in the order they are written
+    #[serde(default)]
+    transform: Vec<Transform>,
     edge_reporter: EdgeReporter,
     metrics_server: MetricsServer,
     persistence: Persistence,
+    routing: Option<serde_json::Value>,
+    reconnect: Option<serde_json::Value>,
+    circuit_breaker: Option<serde_json::Value>,
+    delivery: Option<serde_json::Value>,
     file_uploader: Option<FileUploader>,
 }
 
@@ -94,14 +135,57 @@ impl MiniConfigInterface for MiniConfigDev {
 
         let data_source = serde_json::to_string(&data.data_source)
             .map_err(|err| Error::GetConfig(err.to_string()))?;
-        let north_adapters = serde_json::to_string(&data.north_adapter)
-            .map_err(|err| Error::GetConfig(err.to_string()))?;
+        let north_adapters = match data.north_adapter {
+            NorthAdapters::One(adapter) => vec![adapter],
+            NorthAdapters::Many(adapters) => adapters,
+        };
+        let north_adapters = north_adapters
+            .into_iter()
+            .map(|adapter| {
+                Ok(NorthAdapterConfig {
+                    adapter_type: adapter.adapter_type,
+                    config: serde_json::to_string(&adapter.config)
+                        .map_err(|err| Error::GetConfig(err.to_string()))?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let transforms = data
+            .transform
+            .into_iter()
+            .map(|transform| {
+                Ok(TransformConfig {
+                    transform_type: transform.transform_type,
+                    config: serde_json::to_string(&transform.config)
+                        .map_err(|err| Error::GetConfig(err.to_string()))?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
         let edge_reporter = serde_json::to_string(&data.edge_reporter)
             .map_err(|err| Error::GetConfig(err.to_string()))?;
         let metrics_server = serde_json::to_string(&data.metrics_server)
             .map_err(|err| Error::GetConfig(err.to_string()))?;
         let persistence = serde_json::to_string(&data.persistence)
             .map_err(|err| Error::GetConfig(err.to_string()))?;
+        let routing = data
+            .routing
+            .map(|routing| serde_json::to_string(&routing))
+            .transpose()
+            .map_err(|err| Error::GetConfig(err.to_string()))?;
+        let reconnect = data
+            .reconnect
+            .map(|reconnect| serde_json::to_string(&reconnect))
+            .transpose()
+            .map_err(|err| Error::GetConfig(err.to_string()))?;
+        let circuit_breaker = data
+            .circuit_breaker
+            .map(|circuit_breaker| serde_json::to_string(&circuit_breaker))
+            .transpose()
+            .map_err(|err| Error::GetConfig(err.to_string()))?;
+        let delivery = data
+            .delivery
+            .map(|delivery| serde_json::to_string(&delivery))
+            .transpose()
+            .map_err(|err| Error::GetConfig(err.to_string()))?;
         if let Some(_file_uploader) = data.file_uploader {
             todo!()
         }
@@ -110,9 +194,14 @@ impl MiniConfigInterface for MiniConfigDev {
         let config_data = ConfigData {
             data_source,
             north_adapters,
+            transforms,
             edge_reporter,
             metrics_server,
             persistence,
+            routing,
+            reconnect,
+            circuit_breaker,
+            delivery,
             file_uploads: None,
         };
 
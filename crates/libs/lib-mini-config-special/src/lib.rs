@@ -3,15 +3,17 @@ mod edge_reporter;
 mod metrics_server;
 mod north_adapters;
 mod persistence;
+mod transforms;
 
 use async_trait::async_trait;
 use data_source::get_data_source;
 use edge_reporter::get_edge_reporter;
 use metrics_server::get_metrics_server;
-use mini_config_core::{ConfigData, Error, MiniConfigInterface, Result};
+use mini_config_core::{ConfigData, Error, MiniConfigInterface, NorthAdapterConfig, Result};
 use north_adapters::get_north_adapters;
 use persistence::get_persistence;
 use tracing::trace;
+use transforms::get_transforms;
 
 const SERVER_TYPE: &str = "connector";
 
@@ -41,10 +43,18 @@ impl MiniConfigInterface for MiniConfigSpecial {
             let data_source = serde_json::to_string(&data_source)
                 .map_err(|err| Error::GetConfig(err.to_string()))?;
 
-            // TODO - only 1 adapter is supported at the moment
-            let north_adapters = get_north_adapters("tmp".to_string())?;
-            let north_adapters = serde_json::to_string(&north_adapters)
-                .map_err(|err| Error::GetConfig(err.to_string()))?;
+            let north_adapters = get_north_adapters("tmp".to_string())?
+                .iter()
+                .map(|adapter| {
+                    Ok(NorthAdapterConfig {
+                        adapter_type: adapter.adapter_type().to_string(),
+                        config: serde_json::to_string(adapter)
+                            .map_err(|err| Error::GetConfig(err.to_string()))?,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            let transforms = get_transforms("tmp".to_string())?;
 
             let edge_reporter = get_edge_reporter("tmp".to_string())?;
             let edge_reporter = serde_json::to_string(&edge_reporter)
@@ -61,9 +71,14 @@ impl MiniConfigInterface for MiniConfigSpecial {
             let config_data = ConfigData {
                 data_source,
                 north_adapters: north_adapters,
+                transforms,
                 edge_reporter,
                 metrics_server,
                 persistence,
+                routing: None,
+                reconnect: None,
+                circuit_breaker: None,
+                delivery: None,
                 file_uploads: None,
             };
             trace!("Loaded config: [{:?}]", config_data);
@@ -12,11 +12,23 @@ pub enum NorthAdapter {
     IoTHub(special_iothub::Config),
 }
 
-pub fn get_north_adapters(tmp: String) -> mini_config_core::Result<NorthAdapter> {
+impl NorthAdapter {
+    /// Name of the cloud adapter this configuration is for
+    pub fn adapter_type(&self) -> &'static str {
+        match self {
+            NorthAdapter::HiveMQ(_) => "special-hivemq",
+            NorthAdapter::IoTHub(_) => "special-iothub",
+        }
+    }
+}
+
+/// One adapter configuration per server. Each needs its own credentials, which are not available per server yet
+pub fn get_north_adapters(tmp: String) -> mini_config_core::Result<Vec<NorthAdapter>> {
     // The way this data is obtained is different depending if factory provisioning or a regular protocol is used
     let servers = vec!["server1".to_string(), "server2".to_string()];
 
-    // Multiple servers are not yet supported unfortunately
+    // Multiple servers are not yet supported unfortunately. With the same credentials every adapter would connect with
+    // the same client id, and the broker would keep disconnecting one to let the other in
     if servers.len() > 1 {
         return Err(Error::GetConfig(format!(
             "Only 1 server is currently supported, multiple were found. [{:?}]",
@@ -32,9 +44,11 @@ pub fn get_north_adapters(tmp: String) -> mini_config_core::Result<NorthAdapter>
         password: "password".to_string(),
         ana_endpoint: "ana_endpoint".to_string(),
         mqtt_endpoint: "mqtt_endpoint".to_string(),
+        death_will: true,
+        reconnect: Default::default(),
     });
 
-    Ok(config)
+    Ok(vec![config])
 }
 
 type Username = String;
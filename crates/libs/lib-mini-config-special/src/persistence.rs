@@ -1,3 +1,5 @@
 pub fn get_persistence(tmp: String) -> mini_config_core::Result<persistence_sled::Config> {
-    Ok(persistence_sled::Config { _reserved: 0 })
+    Ok(persistence_sled::Config {
+        path: "persistence.db".to_string(),
+    })
 }
@@ -0,0 +1,50 @@
+use mini_config_core::TransformConfig;
+use serde_json::json;
+
+/// The data sources no longer wrap msgs, the cloud still expects the envelope they used to put them in
+pub fn get_transforms(tmp: String) -> mini_config_core::Result<Vec<TransformConfig>> {
+    let envelope = json!({
+        "payload": "string",
+        "ops": [
+            {"op": "move", "from": "", "to": "/data/message"},
+            {"op": "timestamp", "to": "/timestampMs", "format": "rfc3339"},
+            {"op": "set", "to": "/version", "value": "1.0.0"},
+        ]
+    });
+
+    Ok(vec![TransformConfig {
+        transform_type: "json-map".to_string(),
+        config: envelope.to_string(),
+    }])
+}
+
+#[cfg(test)]
+mod tests {
+    use data_source_core::{Bytes, MsgBusData};
+    use msg_transform_core::MsgTransform;
+    use msg_transform_json_map::TransformJsonMap;
+    use serde_json::Value;
+
+    use super::*;
+
+    #[test]
+    fn wraps_in_envelope() {
+        let transforms = get_transforms("tmp".to_string()).unwrap();
+        let mut transform = TransformJsonMap::new(&transforms[0].config).unwrap();
+        let msg = MsgBusData {
+            payload: Bytes::from_static(b"temp=20"),
+            timestamp: 1_712_062_725_231,
+            ..Default::default()
+        };
+        let msgs = transform.transform(msg).unwrap();
+        let envelope: Value = serde_json::from_slice(&msgs[0].payload).unwrap();
+        assert_eq!(
+            envelope,
+            json!({
+                "data": {"message": "temp=20"},
+                "timestampMs": "2024-04-02T12:58:45.231Z",
+                "version": "1.0.0",
+            })
+        );
+    }
+}
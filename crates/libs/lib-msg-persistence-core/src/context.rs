@@ -0,0 +1,142 @@
+//! Encoding of what a msg carries besides its id, retry count and payload, shared by the backends that store bytes
+//!
+//! Layout: [timestamp: u64 BE][source: opt str][topic: opt str][metadata_len: u32 BE][(key: str, value: str)..]
+//! A str is [len: u32 BE][utf8..], an opt str that is None has a len of u32::MAX
+use std::collections::HashMap;
+
+use data_source_core::{MsgBusData, MsgId};
+
+use crate::{Error, Result};
+
+const NONE_LEN: u32 = u32::MAX;
+
+/// Appends the context of `msg` to `out`
+pub fn encode_context(msg: &MsgBusData, out: &mut Vec<u8>) {
+    out.extend_from_slice(&msg.timestamp.to_be_bytes());
+    put_opt_str(out, msg.source.as_deref());
+    put_opt_str(out, msg.topic.as_deref());
+    out.extend_from_slice(&(msg.metadata.len() as u32).to_be_bytes());
+    for (key, value) in &msg.metadata {
+        put_str(out, key);
+        put_str(out, value);
+    }
+}
+
+/// Bytes [encode_context] appends for `msg`
+pub fn context_len(msg: &MsgBusData) -> usize {
+    let opt_str_len = |value: Option<&str>| 4 + value.map_or(0, str::len);
+    let metadata_len: usize = msg
+        .metadata
+        .iter()
+        .map(|(key, value)| 8 + key.len() + value.len())
+        .sum();
+    8 + opt_str_len(msg.source.as_deref()) + opt_str_len(msg.topic.as_deref()) + 4 + metadata_len
+}
+
+/// Fills in the context of `msg` from what [encode_context] wrote
+pub fn decode_context(bytes: &[u8], msg: &mut MsgBusData) -> Result<()> {
+    let mut reader = Reader {
+        bytes,
+        msg_id: msg.id,
+    };
+    msg.timestamp = u64::from_be_bytes(reader.take(8)?.try_into().unwrap_or_default());
+    msg.source = reader.opt_str()?;
+    msg.topic = reader.opt_str()?;
+    let metadata_len = reader.u32()?;
+    let mut metadata = HashMap::new();
+    for _ in 0..metadata_len {
+        let key = reader.str()?;
+        metadata.insert(key, reader.str()?);
+    }
+    msg.metadata = metadata;
+
+    Ok(())
+}
+
+fn put_str(out: &mut Vec<u8>, value: &str) {
+    out.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn put_opt_str(out: &mut Vec<u8>, value: Option<&str>) {
+    match value {
+        Some(value) => put_str(out, value),
+        None => out.extend_from_slice(&NONE_LEN.to_be_bytes()),
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    msg_id: MsgId,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < len {
+            return Err(Error::Decode(format!(
+                "context of msg [{}] is cut short",
+                self.msg_id
+            )));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(
+            self.take(4)?.try_into().unwrap_or_default(),
+        ))
+    }
+
+    fn str(&mut self) -> Result<String> {
+        match self.opt_str()? {
+            Some(value) => Ok(value),
+            None => Err(Error::Decode(format!(
+                "context of msg [{}] is missing a string",
+                self.msg_id
+            ))),
+        }
+    }
+
+    fn opt_str(&mut self) -> Result<Option<String>> {
+        let len = self.u32()?;
+        if len == NONE_LEN {
+            return Ok(None);
+        }
+        let bytes = self.take(len as usize)?;
+        String::from_utf8(bytes.to_vec())
+            .map(Some)
+            .map_err(|err| Error::Decode(format!("context of msg [{}]. [{}]", self.msg_id, err)))
+    }
+}
+
+#[test]
+fn round_trip() {
+    let msg = MsgBusData {
+        id: 3,
+        timestamp: 1_700_000_000_000,
+        source: Some("mqtt".to_string()),
+        topic: None,
+        metadata: HashMap::from([
+            ("site".to_string(), "north".to_string()),
+            ("unit".to_string(), String::new()),
+        ]),
+        ..Default::default()
+    };
+    let mut bytes = Vec::new();
+    encode_context(&msg, &mut bytes);
+    assert_eq!(context_len(&msg), bytes.len());
+
+    let mut decoded = MsgBusData {
+        id: 3,
+        ..Default::default()
+    };
+    decode_context(&bytes, &mut decoded).unwrap();
+    assert_eq!(decoded.timestamp, msg.timestamp);
+    assert_eq!(decoded.source, msg.source);
+    assert_eq!(decoded.topic, None);
+    assert_eq!(decoded.metadata, msg.metadata);
+
+    assert!(decode_context(&bytes[..bytes.len() - 1], &mut decoded).is_err());
+}
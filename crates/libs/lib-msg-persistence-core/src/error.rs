@@ -0,0 +1,13 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("initialization [{0}]")]
+    Initialization(String),
+    #[error("write [{0}]")]
+    Write(String),
+    #[error("read [{0}]")]
+    Read(String),
+    #[error("decode [{0}]")]
+    Decode(String),
+}
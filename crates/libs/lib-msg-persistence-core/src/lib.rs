@@ -0,0 +1,36 @@
+pub mod context;
+mod error;
+pub use error::Error;
+
+use data_source_core::{MsgBusData, MsgId};
+
+pub type Result<T> = core::result::Result<T, error::Error>;
+
+/// A store-and-forward backend. Msgs are kept by id until they are acked
+pub trait MsgPersistenceInterface: Clone + Send + Sync + 'static {
+    /// Where the backend keeps its data when the config has no `path`
+    const DEFAULT_PATH: &'static str;
+
+    /// * `config` - json of the persistence section of the configuration
+    fn new_persistence(config: &str) -> Result<Self>;
+    /// Store a msg. A msg with the same id is overwritten
+    fn put(&self, msg: &MsgBusData) -> Result<()>;
+    /// Removes a msg, usually because it was acknowledged. Acking an id that isn't stored is not an error
+    fn ack(&self, msg_id: MsgId) -> Result<()>;
+    /// Removes the msg with the lowest id, returning its id
+    fn ack_oldest(&self) -> Result<Option<MsgId>>;
+    /// Reads up to `max_count` msgs in id order, starting at `from_id` (inclusive)
+    fn iterate_from(&self, from_id: MsgId, max_count: usize) -> Result<Vec<MsgBusData>>;
+    /// The highest id stored. Ids handed out after a restart start past it
+    fn last_id(&self) -> Result<Option<MsgId>>;
+    /// Number of msgs stored
+    fn count(&self) -> usize;
+    /// Bytes `put` writes for `msg`: its payload, context, header and key. The persistence quota is measured in these
+    fn record_len(&self, msg: &MsgBusData) -> u64;
+    /// Bytes of the msgs stored, the sum of their [MsgPersistenceInterface::record_len]
+    fn size_bytes(&self) -> u64;
+    /// Reclaims the space held by acked msgs and flushes to disk, for backends that have any
+    fn compact(&self) -> Result<()>;
+    /// Blocks until everything written so far is on disk, for backends that have one
+    fn flush(&self) -> Result<()>;
+}
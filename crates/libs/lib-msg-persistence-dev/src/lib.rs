@@ -1 +1,104 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
 
+use data_source_core::{MsgBusData, MsgId};
+use msg_persistence_core::{context::context_len, MsgPersistenceInterface, Result};
+use tracing::{debug, trace};
+
+/// In-memory persistence for development. Nothing survives a restart
+#[derive(Clone, Default)]
+pub struct PersistenceDev {
+    store: Arc<Mutex<Store>>,
+}
+
+#[derive(Default)]
+struct Store {
+    msgs: BTreeMap<MsgId, MsgBusData>,
+    /// Sum of the record lengths stored
+    record_bytes: u64,
+}
+
+impl MsgPersistenceInterface for PersistenceDev {
+    /// Nothing is written to disk
+    const DEFAULT_PATH: &'static str = "";
+
+    fn new_persistence(_config: &str) -> Result<PersistenceDev> {
+        debug!("Using in-memory dev persistence, msgs will not survive a restart");
+        Ok(PersistenceDev::default())
+    }
+
+    fn put(&self, msg: &MsgBusData) -> Result<()> {
+        trace!("Persisting msg [{}]", msg.id);
+        let record_len = self.record_len(msg);
+        let mut store = self.store.lock().expect("poisoned lock");
+        store.record_bytes += record_len;
+        if let Some(replaced) = store.msgs.insert(msg.id, msg.clone()) {
+            store.record_bytes -= self.record_len(&replaced);
+        }
+
+        Ok(())
+    }
+
+    fn ack(&self, msg_id: MsgId) -> Result<()> {
+        let mut store = self.store.lock().expect("poisoned lock");
+        if let Some(removed) = store.msgs.remove(&msg_id) {
+            store.record_bytes -= self.record_len(&removed);
+            trace!("Removed msg [{}] from persistence", msg_id);
+        }
+
+        Ok(())
+    }
+
+    fn ack_oldest(&self) -> Result<Option<MsgId>> {
+        let mut store = self.store.lock().expect("poisoned lock");
+        let Some((id, removed)) = store.msgs.pop_first() else {
+            return Ok(None);
+        };
+        store.record_bytes -= self.record_len(&removed);
+
+        Ok(Some(id))
+    }
+
+    fn iterate_from(&self, from_id: MsgId, max_count: usize) -> Result<Vec<MsgBusData>> {
+        let msgs = self
+            .store
+            .lock()
+            .expect("poisoned lock")
+            .msgs
+            .range(from_id..)
+            .take(max_count)
+            .map(|(_, msg)| msg.clone())
+            .collect();
+
+        Ok(msgs)
+    }
+
+    fn last_id(&self) -> Result<Option<MsgId>> {
+        let store = self.store.lock().expect("poisoned lock");
+        Ok(store.msgs.last_key_value().map(|(id, _)| *id))
+    }
+
+    fn count(&self) -> usize {
+        self.store.lock().expect("poisoned lock").msgs.len()
+    }
+
+    /// What a msg would take up on disk, there are no headers or keys in memory
+    fn record_len(&self, msg: &MsgBusData) -> u64 {
+        (msg.payload.len() + context_len(msg)) as u64
+    }
+
+    fn size_bytes(&self) -> u64 {
+        self.store.lock().expect("poisoned lock").record_bytes
+    }
+
+    /// Nothing to reclaim, acked msgs are dropped immediately
+    fn compact(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,647 @@
+//! # Log Persistence
+//!
+//! Store-and-forward queue kept in segmented, append-only files. Every put and every ack is a record appended to the
+//! newest segment, nothing is rewritten in place, which keeps write amplification low on flash storage.
+//! An in-memory index points at the newest put of every msg that hasn't been acked.
+//!
+//! Segments are deleted once none of their msgs are waiting anymore. [MsgPersistenceInterface::compact] moves the few
+//! msgs still waiting in mostly acked segments to the newest segment, so the old ones can be deleted
+//!
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use data_source_core::{Bytes, MsgBusData, MsgId};
+use msg_persistence_core::{
+    context::{context_len, decode_context, encode_context},
+    Error, MsgPersistenceInterface, Result,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, trace, warn};
+
+const DEFAULT_PATH: &str = "persistence-log";
+const DEFAULT_SEGMENT_SIZE_KB: u32 = 1024;
+const SEGMENT_EXTENSION: &str = "log";
+
+// Record layout:
+// [crc32: u32 BE][kind: u8][msg_id: u64 BE][retry_count: u32 BE][payload_len: u32 BE][context_len: u32 BE][payload..][context..]
+// The crc covers everything after itself. Acks have no context, see msg_persistence_core::context for its layout
+const HEADER_LEN: usize = 25;
+const KIND_PUT: u8 = 1;
+const KIND_ACK: u8 = 2;
+
+#[derive(Serialize, Deserialize)]
+pub struct Config {
+    /// Directory the segments live in
+    #[serde(default = "default_path")]
+    pub path: String,
+    /// A new segment is started once the current one reaches this size
+    #[serde(default = "default_segment_size_kb")]
+    pub segment_size_kb: u32,
+}
+
+#[derive(Clone)]
+pub struct PersistenceLog {
+    log: Arc<Mutex<Log>>,
+}
+
+struct Log {
+    dir: PathBuf,
+    segment_max_bytes: u64,
+    /// Segment that records are appended to, always the last entry of `segments`
+    active: File,
+    active_seq: u64,
+    segments: BTreeMap<u64, Segment>,
+    /// Where the newest put of each waiting msg is
+    index: BTreeMap<MsgId, Location>,
+    /// Sum of the records of the msgs waiting. Acks and overwritten puts take up space until their segment goes, but
+    /// aren't counted
+    record_bytes: u64,
+}
+
+#[derive(Default)]
+struct Segment {
+    len: u64,
+    /// Puts in this segment that haven't been acked or overwritten
+    live: usize,
+    live_bytes: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Location {
+    segment: u64,
+    offset: u64,
+    len: u64,
+}
+
+/// A decoded record
+struct Record {
+    kind: u8,
+    msg: MsgBusData,
+    /// Bytes the record takes up in the segment
+    len: usize,
+}
+
+impl PersistenceLog {
+    fn open(dir: &Path, segment_max_bytes: u64) -> Result<PersistenceLog> {
+        fs::create_dir_all(dir).map_err(|err| Error::Initialization(err.to_string()))?;
+
+        let mut seqs = Vec::new();
+        for entry in fs::read_dir(dir).map_err(|err| Error::Initialization(err.to_string()))? {
+            let path = entry
+                .map_err(|err| Error::Initialization(err.to_string()))?
+                .path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            match path
+                .file_stem()
+                .and_then(|stem| stem.to_str()?.parse::<u64>().ok())
+            {
+                Some(seq) => seqs.push(seq),
+                None => warn!("Ignoring unexpected file in persistence [{:?}]", path),
+            }
+        }
+        seqs.sort_unstable();
+
+        let mut segments = BTreeMap::new();
+        let mut index = BTreeMap::new();
+        let mut record_bytes = 0;
+        for seq in &seqs {
+            let segment = load_segment(dir, *seq, &mut segments, &mut index, &mut record_bytes)?;
+            segments.insert(*seq, segment);
+        }
+
+        // Keep appending to the last segment, a torn record at its end was truncated while loading
+        let active_seq = seqs.last().copied().unwrap_or_default();
+        let active =
+            open_segment(dir, active_seq).map_err(|err| Error::Initialization(err.to_string()))?;
+        segments.entry(active_seq).or_default();
+
+        let mut log = Log {
+            dir: dir.to_path_buf(),
+            segment_max_bytes,
+            active,
+            active_seq,
+            segments,
+            index,
+            record_bytes,
+        };
+        log.remove_dead_segments()
+            .map_err(|err| Error::Initialization(err.to_string()))?;
+
+        Ok(PersistenceLog {
+            log: Arc::new(Mutex::new(log)),
+        })
+    }
+}
+
+impl MsgPersistenceInterface for PersistenceLog {
+    const DEFAULT_PATH: &'static str = DEFAULT_PATH;
+
+    fn new_persistence(config: &str) -> Result<PersistenceLog> {
+        let Config {
+            path,
+            segment_size_kb,
+        } = serde_json::from_str::<Config>(config)
+            .map_err(|err| Error::Initialization(err.to_string()))?;
+
+        let store = PersistenceLog::open(Path::new(&path), segment_size_kb as u64 * 1024)?;
+        debug!(
+            "Opened log persistence at [{}] with [{}] msgs stored, [{}] bytes",
+            path,
+            store.count(),
+            store.size_bytes()
+        );
+
+        Ok(store)
+    }
+
+    fn put(&self, msg: &MsgBusData) -> Result<()> {
+        trace!("Persisting msg [{}]", msg.id);
+        self.log.lock().expect("poisoned lock").put(msg)
+    }
+
+    fn ack(&self, msg_id: MsgId) -> Result<()> {
+        self.log.lock().expect("poisoned lock").ack(msg_id)
+    }
+
+    fn ack_oldest(&self) -> Result<Option<MsgId>> {
+        let mut log = self.log.lock().expect("poisoned lock");
+        let Some(msg_id) = log.index.keys().next().copied() else {
+            return Ok(None);
+        };
+        log.ack(msg_id)?;
+
+        Ok(Some(msg_id))
+    }
+
+    fn iterate_from(&self, from_id: MsgId, max_count: usize) -> Result<Vec<MsgBusData>> {
+        let log = self.log.lock().expect("poisoned lock");
+        let locations: Vec<Location> = log
+            .index
+            .range(from_id..)
+            .take(max_count)
+            .map(|(_, location)| *location)
+            .collect();
+
+        log.read(&locations)
+    }
+
+    fn last_id(&self) -> Result<Option<MsgId>> {
+        let log = self.log.lock().expect("poisoned lock");
+        Ok(log.index.keys().next_back().copied())
+    }
+
+    fn count(&self) -> usize {
+        self.log.lock().expect("poisoned lock").index.len()
+    }
+
+    fn record_len(&self, msg: &MsgBusData) -> u64 {
+        (HEADER_LEN + msg.payload.len() + context_len(msg)) as u64
+    }
+
+    fn size_bytes(&self) -> u64 {
+        self.log.lock().expect("poisoned lock").record_bytes
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.log.lock().expect("poisoned lock").compact()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.log
+            .lock()
+            .expect("poisoned lock")
+            .active
+            .sync_data()
+            .map_err(|err| Error::Write(err.to_string()))
+    }
+}
+
+impl Log {
+    fn put(&mut self, msg: &MsgBusData) -> Result<()> {
+        let location = self.append(KIND_PUT, msg)?;
+        self.track(msg.id, location)?;
+        self.rotate_if_full()
+    }
+
+    fn ack(&mut self, msg_id: MsgId) -> Result<()> {
+        if !self.index.contains_key(&msg_id) {
+            return Ok(());
+        }
+        let tombstone = MsgBusData {
+            id: msg_id,
+            payload: Bytes::new(),
+            retry_count: 0,
+            ..Default::default()
+        };
+        self.append(KIND_ACK, &tombstone)?;
+        if let Some(location) = self.index.remove(&msg_id) {
+            self.untrack(location);
+        }
+        trace!("Removed msg [{}] from persistence", msg_id);
+
+        self.remove_dead_segments()
+            .map_err(|err| Error::Write(err.to_string()))?;
+        self.rotate_if_full()
+    }
+
+    /// Moves the msgs still waiting in mostly acked segments to the active segment, then syncs it to disk
+    fn compact(&mut self) -> Result<()> {
+        let sparse: Vec<u64> = self
+            .segments
+            .iter()
+            .filter(|(seq, segment)| {
+                **seq != self.active_seq && segment.live > 0 && segment.live_bytes * 2 < segment.len
+            })
+            .map(|(seq, _)| *seq)
+            .collect();
+
+        for seq in sparse {
+            let locations: Vec<Location> = self
+                .index
+                .values()
+                .filter(|location| location.segment == seq)
+                .copied()
+                .collect();
+            debug!(
+                "Compacting persistence segment [{}], moving [{}] msgs",
+                seq,
+                locations.len()
+            );
+            for msg in self.read(&locations)? {
+                self.put(&msg)?;
+            }
+        }
+
+        self.remove_dead_segments()
+            .map_err(|err| Error::Write(err.to_string()))?;
+        self.active
+            .sync_data()
+            .map_err(|err| Error::Write(err.to_string()))?;
+
+        Ok(())
+    }
+
+    fn append(&mut self, kind: u8, msg: &MsgBusData) -> Result<Location> {
+        let record = encode(kind, msg);
+        self.active
+            .write_all(&record)
+            .map_err(|err| Error::Write(err.to_string()))?;
+
+        let segment = self.segments.entry(self.active_seq).or_default();
+        let location = Location {
+            segment: self.active_seq,
+            offset: segment.len,
+            len: record.len() as u64,
+        };
+        segment.len += location.len;
+
+        Ok(location)
+    }
+
+    /// Points the index at a new put, an older put of the same msg is no longer live
+    fn track(&mut self, msg_id: MsgId, location: Location) -> Result<()> {
+        let segment = self.segments.entry(location.segment).or_default();
+        segment.live += 1;
+        segment.live_bytes += location.len;
+        self.record_bytes += location.len;
+
+        if let Some(replaced) = self.index.insert(msg_id, location) {
+            self.untrack(replaced);
+            self.remove_dead_segments()
+                .map_err(|err| Error::Write(err.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    fn untrack(&mut self, location: Location) {
+        if let Some(segment) = self.segments.get_mut(&location.segment) {
+            segment.live -= 1;
+            segment.live_bytes -= location.len;
+        }
+        self.record_bytes -= location.len;
+    }
+
+    fn rotate_if_full(&mut self) -> Result<()> {
+        let len = self
+            .segments
+            .get(&self.active_seq)
+            .map(|segment| segment.len)
+            .unwrap_or_default();
+        if len < self.segment_max_bytes {
+            return Ok(());
+        }
+
+        // A flush only syncs the active segment, so the full one is synced now
+        self.active
+            .sync_data()
+            .map_err(|err| Error::Write(err.to_string()))?;
+        let seq = self.active_seq + 1;
+        trace!(
+            "Persistence segment [{}] is full, starting [{}]",
+            self.active_seq,
+            seq
+        );
+        self.active = open_segment(&self.dir, seq).map_err(|err| Error::Write(err.to_string()))?;
+        self.active_seq = seq;
+        self.segments.insert(seq, Segment::default());
+
+        self.remove_dead_segments()
+            .map_err(|err| Error::Write(err.to_string()))
+    }
+
+    /// Deletes segments without live msgs, oldest first. A segment can't be deleted while an older one remains, it may
+    /// hold the acks for msgs in the older segment
+    fn remove_dead_segments(&mut self) -> std::io::Result<()> {
+        while let Some((seq, segment)) = self.segments.first_key_value() {
+            if *seq == self.active_seq || segment.live > 0 {
+                break;
+            }
+            let seq = *seq;
+            fs::remove_file(segment_path(&self.dir, seq))?;
+            self.segments.remove(&seq);
+            trace!("Removed persistence segment [{}]", seq);
+        }
+
+        Ok(())
+    }
+
+    fn read(&self, locations: &[Location]) -> Result<Vec<MsgBusData>> {
+        let mut files: HashMap<u64, File> = HashMap::new();
+        let mut msgs = Vec::with_capacity(locations.len());
+        for location in locations {
+            let file = match files.entry(location.segment) {
+                std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                std::collections::hash_map::Entry::Vacant(entry) => entry.insert(
+                    File::open(segment_path(&self.dir, location.segment))
+                        .map_err(|err| Error::Read(err.to_string()))?,
+                ),
+            };
+            let mut record = vec![0; location.len as usize];
+            file.seek(SeekFrom::Start(location.offset))
+                .and_then(|_| file.read_exact(&mut record))
+                .map_err(|err| Error::Read(err.to_string()))?;
+
+            let Some(Record { msg, .. }) = decode(&record)? else {
+                return Err(Error::Decode(format!(
+                    "record at [{}:{}] is incomplete",
+                    location.segment, location.offset
+                )));
+            };
+            msgs.push(msg);
+        }
+
+        Ok(msgs)
+    }
+}
+
+/// Replays the records of a segment into the index. A torn or corrupt record ends the segment, it is truncated there
+fn load_segment(
+    dir: &Path,
+    seq: u64,
+    segments: &mut BTreeMap<u64, Segment>,
+    index: &mut BTreeMap<MsgId, Location>,
+    record_bytes: &mut u64,
+) -> Result<Segment> {
+    let path = segment_path(dir, seq);
+    let bytes = fs::read(&path).map_err(|err| Error::Initialization(err.to_string()))?;
+
+    let mut segment = Segment::default();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let record = match decode(&bytes[offset..]) {
+            Ok(Some(record)) => record,
+            Ok(None) | Err(_) => {
+                warn!(
+                    "Persistence segment [{}] has a torn record at [{}], truncating the remaining [{}] bytes",
+                    seq,
+                    offset,
+                    bytes.len() - offset
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)
+                    .and_then(|file| file.set_len(offset as u64))
+                    .map_err(|err| Error::Initialization(err.to_string()))?;
+                break;
+            }
+        };
+        let len = record.len as u64;
+
+        let replaced = if record.kind == KIND_PUT {
+            segment.live += 1;
+            segment.live_bytes += len;
+            *record_bytes += len;
+            let location = Location {
+                segment: seq,
+                offset: offset as u64,
+                len,
+            };
+            index.insert(record.msg.id, location)
+        } else {
+            index.remove(&record.msg.id)
+        };
+
+        if let Some(replaced) = replaced {
+            let older = if replaced.segment == seq {
+                &mut segment
+            } else {
+                segments.entry(replaced.segment).or_default()
+            };
+            older.live -= 1;
+            older.live_bytes -= replaced.len;
+            *record_bytes -= replaced.len;
+        }
+
+        offset += len as usize;
+    }
+    segment.len = offset as u64;
+
+    Ok(segment)
+}
+
+fn open_segment(dir: &Path, seq: u64) -> std::io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, seq))
+}
+
+fn segment_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", seq, SEGMENT_EXTENSION))
+}
+
+fn default_path() -> String {
+    DEFAULT_PATH.to_string()
+}
+
+fn default_segment_size_kb() -> u32 {
+    DEFAULT_SEGMENT_SIZE_KB
+}
+
+fn encode(kind: u8, msg: &MsgBusData) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_LEN + msg.payload.len());
+    record.extend_from_slice(&[0; 4]);
+    record.push(kind);
+    record.extend_from_slice(&msg.id.to_be_bytes());
+    record.extend_from_slice(&msg.retry_count.to_be_bytes());
+    record.extend_from_slice(&(msg.payload.len() as u32).to_be_bytes());
+    record.extend_from_slice(&[0; 4]);
+    record.extend_from_slice(&msg.payload);
+    if kind == KIND_PUT {
+        encode_context(msg, &mut record);
+        let context_len = (record.len() - HEADER_LEN - msg.payload.len()) as u32;
+        record[21..HEADER_LEN].copy_from_slice(&context_len.to_be_bytes());
+    }
+
+    let crc = crc32fast::hash(&record[4..]);
+    record[..4].copy_from_slice(&crc.to_be_bytes());
+    record
+}
+
+/// Decodes the record at the start of `bytes`. `None` if `bytes` ends before the record does
+fn decode(bytes: &[u8]) -> Result<Option<Record>> {
+    if bytes.len() < HEADER_LEN {
+        return Ok(None);
+    }
+    let u32_at =
+        |at: usize| u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]);
+    let msg_id = MsgId::from_be_bytes(bytes[5..13].try_into().unwrap_or_default());
+    let payload_len = u32_at(17) as usize;
+    let context_len = u32_at(21) as usize;
+    let Some(record) = bytes.get(..HEADER_LEN + payload_len + context_len) else {
+        return Ok(None);
+    };
+
+    if crc32fast::hash(&record[4..]) != u32_at(0) {
+        return Err(Error::Decode(format!(
+            "checksum mismatch for msg [{}]",
+            msg_id
+        )));
+    }
+    let kind = record[4];
+    if kind != KIND_PUT && kind != KIND_ACK {
+        return Err(Error::Decode(format!("unknown record kind [{}]", kind)));
+    }
+
+    let (payload, context) = record[HEADER_LEN..].split_at(payload_len);
+    let mut msg = MsgBusData {
+        id: msg_id,
+        payload: Bytes::copy_from_slice(payload),
+        retry_count: u32_at(13),
+        ..Default::default()
+    };
+    if !context.is_empty() {
+        decode_context(context, &mut msg)?;
+    }
+
+    Ok(Some(Record {
+        kind,
+        msg,
+        len: record.len(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("persistence-log-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn msg(id: MsgId, payload: &[u8]) -> MsgBusData {
+        MsgBusData {
+            id,
+            payload: Bytes::copy_from_slice(payload),
+            retry_count: id as u32,
+            ..Default::default()
+        }
+    }
+
+    fn segment_count(dir: &Path) -> usize {
+        fs::read_dir(dir).unwrap().count()
+    }
+
+    #[test]
+    fn survives_restart() {
+        let dir = temp_dir("restart");
+        let store = PersistenceLog::open(&dir, 1024 * 1024).unwrap();
+        store.put(&msg(1, b"one")).unwrap();
+        store.put(&msg(2, b"two")).unwrap();
+        store.put(&msg(3, b"three")).unwrap();
+        store
+            .put(&MsgBusData {
+                metadata: HashMap::from([("site".to_string(), "north".to_string())]),
+                ..msg(3, b"three again")
+            })
+            .unwrap();
+        store.ack(2).unwrap();
+        drop(store);
+
+        // A write that was cut off half way
+        let mut file = open_segment(&dir, 0).unwrap();
+        file.write_all(&encode(KIND_PUT, &msg(4, b"four"))[..10])
+            .unwrap();
+
+        let store = PersistenceLog::open(&dir, 1024 * 1024).unwrap();
+        let msgs = store.iterate_from(0, 10).unwrap();
+        let ids: Vec<MsgId> = msgs.iter().map(|msg| msg.id).collect();
+        assert_eq!(ids, [1, 3]);
+        assert_eq!(store.last_id().unwrap(), Some(3));
+        assert_eq!(msgs[1].payload, &b"three again"[..]);
+        assert_eq!(msgs[1].retry_count, 3);
+        assert_eq!(msgs[1].metadata["site"], "north");
+        // Header, payload and context of msgs 1 and 3
+        assert_eq!(store.size_bytes(), (25 + 3 + 20) + (25 + 11 + 37));
+        assert_eq!(
+            store.size_bytes(),
+            store.record_len(&msgs[0]) + store.record_len(&msgs[1])
+        );
+
+        // Appends after the truncated record are readable
+        store.put(&msg(5, b"five")).unwrap();
+        assert_eq!(store.iterate_from(4, 10).unwrap()[0].payload, &b"five"[..]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compact_moves_stragglers() {
+        let dir = temp_dir("compact");
+        // Records are 75 bytes, 5 puts fill a segment
+        let store = PersistenceLog::open(&dir, 350).unwrap();
+        for id in 0..10 {
+            store.put(&msg(id, &[id as u8; 30])).unwrap();
+        }
+        for id in 1..10 {
+            store.ack(id).unwrap();
+        }
+        // Msg 0 holds on to its segment and everything after it
+        assert_eq!(segment_count(&dir), 3);
+
+        // Msg 0 moves to the active segment, with the acks, and the two full segments go
+        store.compact().unwrap();
+        assert_eq!(segment_count(&dir), 1);
+        drop(store);
+
+        let store = PersistenceLog::open(&dir, 350).unwrap();
+        let msgs = store.iterate_from(0, 10).unwrap();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].payload, [0; 30][..]);
+
+        assert_eq!(store.ack_oldest().unwrap(), Some(0));
+        assert_eq!(store.count(), 0);
+        assert_eq!(segment_count(&dir), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
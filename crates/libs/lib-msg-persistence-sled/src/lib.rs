@@ -1,6 +1,307 @@
+//! # Sled Persistence
+//!
+//! Store-and-forward queue for messages that could not be delivered to the cloud.
+//! Messages are kept in a sled tree keyed by their big-endian message id, so iterating the tree yields them in id order
+//!
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use data_source_core::{Bytes, MsgBusData, MsgId};
+use msg_persistence_core::{
+    context::{context_len, decode_context, encode_context},
+    Error, MsgPersistenceInterface, Result,
+};
 use serde::{Deserialize, Serialize};
+use tracing::{debug, trace};
+
+const TREE_NAME: &str = "msgs";
+const DEFAULT_PATH: &str = "persistence.db";
+
+/// Size of the retry_count and payload_len header that is stored in front of every payload
+const HEADER_LEN: usize = 2 * std::mem::size_of::<u32>();
+/// Size of the msg id every value is keyed by
+const KEY_LEN: u64 = std::mem::size_of::<MsgId>() as u64;
 
 #[derive(Serialize, Deserialize)]
 pub struct Config {
-    pub _reserved: u32,
+    /// Directory the sled database lives in
+    #[serde(default = "default_path")]
+    pub path: String,
+}
+
+#[derive(Clone)]
+pub struct PersistenceSled {
+    db: sled::Db,
+    msgs: sled::Tree,
+    /// Sum of the keys and values stored, this is what the persistence quota is measured against
+    record_bytes: Arc<AtomicU64>,
+}
+
+impl PersistenceSled {
+    fn from_tree(db: sled::Db, msgs: sled::Tree) -> Result<PersistenceSled> {
+        // Count what a previous run left behind
+        let mut record_bytes = 0;
+        for entry in msgs.iter().values() {
+            let value = entry.map_err(|err| Error::Initialization(err.to_string()))?;
+            record_bytes += KEY_LEN + value.len() as u64;
+        }
+
+        Ok(PersistenceSled {
+            db,
+            msgs,
+            record_bytes: Arc::new(AtomicU64::new(record_bytes)),
+        })
+    }
+}
+
+impl MsgPersistenceInterface for PersistenceSled {
+    const DEFAULT_PATH: &'static str = DEFAULT_PATH;
+
+    fn new_persistence(config: &str) -> Result<PersistenceSled> {
+        let Config { path } = serde_json::from_str::<Config>(config)
+            .map_err(|err| Error::Initialization(err.to_string()))?;
+
+        let db = sled::open(&path).map_err(|err| Error::Initialization(err.to_string()))?;
+        let msgs = db
+            .open_tree(TREE_NAME)
+            .map_err(|err| Error::Initialization(err.to_string()))?;
+        let store = PersistenceSled::from_tree(db, msgs)?;
+        debug!(
+            "Opened sled persistence at [{}] with [{}] msgs stored, [{}] bytes",
+            path,
+            store.count(),
+            store.size_bytes()
+        );
+
+        Ok(store)
+    }
+
+    fn put(&self, msg: &MsgBusData) -> Result<()> {
+        trace!("Persisting msg [{}]", msg.id);
+        let value = encode(msg);
+        let record_len = KEY_LEN + value.len() as u64;
+        let replaced = self
+            .msgs
+            .insert(msg.id.to_be_bytes(), value)
+            .map_err(|err| Error::Write(err.to_string()))?;
+
+        self.record_bytes.fetch_add(record_len, Ordering::Relaxed);
+        if let Some(replaced) = replaced {
+            self.record_bytes
+                .fetch_sub(KEY_LEN + replaced.len() as u64, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
+    fn ack(&self, msg_id: MsgId) -> Result<()> {
+        if let Some(removed) = self
+            .msgs
+            .remove(msg_id.to_be_bytes())
+            .map_err(|err| Error::Write(err.to_string()))?
+        {
+            self.record_bytes
+                .fetch_sub(KEY_LEN + removed.len() as u64, Ordering::Relaxed);
+            trace!("Removed msg [{}] from persistence", msg_id);
+        }
+
+        Ok(())
+    }
+
+    fn ack_oldest(&self) -> Result<Option<MsgId>> {
+        let Some((key, value)) = self
+            .msgs
+            .pop_min()
+            .map_err(|err| Error::Write(err.to_string()))?
+        else {
+            return Ok(None);
+        };
+        self.record_bytes
+            .fetch_sub(KEY_LEN + value.len() as u64, Ordering::Relaxed);
+
+        decode(&key, &value).map(|msg| Some(msg.id))
+    }
+
+    fn iterate_from(&self, from_id: MsgId, max_count: usize) -> Result<Vec<MsgBusData>> {
+        self.msgs
+            .range(from_id.to_be_bytes()..)
+            .take(max_count)
+            .map(|entry| {
+                let (key, value) = entry.map_err(|err| Error::Read(err.to_string()))?;
+                decode(&key, &value)
+            })
+            .collect()
+    }
+
+    fn last_id(&self) -> Result<Option<MsgId>> {
+        match self
+            .msgs
+            .last()
+            .map_err(|err| Error::Read(err.to_string()))?
+        {
+            Some((key, _)) => decode_key(&key).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn count(&self) -> usize {
+        self.msgs.len()
+    }
+
+    fn record_len(&self, msg: &MsgBusData) -> u64 {
+        KEY_LEN + (HEADER_LEN + msg.payload.len() + context_len(msg)) as u64
+    }
+
+    fn size_bytes(&self) -> u64 {
+        self.record_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Sled reclaims space on its own, this only blocks until all pending writes are on disk
+    fn compact(&self) -> Result<()> {
+        self.flush()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db
+            .flush()
+            .map_err(|err| Error::Write(err.to_string()))?;
+
+        Ok(())
+    }
+}
+
+fn default_path() -> String {
+    DEFAULT_PATH.to_string()
+}
+
+fn payload_len(value: &[u8]) -> u64 {
+    value.get(4..HEADER_LEN).map_or(0, |len| {
+        u32::from_be_bytes(len.try_into().unwrap_or_default()) as u64
+    })
+}
+
+// Stored value layout: [retry_count: u32 BE][payload_len: u32 BE][payload..][context..]
+// See msg_persistence_core::context for the layout of the context
+fn encode(msg: &MsgBusData) -> Vec<u8> {
+    let mut value = Vec::with_capacity(HEADER_LEN + msg.payload.len());
+    value.extend_from_slice(&msg.retry_count.to_be_bytes());
+    value.extend_from_slice(&(msg.payload.len() as u32).to_be_bytes());
+    value.extend_from_slice(&msg.payload);
+    encode_context(msg, &mut value);
+    value
+}
+
+fn decode_key(key: &[u8]) -> Result<MsgId> {
+    key.try_into()
+        .map(MsgId::from_be_bytes)
+        .map_err(|_| Error::Decode(format!("invalid key length [{}]", key.len())))
+}
+
+fn decode(key: &[u8], value: &[u8]) -> Result<MsgBusData> {
+    let id = decode_key(key)?;
+    if value.len() < HEADER_LEN {
+        return Err(Error::Decode(format!(
+            "value for msg [{}] is too short [{}]",
+            id,
+            value.len()
+        )));
+    }
+    let (header, rest) = value.split_at(HEADER_LEN);
+    let retry_count = u32::from_be_bytes(header[..4].try_into().unwrap_or_default());
+    let Some((payload, context)) = rest.split_at_checked(payload_len(value) as usize) else {
+        return Err(Error::Decode(format!(
+            "payload of msg [{}] is cut short",
+            id
+        )));
+    };
+
+    let mut msg = MsgBusData {
+        id,
+        payload: Bytes::copy_from_slice(payload),
+        retry_count,
+        ..Default::default()
+    };
+    decode_context(context, &mut msg)?;
+    Ok(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_store() -> PersistenceSled {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let msgs = db.open_tree(TREE_NAME).unwrap();
+        PersistenceSled::from_tree(db, msgs).unwrap()
+    }
+
+    #[test]
+    fn put_then_remove() {
+        let store = temp_store();
+        let msg = MsgBusData {
+            id: 7,
+            payload: Bytes::from_static(b"hello"),
+            retry_count: 2,
+            topic: Some("site/north".to_string()),
+            timestamp: 1_700_000_000_000,
+            ..Default::default()
+        };
+
+        store.put(&msg).unwrap();
+        store.put(&msg).unwrap();
+        assert_eq!(store.count(), 1);
+        // Key, header, payload and context, which holds the topic
+        assert_eq!(store.size_bytes(), 8 + 8 + 5 + 30);
+        assert_eq!(store.size_bytes(), store.record_len(&msg));
+
+        let decoded = store.iterate_from(0, 10).unwrap().remove(0);
+        assert_eq!(decoded.id, 7);
+        assert_eq!(decoded.retry_count, 2);
+        assert_eq!(decoded.payload, &b"hello"[..]);
+        assert_eq!(decoded.topic, msg.topic);
+        assert_eq!(decoded.timestamp, msg.timestamp);
+
+        store.ack(7).unwrap();
+        store.ack(7).unwrap();
+        assert_eq!(store.count(), 0);
+        assert_eq!(store.size_bytes(), 0);
+    }
+
+    #[test]
+    fn iterate_in_id_order() {
+        let store = temp_store();
+        // Ids that would sort wrong as little-endian or as strings, or cut down to 32 bits
+        for id in [300, 2, 1 << 32, 256, 10] {
+            let msg = MsgBusData {
+                id,
+                payload: Bytes::new(),
+                retry_count: 0,
+                ..Default::default()
+            };
+            store.put(&msg).unwrap();
+        }
+
+        let ids: Vec<MsgId> = store
+            .iterate_from(0, 10)
+            .unwrap()
+            .iter()
+            .map(|msg| msg.id)
+            .collect();
+        assert_eq!(ids, [2, 10, 256, 300, 1 << 32]);
+
+        let ids: Vec<MsgId> = store
+            .iterate_from(10, 2)
+            .unwrap()
+            .iter()
+            .map(|msg| msg.id)
+            .collect();
+        assert_eq!(ids, [10, 256]);
+        assert_eq!(store.last_id().unwrap(), Some(1 << 32));
+
+        assert_eq!(store.ack_oldest().unwrap(), Some(2));
+        assert_eq!(store.ack_oldest().unwrap(), Some(10));
+        assert_eq!(store.count(), 3);
+    }
 }
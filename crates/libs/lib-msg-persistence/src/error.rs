@@ -0,0 +1,15 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("initialization: [{0}]")]
+    Initialization(String),
+    #[error("store: [{0}]")]
+    Store(#[from] msg_persistence_core::Error),
+    #[error("msg [{msg_id}] rejected, persistence is at its highwater of [{highwater_bytes}] bytes. [{total}] rejected so far")]
+    QuotaExceeded {
+        msg_id: data_source_core::MsgId,
+        highwater_bytes: u64,
+        total: u64,
+    },
+}
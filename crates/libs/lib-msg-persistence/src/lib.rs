@@ -1,12 +1,427 @@
+//! # Message Persistence
+//!
+//! Store-and-forward of messages that could not be delivered. The backend is chosen by feature flag, `sled` takes
+//! priority over `log`, which takes priority over `dev`
+//!
+//! With `write_ahead` enabled, every msg is also journaled before it is published and cleared once it is acked.
+//! The journal is a separate store, msgs left in it after a power loss are published again on the next start, ahead of
+//! new data
+//!
+//! Every cloud adapter gets its own partition, so an adapter that is offline doesn't hold back the others.
+//! Partition 0 uses the configured paths as they are, the others get `-{partition}` appended
+//!
+mod error;
+
+pub use error::Error;
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use data_source_core::{MsgBusData, MsgId};
+pub use msg_persistence_core::MsgPersistenceInterface;
+use serde::Deserialize;
+use tracing::{info, trace, warn};
+
+#[cfg(not(any(feature = "dev", feature = "sled", feature = "log")))]
+compile_error!("a persistence feature must be chosen, 'dev', 'sled' or 'log'");
+
+#[cfg(feature = "sled")]
+type Store = persistence_sled::PersistenceSled;
+#[cfg(all(feature = "log", not(feature = "sled")))]
+type Store = persistence_log::PersistenceLog;
+#[cfg(all(feature = "dev", not(any(feature = "sled", feature = "log"))))]
+type Store = persistence_dev::PersistenceDev;
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+const BYTES_PER_MB: u64 = 1024 * 1024;
+const DEFAULT_JOURNAL_PATH: &str = "persistence-journal";
+/// How many msgs are read from the journal at a time when recovering it
+const JOURNAL_BATCH_SIZE: usize = 100;
+
+#[derive(Deserialize)]
+struct Config {
+    #[serde(default = "enabled_default")]
+    enabled: bool,
+    /// Quota for the bytes kept in persistence, payloads along with their context and the backend's headers. 0 means no
+    /// quota
+    #[serde(default)]
+    highwater_mb: u32,
+    #[serde(default)]
+    eviction_policy: EvictionPolicy,
+    /// Journal every msg before publishing it, so msgs in flight survive a power loss
+    #[serde(default)]
+    write_ahead: bool,
+    /// Where the journal lives, it replaces `path` for the backend
+    #[serde(default = "journal_path_default")]
+    journal_path: String,
+}
+
+/// What to do with a msg that doesn't fit under `highwater_mb`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EvictionPolicy {
+    /// Drop the oldest persisted msgs until the new one fits
+    #[default]
+    DropOldest,
+    /// Drop the new msg
+    DropNewest,
+    /// Drop the new msg and return [Error::QuotaExceeded]
+    RejectAndReport,
+}
+
+/// Msgs that were dropped to keep persistence under its quota
+#[derive(Debug, Default)]
+pub struct Evicted {
+    pub msg_ids: Vec<MsgId>,
+    /// Evictions since startup, including these
+    pub total: u64,
+}
+
 #[derive(Clone)]
 pub struct MsgPersistence {
-    pub tmp: String,
+    enabled: bool,
+    /// `None` when there is no quota
+    highwater_bytes: Option<u64>,
+    eviction_policy: EvictionPolicy,
+    evicted_total: Arc<AtomicU64>,
+    store: Store,
+    /// `None` unless `write_ahead` is enabled
+    journal: Option<Store>,
 }
 
 impl MsgPersistence {
-    pub fn new() -> MsgPersistence {
-        MsgPersistence {
-            tmp: "hi".to_string(),
+    /// * `config` - json of the persistence section of the configuration, it is also handed to the backend
+    /// * `partition` - index of the cloud adapter this persistence is for
+    pub fn new(config: &str, partition: usize) -> Result<MsgPersistence> {
+        let Config {
+            enabled,
+            highwater_mb,
+            eviction_policy,
+            write_ahead,
+            journal_path,
+        } = serde_json::from_str::<Config>(config)
+            .map_err(|err| Error::Initialization(err.to_string()))?;
+        if !enabled {
+            warn!("Persistence is disabled, msgs that can't be delivered will be dropped");
+        }
+        let highwater_bytes = match highwater_mb {
+            0 => {
+                warn!("Persistence has no highwater_mb set, it can grow until the disk is full");
+                None
+            }
+            highwater_mb => {
+                info!(
+                    "Persistence highwater: [{}] MB, eviction policy: [{:?}]",
+                    highwater_mb, eviction_policy
+                );
+                Some(highwater_mb as u64 * BYTES_PER_MB)
+            }
+        };
+
+        let store = Store::new_persistence(&backend_config(config, None, partition)?)?;
+        let journal = if write_ahead {
+            info!("Write-ahead journal enabled at [{}]", journal_path);
+            let journal_config = backend_config(config, Some(journal_path), partition)?;
+            Some(Store::new_persistence(&journal_config)?)
+        } else {
+            None
+        };
+
+        Ok(MsgPersistence {
+            enabled,
+            highwater_bytes,
+            eviction_policy,
+            evicted_total: Arc::new(AtomicU64::new(0)),
+            store,
+            journal,
+        })
+    }
+
+    /// Journals a msg that is about to be published. Does nothing unless `write_ahead` is enabled
+    pub fn journal(&self, msg: &MsgBusData) -> Result<()> {
+        let Some(journal) = &self.journal else {
+            return Ok(());
+        };
+        journal.put(msg)?;
+        journal.flush()?;
+
+        Ok(())
+    }
+
+    /// Clears a msg from the journal, once it is acked or safely in persistence
+    pub fn clear_journal(&self, msg_id: MsgId) -> Result<()> {
+        if let Some(journal) = &self.journal {
+            journal.ack(msg_id)?;
         }
+
+        Ok(())
+    }
+
+    /// Msgs that were journaled but never acked, in id order. They stay journaled until cleared
+    pub fn unacked_journal(&self) -> Result<Vec<MsgBusData>> {
+        let Some(journal) = &self.journal else {
+            return Ok(Vec::new());
+        };
+
+        let mut msgs: Vec<MsgBusData> = Vec::with_capacity(journal.count());
+        loop {
+            let from_id = match msgs.last() {
+                Some(last) if last.id == MsgId::MAX => break,
+                Some(last) => last.id + 1,
+                None => 0,
+            };
+            let batch = journal.iterate_from(from_id, JOURNAL_BATCH_SIZE)?;
+            if batch.is_empty() {
+                break;
+            }
+            msgs.extend(batch);
+        }
+
+        Ok(msgs)
+    }
+
+    /// Store a msg until it is acknowledged. If the quota is hit, the eviction policy decides what is dropped
+    pub fn persist(&self, msg: &MsgBusData) -> Result<Evicted> {
+        if !self.enabled {
+            trace!("Persistence disabled, dropping msg [{}]", msg.id);
+            return Ok(Evicted::default());
+        }
+
+        let evicted = self.make_room(msg)?;
+        if evicted.msg_ids.contains(&msg.id) {
+            return Ok(evicted);
+        }
+
+        self.store.put(msg)?;
+
+        Ok(evicted)
+    }
+
+    /// Applies the eviction policy until `msg` fits under the quota. If `msg` itself is evicted, its id is in the result
+    fn make_room(&self, msg: &MsgBusData) -> Result<Evicted> {
+        let mut evicted = Evicted::default();
+        let Some(highwater_bytes) = self.highwater_bytes else {
+            return Ok(evicted);
+        };
+
+        let needed = self.store.record_len(msg);
+        while self.store.size_bytes() + needed > highwater_bytes {
+            match self.eviction_policy {
+                // A msg bigger than the whole quota can never fit, so it is the one dropped
+                EvictionPolicy::DropOldest if needed <= highwater_bytes => {
+                    match self.store.ack_oldest()? {
+                        Some(msg_id) => evicted.msg_ids.push(msg_id),
+                        None => break,
+                    }
+                }
+                EvictionPolicy::DropOldest | EvictionPolicy::DropNewest => {
+                    evicted.msg_ids.push(msg.id);
+                    break;
+                }
+                EvictionPolicy::RejectAndReport => {
+                    let total = self.evicted_total.fetch_add(1, Ordering::Relaxed) + 1;
+                    return Err(Error::QuotaExceeded {
+                        msg_id: msg.id,
+                        highwater_bytes,
+                        total,
+                    });
+                }
+            }
+        }
+
+        evicted.total = self
+            .evicted_total
+            .fetch_add(evicted.msg_ids.len() as u64, Ordering::Relaxed)
+            + evicted.msg_ids.len() as u64;
+
+        Ok(evicted)
+    }
+
+    /// Removes an acknowledged msg. It is fine to call this for a msg that was never persisted
+    pub fn remove(&self, msg_id: MsgId) -> Result<()> {
+        self.store.ack(msg_id)?;
+
+        Ok(())
+    }
+
+    /// Reads up to `max_count` msgs in id order, starting at `from_id` (inclusive). The msgs stay persisted until removed
+    pub fn iterate_from(&self, from_id: MsgId, max_count: usize) -> Result<Vec<MsgBusData>> {
+        let msgs = self.store.iterate_from(from_id, max_count)?;
+
+        Ok(msgs)
+    }
+
+    /// The highest id waiting in persistence or the journal, new ids are handed out after it
+    pub fn last_id(&self) -> Result<Option<MsgId>> {
+        let mut last_id = self.store.last_id()?;
+        if let Some(journal) = &self.journal {
+            last_id = last_id.max(journal.last_id()?);
+        }
+
+        Ok(last_id)
+    }
+
+    /// Number of msgs waiting in persistence
+    pub fn len(&self) -> usize {
+        self.store.count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.store.count() == 0
+    }
+
+    /// Bytes of the msgs waiting in persistence, as the backend writes them
+    pub fn size_bytes(&self) -> u64 {
+        self.store.size_bytes()
+    }
+
+    /// Reclaims the space held by acked msgs
+    pub fn compact(&self) -> Result<()> {
+        self.store.compact()?;
+
+        Ok(())
+    }
+
+    /// Msgs evicted or rejected because of the quota, since startup
+    pub fn evicted_total(&self) -> u64 {
+        self.evicted_total.load(Ordering::Relaxed)
+    }
+}
+
+/// The config handed to the backend. `path` replaces the configured path, ie. for the journal, and the partition is
+/// appended to it
+fn backend_config(config: &str, path: Option<String>, partition: usize) -> Result<String> {
+    if path.is_none() && partition == 0 {
+        return Ok(config.to_string());
+    }
+
+    let mut config = serde_json::from_str::<serde_json::Value>(config)
+        .map_err(|err| Error::Initialization(err.to_string()))?;
+    let path = path
+        .or_else(|| config["path"].as_str().map(str::to_string))
+        .unwrap_or_else(|| Store::DEFAULT_PATH.to_string());
+    config["path"] = serde_json::Value::String(match partition {
+        0 => path,
+        partition => format!("{}-{}", path, partition),
+    });
+
+    Ok(config.to_string())
+}
+
+fn enabled_default() -> bool {
+    true
+}
+
+fn journal_path_default() -> String {
+    DEFAULT_JOURNAL_PATH.to_string()
+}
+
+#[cfg(all(test, feature = "dev", not(any(feature = "sled", feature = "log"))))]
+mod tests {
+    use std::collections::HashMap;
+
+    use data_source_core::Bytes;
+
+    use super::*;
+
+    fn msg(id: MsgId, len: usize) -> MsgBusData {
+        MsgBusData {
+            id,
+            payload: Bytes::from(vec![0; len]),
+            retry_count: 0,
+            ..Default::default()
+        }
+    }
+
+    fn persistence(eviction_policy: &str) -> MsgPersistence {
+        let config = format!(
+            r#"{{"highwater_mb": 1, "eviction_policy": "{}"}}"#,
+            eviction_policy
+        );
+        MsgPersistence::new(&config, 0).unwrap()
+    }
+
+    /// Context of a msg without a source, topic or metadata
+    const EMPTY_CONTEXT: usize = 20;
+    /// Payload of a msg that takes up half the quota
+    const HALF_MB: usize = BYTES_PER_MB as usize / 2 - EMPTY_CONTEXT;
+
+    #[test]
+    fn drop_oldest() {
+        let persistence = persistence("drop-oldest");
+        persistence.persist(&msg(1, HALF_MB)).unwrap();
+        persistence.persist(&msg(2, HALF_MB)).unwrap();
+
+        let evicted = persistence.persist(&msg(3, HALF_MB)).unwrap();
+        assert_eq!(evicted.msg_ids, [1]);
+        assert_eq!(evicted.total, 1);
+        let ids: Vec<MsgId> = persistence
+            .iterate_from(0, 10)
+            .unwrap()
+            .iter()
+            .map(|msg| msg.id)
+            .collect();
+        assert_eq!(ids, [2, 3]);
+    }
+
+    #[test]
+    fn drop_newest() {
+        let persistence = persistence("drop-newest");
+        persistence.persist(&msg(1, HALF_MB)).unwrap();
+        persistence.persist(&msg(2, HALF_MB)).unwrap();
+
+        let evicted = persistence.persist(&msg(3, 1)).unwrap();
+        assert_eq!(evicted.msg_ids, [3]);
+        assert_eq!(persistence.len(), 2);
+        assert_eq!(persistence.evicted_total(), 1);
+    }
+
+    #[test]
+    fn reject_and_report() {
+        let persistence = persistence("reject-and-report");
+        persistence.persist(&msg(1, HALF_MB)).unwrap();
+        persistence.persist(&msg(2, HALF_MB)).unwrap();
+
+        let err = persistence.persist(&msg(3, 1)).unwrap_err();
+        assert!(matches!(err, Error::QuotaExceeded { msg_id: 3, .. }));
+        assert_eq!(persistence.len(), 2);
+        assert_eq!(persistence.evicted_total(), 1);
+    }
+
+    #[test]
+    fn metadata_counts() {
+        let persistence = persistence("drop-oldest");
+        let big_metadata = |id| MsgBusData {
+            metadata: HashMap::from([("notes".to_string(), "x".repeat(HALF_MB))]),
+            ..msg(id, 1)
+        };
+        persistence.persist(&big_metadata(1)).unwrap();
+        assert!(persistence.size_bytes() > HALF_MB as u64);
+
+        let evicted = persistence.persist(&big_metadata(2)).unwrap();
+        assert_eq!(evicted.msg_ids, [1]);
+    }
+
+    #[test]
+    fn journal_is_separate() {
+        let persistence = MsgPersistence::new(r#"{"write_ahead": true}"#, 0).unwrap();
+        for id in 0..3 {
+            persistence.journal(&msg(id, 1)).unwrap();
+        }
+        persistence.clear_journal(1).unwrap();
+
+        let ids: Vec<MsgId> = persistence
+            .unacked_journal()
+            .unwrap()
+            .iter()
+            .map(|msg| msg.id)
+            .collect();
+        assert_eq!(ids, [0, 2]);
+        assert!(persistence.is_empty());
+        // Journaled msgs are published again, new ids have to come after them
+        assert_eq!(persistence.last_id().unwrap(), Some(2));
     }
 }
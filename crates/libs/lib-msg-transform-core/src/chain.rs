@@ -0,0 +1,144 @@
+use data_source_core::{MsgBusData, MsgIds};
+
+use crate::{BoxMsgTransform, Error, MsgTransform, Result};
+
+/// Transforms run in the order they were pushed, every msg a transform returns goes through the next one.
+/// An empty chain passes msgs through as they are. When a msg comes out as several, the first keeps its id and the rest
+/// are given new ones
+pub struct TransformChain {
+    /// (name, transform), the name is only used for errors
+    steps: Vec<(String, BoxMsgTransform)>,
+    ids: MsgIds,
+}
+
+impl TransformChain {
+    /// * `ids` - the id sequence of the data sources' [data_source_core::TxData]
+    pub fn new(ids: MsgIds) -> TransformChain {
+        TransformChain {
+            steps: Vec::new(),
+            ids,
+        }
+    }
+
+    pub fn push(&mut self, name: impl Into<String>, transform: impl MsgTransform + 'static) {
+        self.steps.push((name.into(), Box::new(transform)));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Names of the transforms, in the order they run
+    pub fn names(&self) -> Vec<&str> {
+        self.steps.iter().map(|(name, _)| name.as_str()).collect()
+    }
+}
+
+impl MsgTransform for TransformChain {
+    fn transform(&mut self, msg: MsgBusData) -> Result<Vec<MsgBusData>> {
+        let mut msgs = vec![msg];
+        for (name, step) in &mut self.steps {
+            let mut transformed = Vec::with_capacity(msgs.len());
+            for msg in msgs {
+                let out = step.transform(msg).map_err(|err| match err {
+                    Error::Transform(..) => err,
+                    err => Error::Transform(name.clone(), err.to_string()),
+                })?;
+                transformed.extend(out);
+            }
+            if transformed.is_empty() {
+                return Ok(transformed);
+            }
+            msgs = transformed;
+        }
+
+        for msg in msgs.iter_mut().skip(1) {
+            msg.id = self.ids.next();
+        }
+        Ok(msgs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data_source_core::{Bytes, TxData};
+
+    use super::*;
+
+    /// Splits a payload on commas
+    struct Split;
+
+    impl MsgTransform for Split {
+        fn transform(&mut self, msg: MsgBusData) -> Result<Vec<MsgBusData>> {
+            let parts = msg.payload.split(|byte| *byte == b',');
+            Ok(parts
+                .map(|part| MsgBusData {
+                    payload: Bytes::copy_from_slice(part),
+                    ..msg.clone()
+                })
+                .collect())
+        }
+    }
+
+    /// Drops empty payloads
+    struct DropEmpty;
+
+    impl MsgTransform for DropEmpty {
+        fn transform(&mut self, msg: MsgBusData) -> Result<Vec<MsgBusData>> {
+            Ok(Some(msg)
+                .filter(|msg| !msg.payload.is_empty())
+                .into_iter()
+                .collect())
+        }
+    }
+
+    struct Fail;
+
+    impl MsgTransform for Fail {
+        fn transform(&mut self, _msg: MsgBusData) -> Result<Vec<MsgBusData>> {
+            Err(Error::Configuration("on purpose".to_string()))
+        }
+    }
+
+    fn msg(payload: &'static [u8]) -> MsgBusData {
+        MsgBusData {
+            payload: Bytes::from_static(payload),
+            ..Default::default()
+        }
+    }
+
+    fn chain() -> TransformChain {
+        TransformChain::new(TxData::new().0.msg_ids())
+    }
+
+    #[test]
+    fn runs_in_order() {
+        let mut chain = chain();
+        chain.push("split", Split);
+        chain.push("drop-empty", DropEmpty);
+        assert_eq!(chain.names(), ["split", "drop-empty"]);
+
+        let msgs = chain.transform(msg(b"a,,b")).unwrap();
+        let payloads: Vec<_> = msgs.iter().map(|msg| &msg.payload[..]).collect();
+        assert_eq!(payloads, [&b"a"[..], &b"b"[..]]);
+        // Each part is persisted and acked on its own
+        assert_eq!(msgs[0].id, 0);
+        assert_ne!(msgs[1].id, 0);
+
+        // Nothing reaches a later step once a msg is dropped
+        chain.push("fail", Fail);
+        assert!(chain.transform(msg(b"")).unwrap().is_empty());
+        let Err(Error::Transform(name, _)) = chain.transform(msg(b"a")) else {
+            panic!("the failing step wasn't named");
+        };
+        assert_eq!(name, "fail");
+    }
+
+    #[test]
+    fn empty_passes_through() {
+        let mut chain = chain();
+        let msgs = chain.transform(msg(b"as is")).unwrap();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(&msgs[0].payload[..], b"as is");
+    }
+}
@@ -1,3 +1,37 @@
-pub trait MsgTransform {
-    fn transform(payload: String);
+mod chain;
+
+use data_source_core::MsgBusData;
+use thiserror::Error;
+
+pub use chain::TransformChain;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("configuration [{0}]")]
+    Configuration(String),
+    /// * Transform the msg failed in
+    /// * Why
+    #[error("transform [{0}] failed. [{1}]")]
+    Transform(String, String),
+}
+
+/// A step msgs go through after the data source and before the north adapters. It can change a msg, drop it by returning
+/// none, split it into several, or add msgs of its own. A [TransformChain] gives every msg after the first a new id, so
+/// they can be built from a clone of the msg they came from
+///
+/// Transforms run once per msg as it comes in. Msgs replayed from persistence aren't transformed again, so anything
+/// that depends on the state of an adapter's session, such as the Sparkplug `seq` of the HiveMQ adapter, is encoded by
+/// the adapter as it publishes instead
+pub trait MsgTransform: Send {
+    fn transform(&mut self, msg: MsgBusData) -> Result<Vec<MsgBusData>>;
+}
+
+pub type BoxMsgTransform = Box<dyn MsgTransform>;
+
+impl MsgTransform for BoxMsgTransform {
+    fn transform(&mut self, msg: MsgBusData) -> Result<Vec<MsgBusData>> {
+        (**self).transform(msg)
+    }
 }
@@ -1,10 +1,19 @@
-use msg_transform_core::MsgTransform;
+use data_source_core::MsgBusData;
+use msg_transform_core::{MsgTransform, Result};
+use tracing::debug;
 
+/// Logs each msg and passes it on as it is
+#[derive(Default)]
 pub struct TransformDev {}
 
 impl MsgTransform for TransformDev {
-    fn transform(payload: String) {
-        println!("transforming");
+    fn transform(&mut self, msg: MsgBusData) -> Result<Vec<MsgBusData>> {
+        debug!(
+            "Transforming msg [{}] of [{}] bytes",
+            msg.id,
+            msg.payload.len()
+        );
+        Ok(vec![msg])
     }
 }
 
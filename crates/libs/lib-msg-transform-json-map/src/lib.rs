@@ -0,0 +1,252 @@
+mod pointer;
+
+use std::time::{Duration, UNIX_EPOCH};
+
+use data_source_core::{Bytes, MsgBusData};
+use msg_transform_core::{Error, MsgTransform, Result};
+use serde::Deserialize;
+use serde_json::Value;
+
+const NAME: &str = "json-map";
+
+/// Reshapes json payloads by running `ops` in order on each of them. Fields the ops point at that aren't in a payload
+/// are skipped, a payload that isn't json fails the transform
+#[derive(Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    payload: PayloadFormat,
+    ops: Vec<Op>,
+}
+
+/// How payloads are read
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum PayloadFormat {
+    #[default]
+    Json,
+    /// The whole payload is a json string, for payloads that are text
+    String,
+}
+
+/// Every path is a json pointer, "" is the whole payload
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+enum Op {
+    /// Takes the value at `from` and puts it at `to`, making the objects on the way
+    Move { from: String, to: String },
+    /// Gives the field at `path` the key `name`, in the same object
+    Rename { path: String, name: String },
+    /// Puts `value` at `to`
+    Set { to: String, value: Value },
+    /// Puts the time the data source took the msg in at `to`
+    Timestamp {
+        to: String,
+        #[serde(default)]
+        format: TimestampFormat,
+    },
+    /// Puts the msg metadata value of `key` at `to`, or all of the metadata as an object without a `key`
+    Metadata { to: String, key: Option<String> },
+    /// Removes the field at `path`
+    Drop { path: String },
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum TimestampFormat {
+    /// Milliseconds since the unix epoch
+    #[default]
+    Ms,
+    /// ie. "2024-04-02T12:58:45.231Z"
+    Rfc3339,
+}
+
+pub struct TransformJsonMap {
+    config: Config,
+}
+
+impl TransformJsonMap {
+    /// * `config` - json of the transform's configuration, see [Config]
+    pub fn new(config: &str) -> Result<TransformJsonMap> {
+        let config: Config =
+            serde_json::from_str(config).map_err(|err| Error::Configuration(err.to_string()))?;
+        for op in &config.ops {
+            let paths = match op {
+                Op::Move { from, to } => vec![from, to],
+                Op::Rename { path, .. } if path.is_empty() => {
+                    return Err(Error::Configuration(
+                        "the whole payload can't be renamed".to_string(),
+                    ))
+                }
+                Op::Rename { path, .. } | Op::Drop { path } => vec![path],
+                Op::Set { to, .. } | Op::Timestamp { to, .. } | Op::Metadata { to, .. } => vec![to],
+            };
+            for path in paths {
+                pointer::validate(path).map_err(Error::Configuration)?;
+            }
+        }
+
+        Ok(TransformJsonMap { config })
+    }
+
+    fn reshape(&self, msg: &MsgBusData) -> std::result::Result<Value, String> {
+        let mut doc = match self.config.payload {
+            PayloadFormat::Json => {
+                serde_json::from_slice(&msg.payload).map_err(|err| err.to_string())?
+            }
+            PayloadFormat::String => std::str::from_utf8(&msg.payload)
+                .map(Value::from)
+                .map_err(|err| err.to_string())?,
+        };
+
+        for op in &self.config.ops {
+            match op {
+                Op::Move { from, to } => {
+                    if let Some(value) = pointer::take(&mut doc, from) {
+                        pointer::insert(&mut doc, to, value)?;
+                    }
+                }
+                Op::Rename { path, name } => {
+                    if let Some(value) = pointer::take(&mut doc, path) {
+                        let parent = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+                        let to =
+                            format!("{}/{}", parent, name.replace('~', "~0").replace('/', "~1"));
+                        pointer::insert(&mut doc, &to, value)?;
+                    }
+                }
+                Op::Set { to, value } => pointer::insert(&mut doc, to, value.clone())?,
+                Op::Timestamp { to, format } => {
+                    let timestamp = match format {
+                        TimestampFormat::Ms => Value::from(msg.timestamp),
+                        TimestampFormat::Rfc3339 => {
+                            let at = UNIX_EPOCH + Duration::from_millis(msg.timestamp);
+                            Value::from(humantime::format_rfc3339_millis(at).to_string())
+                        }
+                    };
+                    pointer::insert(&mut doc, to, timestamp)?;
+                }
+                Op::Metadata { to, key: Some(key) } => {
+                    if let Some(value) = msg.metadata.get(key) {
+                        pointer::insert(&mut doc, to, Value::from(value.as_str()))?;
+                    }
+                }
+                Op::Metadata { to, key: None } => {
+                    let metadata = msg
+                        .metadata
+                        .iter()
+                        .map(|(key, value)| (key.clone(), Value::from(value.as_str())))
+                        .collect();
+                    pointer::insert(&mut doc, to, Value::Object(metadata))?;
+                }
+                Op::Drop { path } => {
+                    pointer::take(&mut doc, path);
+                }
+            }
+        }
+
+        Ok(doc)
+    }
+}
+
+impl MsgTransform for TransformJsonMap {
+    fn transform(&mut self, mut msg: MsgBusData) -> Result<Vec<MsgBusData>> {
+        let doc = self
+            .reshape(&msg)
+            .map_err(|err| Error::Transform(NAME.to_string(), err))?;
+        let payload = serde_json::to_vec(&doc)
+            .map_err(|err| Error::Transform(NAME.to_string(), err.to_string()))?;
+        msg.payload = Bytes::from(payload);
+
+        Ok(vec![msg])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use super::*;
+
+    fn run(config: Value, msg: MsgBusData) -> Value {
+        let mut transform = TransformJsonMap::new(&config.to_string()).unwrap();
+        let msgs = transform.transform(msg).unwrap();
+        serde_json::from_slice(&msgs[0].payload).unwrap()
+    }
+
+    #[test]
+    fn special_envelope() {
+        // The envelope the http-rest and mqtt data sources used to wrap every msg in
+        let config = json!({
+            "payload": "string",
+            "ops": [
+                {"op": "move", "from": "", "to": "/data/message"},
+                {"op": "timestamp", "to": "/timestampMs", "format": "rfc3339"},
+                {"op": "set", "to": "/version", "value": "1.0.0"},
+            ]
+        });
+        let msg = MsgBusData {
+            payload: Bytes::from_static(b"temp=20"),
+            timestamp: 1_712_062_725_231,
+            ..Default::default()
+        };
+        assert_eq!(
+            run(config, msg),
+            json!({
+                "data": {"message": "temp=20"},
+                "timestampMs": "2024-04-02T12:58:45.231Z",
+                "version": "1.0.0",
+            })
+        );
+    }
+
+    #[test]
+    fn reshape() {
+        let config = json!({
+            "ops": [
+                {"op": "rename", "path": "/reading/temp", "name": "temperature"},
+                {"op": "move", "from": "/reading", "to": "/values"},
+                {"op": "drop", "path": "/debug"},
+                {"op": "drop", "path": "/not/there"},
+                {"op": "metadata", "to": "/site", "key": "site"},
+                {"op": "metadata", "to": "/headers"},
+                {"op": "timestamp", "to": "/ingested"},
+            ]
+        });
+        let msg = MsgBusData {
+            payload: Bytes::from_static(br#"{"reading": {"temp": 20}, "debug": true}"#),
+            metadata: HashMap::from([("site".to_string(), "north".to_string())]),
+            timestamp: 1_700_000_000_000,
+            ..Default::default()
+        };
+        assert_eq!(
+            run(config, msg),
+            json!({
+                "values": {"temperature": 20},
+                "site": "north",
+                "headers": {"site": "north"},
+                "ingested": 1_700_000_000_000u64,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects() {
+        assert!(TransformJsonMap::new(r#"{"ops": [{"op": "drop", "path": "no-slash"}]}"#).is_err());
+        assert!(
+            TransformJsonMap::new(r#"{"ops": [{"op": "rename", "path": "", "name": "x"}]}"#)
+                .is_err()
+        );
+        assert!(TransformJsonMap::new(r#"{"ops": [{"op": "explode"}]}"#).is_err());
+
+        let mut transform = TransformJsonMap::new(r#"{"ops": []}"#).unwrap();
+        let msg = MsgBusData {
+            payload: Bytes::from_static(b"not json"),
+            ..Default::default()
+        };
+        assert!(matches!(
+            transform.transform(msg),
+            Err(Error::Transform(..))
+        ));
+    }
+}
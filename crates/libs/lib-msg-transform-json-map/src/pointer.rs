@@ -0,0 +1,99 @@
+//! JSON pointers, RFC 6901, that can take values out of a document and put them in, creating objects along the way
+use serde_json::{Map, Value};
+
+/// A pointer is empty for the whole document, or starts with a `/`
+pub fn validate(pointer: &str) -> Result<(), String> {
+    if pointer.is_empty() || pointer.starts_with('/') {
+        Ok(())
+    } else {
+        Err(format!("[{}] is not a json pointer", pointer))
+    }
+}
+
+/// Removes the value at `pointer`, the whole document for an empty pointer. None when there's nothing there
+pub fn take(doc: &mut Value, pointer: &str) -> Option<Value> {
+    let Some((parent, last)) = pointer.rsplit_once('/') else {
+        return Some(doc.take());
+    };
+    let last = unescape(last);
+    match doc.pointer_mut(parent)? {
+        Value::Object(map) => map.remove(&last),
+        Value::Array(array) => {
+            let index = last
+                .parse::<usize>()
+                .ok()
+                .filter(|index| *index < array.len())?;
+            Some(array.remove(index))
+        }
+        _ => None,
+    }
+}
+
+/// Puts `value` at `pointer`, replacing what was there. Missing objects on the way are made, as is an object where the
+/// way goes through null. `-` appends to an array
+pub fn insert(doc: &mut Value, pointer: &str, value: Value) -> Result<(), String> {
+    let mut tokens = pointer.split('/').skip(1).map(unescape).peekable();
+    let mut at = doc;
+    while let Some(token) = tokens.next() {
+        if at.is_null() {
+            *at = Value::Object(Map::new());
+        }
+        let last = tokens.peek().is_none();
+        at = match at {
+            Value::Object(map) if last => {
+                map.insert(token, value);
+                return Ok(());
+            }
+            Value::Object(map) => map.entry(token).or_insert(Value::Null),
+            Value::Array(array) if last && token == "-" => {
+                array.push(value);
+                return Ok(());
+            }
+            Value::Array(array) => match token.parse::<usize>() {
+                Ok(index) if index < array.len() => &mut array[index],
+                _ => return Err(format!("[{}] of [{}] is not in the array", token, pointer)),
+            },
+            _ => {
+                return Err(format!(
+                    "[{}] of [{}] is in neither an object nor an array",
+                    token, pointer
+                ))
+            }
+        };
+    }
+    *at = value;
+
+    Ok(())
+}
+
+fn unescape(token: &str) -> String {
+    token.replace("~1", "/").replace("~0", "~")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_and_insert() {
+        let mut doc = serde_json::json!({"a": {"b/c": 1, "list": [1, 2]}, "n": null, "s": "text"});
+        assert_eq!(take(&mut doc, "/a/b~1c"), Some(Value::from(1)));
+        assert_eq!(take(&mut doc, "/a/b~1c"), None);
+        assert_eq!(take(&mut doc, "/a/list/0"), Some(Value::from(1)));
+        assert_eq!(take(&mut doc, "/a/list/5"), None);
+
+        insert(&mut doc, "/x/y", Value::from(2)).unwrap();
+        insert(&mut doc, "/n/z", Value::from(3)).unwrap();
+        insert(&mut doc, "/a/list/-", Value::from(4)).unwrap();
+        assert!(insert(&mut doc, "/s/t", Value::from(5)).is_err());
+        assert_eq!(
+            doc,
+            serde_json::json!({"a": {"list": [2, 4]}, "n": {"z": 3}, "s": "text", "x": {"y": 2}})
+        );
+
+        let whole = take(&mut doc, "").unwrap();
+        assert!(doc.is_null());
+        insert(&mut doc, "/wrapped", whole).unwrap();
+        assert_eq!(doc["wrapped"]["x"]["y"], 2);
+    }
+}
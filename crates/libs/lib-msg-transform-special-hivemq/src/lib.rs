@@ -7,20 +7,98 @@ use std::{
 
 use flate2::{write::GzEncoder, Compression};
 use sequence::Sequence;
-use sparkplug_rs::{payload::Metric, protobuf::Message, sparkplug_b};
+use sparkplug_rs::{
+    payload::{metric::Value, Metric},
+    protobuf::Message,
+    sparkplug_b, DataType,
+};
 use tracing::{debug, error, trace, warn};
 
+/// Metric of NBIRTH and NDEATH that ties a death to the birth of the same session
+pub const METRIC_BD_SEQ: &str = "bdSeq";
+/// Metric of NBIRTH that host applications write true to through NCMD to ask for a new NBIRTH
+pub const METRIC_REBIRTH: &str = "Node Control/Rebirth";
+/// Metric of NBIRTH that host applications write true to through NCMD to restart the bridge
+pub const METRIC_REBOOT: &str = "Node Control/Reboot";
+/// Metric of NBIRTH that host applications write true to through NCMD to restart the bridge with its config fetched again
+pub const METRIC_RELOAD_CONFIG: &str = "Node Control/Reload Config";
+
+/// A metric written by a host application through NCMD
+#[derive(Debug, PartialEq)]
+pub enum NodeCommand {
+    Rebirth,
+    Reboot,
+    ReloadConfig,
+    /// Any other metric. The value as text, bytes are kept as is
+    Write {
+        name: String,
+        value: Vec<u8>,
+    },
+}
+
 pub struct TransformSpecialHiveMQ {
     seq: Sequence,
+    /// bdSeq of the current session, or of the next one to connect
+    bd_seq: u8,
 }
 
 impl TransformSpecialHiveMQ {
     pub fn new() -> TransformSpecialHiveMQ {
         TransformSpecialHiveMQ {
             seq: Sequence::new(),
+            bd_seq: 0,
         }
     }
 
+    /// NBIRTH of the current session. Restarts `seq` so this is 0 and the next NDATA is 1.
+    /// It declares every metric the session's NDATA will hold
+    pub fn birth(&mut self) -> Vec<u8> {
+        self.seq.reset();
+        let mut sparkplug = sparkplug_b::Payload::new();
+        sparkplug.metrics.push(self.metric_bd_seq());
+
+        for name in [METRIC_REBIRTH, METRIC_REBOOT, METRIC_RELOAD_CONFIG] {
+            let mut metric_control = Metric::new();
+            metric_control.set_name(name.to_string());
+            metric_control.set_datatype(DataType::Boolean as u32);
+            metric_control.set_boolean_value(false);
+            sparkplug.metrics.push(metric_control);
+        }
+
+        sparkplug.metrics.push(metric_type());
+        sparkplug.set_seq(self.seq.pull_seq());
+        sparkplug.set_timestamp(timestamp());
+
+        let mut msg: Vec<u8> = Vec::new();
+        sparkplug.write_to_vec(&mut msg).unwrap();
+        msg
+    }
+
+    /// NDEATH of the current session, registered as the will when connecting, and published before a clean disconnect.
+    /// An NDEATH has no `seq`
+    pub fn death(&self) -> Vec<u8> {
+        let mut sparkplug = sparkplug_b::Payload::new();
+        sparkplug.metrics.push(self.metric_bd_seq());
+        sparkplug.set_timestamp(timestamp());
+
+        let mut msg: Vec<u8> = Vec::new();
+        sparkplug.write_to_vec(&mut msg).unwrap();
+        msg
+    }
+
+    /// Moves on to the bdSeq of the next session, once the current one has ended
+    pub fn next_session(&mut self) {
+        self.bd_seq = self.bd_seq.wrapping_add(1);
+    }
+
+    fn metric_bd_seq(&self) -> Metric {
+        let mut metric_bd_seq = Metric::new();
+        metric_bd_seq.set_name(METRIC_BD_SEQ.to_string());
+        metric_bd_seq.set_datatype(DataType::UInt64 as u32);
+        metric_bd_seq.set_long_value(self.bd_seq.into());
+        metric_bd_seq
+    }
+
     // Ideally don't return a Vec<u8>, I don't want to force allocation
     /// Takes in an arbitrary slice of bytes.  This method will deserialize to the expected type and then serialize to the format expected by cloud
     pub fn transform(&mut self, data: Vec<u8>) -> Vec<u8> {
@@ -48,13 +126,6 @@ impl TransformSpecialHiveMQ {
         metric_body_content_type.set_is_transient(false);
         metric_body_content_type.set_string_value("application/json".to_string());
 
-        let mut metric_type = Metric::new();
-        metric_type.set_name("type".to_string());
-        metric_type.set_datatype(12);
-        metric_type.set_is_historical(false);
-        metric_type.set_is_transient(false);
-        metric_type.set_string_value("data".to_string());
-
         let mut metric_class_type = Metric::new();
         metric_class_type.set_name("class".to_string());
         metric_class_type.set_datatype(12);
@@ -65,7 +136,7 @@ impl TransformSpecialHiveMQ {
         //sparkplug.metrics.push(metric_id);
         //sparkplug.metrics.push(metric_body_content_type);
         //sparkplug.metrics.push(metric_class_type);
-        sparkplug.metrics.push(metric_type);
+        sparkplug.metrics.push(metric_type());
 
         let body = if false {
             sparkplug.set_uuid("COMPRESSED".to_string());
@@ -88,18 +159,7 @@ impl TransformSpecialHiveMQ {
         //sparkplug.set_uuid(v)
         sparkplug.set_body(body);
         sparkplug.set_seq(self.seq.pull_seq());
-        sparkplug.set_timestamp(
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or_else(|err| {
-                    error!("System time is invalid, sparkplug timestamp will be set to 0. [{}]", err);
-                    Duration::from_secs(0)
-                })
-                .as_millis().try_into().unwrap_or_else(|err|{
-                    error!("Could not convert system UTC epoch time to u64 for sparkplugb protocol. [{}]", err);
-                    0
-                }),
-        );
+        sparkplug.set_timestamp(timestamp());
 
         let mut msg: Vec<u8> = Vec::new();
         sparkplug.write_to_vec(&mut msg).unwrap();
@@ -108,9 +168,146 @@ impl TransformSpecialHiveMQ {
     }
 }
 
+/// Decodes the metrics of an NCMD payload. The Node Control metrics only count when written true
+pub fn node_commands(payload: &[u8]) -> Result<Vec<NodeCommand>, String> {
+    let payload = sparkplug_b::Payload::parse_from_bytes(payload).map_err(|err| err.to_string())?;
+    let commands = payload
+        .metrics
+        .iter()
+        .filter_map(|metric| {
+            let control = metric.value == Some(Value::BooleanValue(true));
+            match metric.name() {
+                METRIC_REBIRTH if control => Some(NodeCommand::Rebirth),
+                METRIC_REBOOT if control => Some(NodeCommand::Reboot),
+                METRIC_RELOAD_CONFIG if control => Some(NodeCommand::ReloadConfig),
+                METRIC_REBIRTH | METRIC_REBOOT | METRIC_RELOAD_CONFIG => None,
+                name => {
+                    let value = match &metric.value {
+                        Some(Value::IntValue(value)) => value.to_string().into_bytes(),
+                        Some(Value::LongValue(value)) => value.to_string().into_bytes(),
+                        Some(Value::FloatValue(value)) => value.to_string().into_bytes(),
+                        Some(Value::DoubleValue(value)) => value.to_string().into_bytes(),
+                        Some(Value::BooleanValue(value)) => value.to_string().into_bytes(),
+                        Some(Value::StringValue(value)) => value.clone().into_bytes(),
+                        Some(Value::BytesValue(value)) => value.clone(),
+                        Some(_) | None => {
+                            warn!("NCMD metric [{}] has no value that can be forwarded", name);
+                            return None;
+                        }
+                    };
+                    Some(NodeCommand::Write {
+                        name: name.to_string(),
+                        value,
+                    })
+                }
+            }
+        })
+        .collect();
+
+    Ok(commands)
+}
+
+/// The only metric of NDATA, so it's in the NBIRTH catalogue as well
+fn metric_type() -> Metric {
+    let mut metric_type = Metric::new();
+    metric_type.set_name("type".to_string());
+    metric_type.set_datatype(DataType::String as u32);
+    metric_type.set_is_historical(false);
+    metric_type.set_is_transient(false);
+    metric_type.set_string_value("data".to_string());
+    metric_type
+}
+
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_else(|err| {
+            error!(
+                "System time is invalid, sparkplug timestamp will be set to 0. [{}]",
+                err
+            );
+            Duration::from_secs(0)
+        })
+        .as_millis()
+        .try_into()
+        .unwrap_or_else(|err| {
+            error!(
+                "Could not convert system UTC epoch time to u64 for sparkplugb protocol. [{}]",
+                err
+            );
+            0
+        })
+}
+
 #[test]
 fn to_and_from() {
-    // TODO
-    let msg: [u8; 0] = [5; 0];
-    let test = sparkplug_b::Payload::parse_from_bytes(&msg);
+    let mut transform = TransformSpecialHiveMQ::new();
+    let msg = transform.transform(br#"{"temp": 20}"#.to_vec());
+    let payload = sparkplug_b::Payload::parse_from_bytes(&msg).unwrap();
+    assert_eq!(payload.body(), br#"{"temp": 20}"#);
+    assert_eq!(payload.seq(), 0);
+    assert_eq!(payload.metrics[0].string_value(), "data");
+}
+
+#[test]
+fn birth_and_death_share_bd_seq() {
+    let mut transform = TransformSpecialHiveMQ::new();
+    transform.transform(Vec::new());
+    transform.next_session();
+
+    let birth = sparkplug_b::Payload::parse_from_bytes(&transform.birth()).unwrap();
+    assert_eq!(birth.seq(), 0);
+    let metric = |payload: &sparkplug_b::Payload, name: &str| {
+        payload
+            .metrics
+            .iter()
+            .find(|metric| metric.name() == name)
+            .cloned()
+            .unwrap()
+    };
+    assert_eq!(metric(&birth, METRIC_BD_SEQ).long_value(), 1);
+    assert!(!metric(&birth, METRIC_REBIRTH).boolean_value());
+    assert!(!metric(&birth, METRIC_REBOOT).boolean_value());
+    assert_eq!(metric(&birth, "type").datatype(), DataType::String as u32);
+
+    // NDATA carries on from the birth
+    let data = sparkplug_b::Payload::parse_from_bytes(&transform.transform(Vec::new())).unwrap();
+    assert_eq!(data.seq(), 1);
+
+    let death = sparkplug_b::Payload::parse_from_bytes(&transform.death()).unwrap();
+    assert!(!death.has_seq());
+    assert_eq!(metric(&death, METRIC_BD_SEQ).long_value(), 1);
+}
+
+#[test]
+fn decode_node_commands() {
+    let mut ncmd = sparkplug_b::Payload::new();
+    let mut push = |name: &str, value: Value| {
+        let mut metric = Metric::new();
+        metric.set_name(name.to_string());
+        metric.value = Some(value);
+        ncmd.metrics.push(metric);
+    };
+    push(METRIC_REBIRTH, Value::BooleanValue(true));
+    push(METRIC_REBOOT, Value::BooleanValue(false));
+    push("setpoint", Value::DoubleValue(21.5));
+    push("label", Value::StringValue("lobby".to_string()));
+    let mut payload = Vec::new();
+    ncmd.write_to_vec(&mut payload).unwrap();
+
+    assert_eq!(
+        node_commands(&payload).unwrap(),
+        [
+            NodeCommand::Rebirth,
+            NodeCommand::Write {
+                name: "setpoint".to_string(),
+                value: b"21.5".to_vec()
+            },
+            NodeCommand::Write {
+                name: "label".to_string(),
+                value: b"lobby".to_vec()
+            },
+        ]
+    );
+    assert!(node_commands(b"not sparkplug").is_err());
 }
@@ -1,4 +1,5 @@
-pub struct Sequence(u64);
+/// Sparkplug B sequence numbers, `seq` and `bdSeq`, count 0 to 255 then wrap back to 0
+pub struct Sequence(u8);
 
 impl Sequence {
     pub fn new() -> Sequence {
@@ -7,7 +8,23 @@ impl Sequence {
 
     pub fn pull_seq(&mut self) -> u64 {
         let seq = self.0;
-        self.0 += 1;
-        seq
+        self.0 = self.0.wrapping_add(1);
+        seq.into()
     }
+
+    /// The next `pull_seq` returns 0
+    pub fn reset(&mut self) {
+        self.0 = 0;
+    }
+}
+
+#[test]
+fn wraps_after_255() {
+    let mut seq = Sequence::new();
+    let pulled: Vec<u64> = (0..258).map(|_| seq.pull_seq()).collect();
+    assert_eq!(pulled[255], 255);
+    assert_eq!(pulled[256..], [0, 1]);
+
+    seq.reset();
+    assert_eq!(seq.pull_seq(), 0);
 }
@@ -1,8 +1,129 @@
-use msg_transform_core::MsgTransform;
+// region:  --- Modules
+
+use std::sync::RwLock;
+
+use msg_transform_core::BoxMsgTransform;
+#[cfg(feature = "dev")]
 use msg_transform_dev::TransformDev;
+#[cfg(feature = "json-map")]
+use msg_transform_json_map::TransformJsonMap;
+use thiserror::Error;
+
+// endregion
+
+// region:  --- Types
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    /// * Transform chosen
+    /// * Transforms compiled into this binary
+    #[error("transform type [{0}] not found, this build supports [{}]", .1.join(", "))]
+    TransformTypeNotFound(String, Vec<&'static str>),
+    #[error("initialization [{0}]")]
+    Initialization(String),
+}
+
+/// Constructs a transform from its config
+pub type Constructor = fn(&str) -> Result<BoxMsgTransform>;
+
+/// Transforms registered from outside this crate, see [register]
+static REGISTERED: RwLock<Vec<(&'static str, Constructor)>> = RwLock::new(Vec::new());
+
+/// Generates [transform_types] and [new] from the transforms listed, each under the feature of the same name.
+/// Adding a transform crate is a line in the list and a feature in Cargo.toml
+macro_rules! declare_transforms {
+    ($($transform_type:literal => $constructor:expr),* $(,)?) => {
+        /// Names of the transforms compiled into this binary or registered, these are the valid `transform_type`s for [new]
+        pub fn transform_types() -> Vec<&'static str> {
+            let mut transform_types = vec![$(#[cfg(feature = $transform_type)] $transform_type,)*];
+            let registered = REGISTERED.read().expect("poisoned lock");
+            transform_types.extend(registered.iter().map(|(transform_type, _)| *transform_type));
+            transform_types
+        }
+
+        pub fn new(transform_type: &str, config: &str) -> Result<BoxMsgTransform> {
+            match transform_type {
+                $(
+                    #[cfg(feature = $transform_type)]
+                    $transform_type => {
+                        let transform = ($constructor)(config)
+                            .map_err(|err| Error::Initialization(err.to_string()))?;
+                        Ok(Box::new(transform))
+                    }
+                )*
+                _ => new_registered(transform_type, config),
+            }
+        }
+    };
+}
+
+// endregion
+
+// region:  --- Public Functions
+
+declare_transforms! {
+    "dev" => |_| Ok::<_, Error>(TransformDev::new()),
+    "json-map" => TransformJsonMap::new,
+}
+
+/// Makes a transform that lives outside this crate available to [new]. Transforms compiled in take precedence
+pub fn register(transform_type: &'static str, constructor: Constructor) {
+    REGISTERED
+        .write()
+        .expect("poisoned lock")
+        .push((transform_type, constructor));
+}
+
+// endregion
+
+// region:  --- Private Functions
+
+fn new_registered(transform_type: &str, config: &str) -> Result<BoxMsgTransform> {
+    let constructor = REGISTERED
+        .read()
+        .expect("poisoned lock")
+        .iter()
+        .find(|(registered, _)| *registered == transform_type)
+        .map(|(_, constructor)| *constructor);
+    match constructor {
+        Some(constructor) => constructor(config),
+        None => Err(Error::TransformTypeNotFound(
+            transform_type.to_string(),
+            transform_types(),
+        )),
+    }
+}
+
+// endregion
+
+#[cfg(test)]
+mod tests {
+    use data_source_core::{MsgBusData, TxData};
+    use msg_transform_core::{MsgTransform, TransformChain};
+
+    use super::*;
+
+    struct Drop;
+
+    impl MsgTransform for Drop {
+        fn transform(&mut self, _msg: MsgBusData) -> msg_transform_core::Result<Vec<MsgBusData>> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[test]
+    fn registered_transform() {
+        register("drop", |_| Ok(Box::new(Drop)));
+        assert!(transform_types().contains(&"drop"));
 
-pub fn init_msg_transformer() -> impl MsgTransform + Send {
-    // TODO - wrap possible choices in enum and choose from there
-    TransformDev::new()
-    //TransformSpecial::new()
+        let mut chain = TransformChain::new(TxData::new().0.msg_ids());
+        chain.push("drop", new("drop", "{}").unwrap());
+        assert!(chain.transform(MsgBusData::default()).unwrap().is_empty());
+        assert!(matches!(
+            new("nope", "{}"),
+            Err(Error::TransformTypeNotFound(..))
+        ));
+    }
 }
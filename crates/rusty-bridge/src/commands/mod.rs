@@ -0,0 +1,129 @@
+use cloud_adapter_core::Command;
+use data_source_core::MsgBusData;
+use futures::future::select_all;
+use tokio::{
+    select, spawn,
+    sync::broadcast::{self, error::RecvError},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::shutdown::termination::ExitReason;
+
+/// Called with each southbound command, the msg topic is the name of the command
+pub type SouthboundCallback = Box<dyn FnMut(MsgBusData) + Send>;
+
+/// Carries out the commands the cloud adapters receive. Reboot and config reload shut the bridge down with an exit
+/// reason the entrypoint restarts it on, anything else goes southbound
+pub struct CommandHandler {
+    rx_commands: Vec<broadcast::Receiver<Command>>,
+    southbound: Option<SouthboundCallback>,
+}
+
+impl CommandHandler {
+    pub fn new(rx_commands: Vec<broadcast::Receiver<Command>>) -> CommandHandler {
+        CommandHandler {
+            rx_commands,
+            southbound: None,
+        }
+    }
+
+    /// Southbound commands are passed to `callback`, they're dropped when nothing is registered
+    pub fn on_southbound(&mut self, callback: impl FnMut(MsgBusData) + Send + 'static) {
+        self.southbound = Some(Box::new(callback));
+    }
+
+    /// Runs until a command restarts the bridge, which cancels `shutdown_token`, or until it's cancelled otherwise.
+    /// Returns the reason the bridge should exit with
+    pub fn spawn(mut self, shutdown_token: CancellationToken) -> JoinHandle<ExitReason> {
+        spawn(async move {
+            while !self.rx_commands.is_empty() {
+                let (result, index, _) = select! {
+                    _ = shutdown_token.cancelled() => break,
+                    received = select_all(self.rx_commands.iter_mut().map(|rx| Box::pin(rx.recv()))) => received,
+                };
+
+                let command = match result {
+                    Ok(command) => command,
+                    Err(RecvError::Lagged(count)) => {
+                        warn!("Commands came in too fast, [{}] were dropped", count);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => {
+                        self.rx_commands.swap_remove(index);
+                        continue;
+                    }
+                };
+                if let Some(exit_reason) = self.handle(command) {
+                    shutdown_token.cancel();
+                    return exit_reason;
+                }
+            }
+
+            shutdown_token.cancelled().await;
+            debug!("Exiting command handler");
+            ExitReason::Success
+        })
+    }
+
+    /// The exit reason, for commands that restart the bridge
+    fn handle(&mut self, command: Command) -> Option<ExitReason> {
+        match command {
+            Command::Reboot => {
+                info!("Reboot command received, shutting down to restart");
+                Some(ExitReason::Reboot)
+            }
+            Command::ReloadConfig => {
+                info!(
+                    "Config reload command received, shutting down to restart with the new config"
+                );
+                Some(ExitReason::Reconfiguration)
+            }
+            Command::Southbound(msg) => {
+                match &mut self.southbound {
+                    Some(southbound) => southbound(msg),
+                    None => warn!(
+                        "Nothing handles southbound commands, dropping [{}]",
+                        msg.topic.as_deref().unwrap_or_default()
+                    ),
+                }
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use data_source_core::Bytes;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn southbound_then_reload() {
+        let (tx_command, rx_command) = broadcast::channel(4);
+        let mut handler = CommandHandler::new(vec![rx_command]);
+        let (tx_southbound, rx_southbound) = mpsc::channel();
+        handler.on_southbound(move |msg| tx_southbound.send(msg).unwrap());
+        let shutdown_token = CancellationToken::new();
+        let handle = handler.spawn(shutdown_token.clone());
+
+        tx_command
+            .send(Command::Southbound(MsgBusData {
+                topic: Some("setpoint".to_string()),
+                payload: Bytes::from_static(b"21.5"),
+                ..Default::default()
+            }))
+            .unwrap();
+        tx_command.send(Command::ReloadConfig).unwrap();
+
+        assert!(matches!(handle.await.unwrap(), ExitReason::Reconfiguration));
+        assert!(shutdown_token.is_cancelled());
+        let msg = rx_southbound.try_recv().unwrap();
+        assert_eq!(msg.topic.as_deref(), Some("setpoint"));
+        assert_eq!(msg.payload, &b"21.5"[..]);
+    }
+}
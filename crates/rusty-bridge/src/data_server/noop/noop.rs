@@ -1,4 +1,7 @@
-use crate::initialize::InitError;
+use crate::{health::Health, initialize::InitError};
+use cloud_adapter_core::ReconnectPolicy;
+use data_source_core::MsgId;
+use std::time::Duration;
 use tokio_util::sync::CancellationToken;
 use tracing::info;
 
@@ -11,8 +14,18 @@ pub async fn init_data_server(shutdown: CancellationToken) -> Result<DataServerH
 }
 
 impl DataServerHandle {
+    pub fn for_adapter(&self, adapter: usize) -> DataServerHandle {
+        DataServerHandle {}
+    }
     pub fn event_connection(&self, connected: bool) {}
-    pub fn event_rx_data(&self, id: u32) {}
-    pub fn event_pub_data(&self, id: u32) {}
-    pub fn event_pub_ack(&self, id: u32, success: bool) {}
+    pub fn event_rx_data(&self, id: MsgId) {}
+    pub fn event_pub_data(&self, id: MsgId) {}
+    pub fn event_pub_ack(&self, id: MsgId, success: bool) {}
+    pub fn event_ack_timeout(&self, id: MsgId, total: u64) {}
+    pub fn event_eviction(&self, ids: Vec<MsgId>, total: u64, rejected: bool) {}
+    pub fn event_routing_rules(&self, names: Vec<String>) {}
+    pub fn event_route(&self, rule: usize) {}
+    pub fn event_reconnect_policy(&self, policy: ReconnectPolicy) {}
+    pub fn event_reconnect(&self, attempt: u32, delay: Duration) {}
+    pub fn event_health(&self, state: Health) {}
 }
@@ -1,5 +1,11 @@
+use std::collections::BTreeMap;
+
+use cloud_adapter_core::ReconnectPolicy;
+use data_source_core::MsgId;
 use serde::{Deserialize, Serialize};
 
+use crate::health::Health;
+
 // Time since unix epoch, milliseconds
 pub type EpochTimeMS = u128;
 
@@ -7,22 +13,96 @@ pub type EpochTimeMS = u128;
 pub enum DataEvent {
     ConnectionEvent(ConnectionEvent),
     NewMsg {
-        id: u32,
+        id: MsgId,
         utc_time: EpochTimeMS,
     },
     PubMsg {
-        id: u32,
+        id: MsgId,
         utc_time: EpochTimeMS,
+        /// Index of the cloud adapter
+        adapter: usize,
     },
     AckMsg {
-        id: u32,
+        id: MsgId,
         utc_time: EpochTimeMS,
         success: bool,
+        adapter: usize,
+    },
+    /// No ack came for the msg in time, it's not counted as a nack
+    AckTimeout {
+        id: MsgId,
+        utc_time: EpochTimeMS,
+        adapter: usize,
+        /// Timeouts of the adapter since startup
+        total: u64,
+    },
+    /// Msgs dropped because persistence reached its highwater
+    Eviction {
+        ids: Vec<MsgId>,
+        adapter: usize,
+        utc_time: EpochTimeMS,
+        /// Evictions since startup
+        total: u64,
+        /// The msg was refused by the reject-and-report policy
+        rejected: bool,
     },
+    /// Names of the routing rules, in the order `RouteHit` refers to them
+    RoutingRules {
+        names: Vec<String>,
+    },
+    /// A msg was routed by this rule
+    RouteHit {
+        rule: usize,
+    },
+    /// How reconnection attempts are spaced out
+    ReconnectPolicy(ReconnectPolicy),
+    /// A reconnection attempt was scheduled
+    Reconnect(ReconnectEvent),
+    /// The health of a cloud adapter changed
+    Health(HealthEvent),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConnectionEvent {
     pub utc_time: EpochTimeMS,
     pub connected: bool,
+    /// Index of the cloud adapter
+    pub adapter: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthEvent {
+    pub utc_time: EpochTimeMS,
+    /// Index of the cloud adapter
+    pub adapter: usize,
+    pub state: Health,
+}
+
+/// The current health of each cloud adapter, along with the raw connect and disconnect events
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Connections {
+    pub health: BTreeMap<usize, HealthEvent>,
+    pub events: Vec<ConnectionEvent>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReconnectEvent {
+    pub utc_time: EpochTimeMS,
+    /// Index of the cloud adapter
+    pub adapter: usize,
+    /// Attempts since the last successful connection, starting at 1
+    pub attempt: u32,
+    pub delay_ms: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Reconnects {
+    pub policy: Option<ReconnectPolicy>,
+    pub attempts: Vec<ReconnectEvent>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteHits {
+    pub rule: String,
+    pub hits: u64,
 }
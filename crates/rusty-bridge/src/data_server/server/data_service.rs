@@ -10,8 +10,10 @@ use hyper::{body::Bytes, service::Service, Request, Response};
 use tracing::trace;
 
 use super::{
-    data_events::{ConnectionEvent, DataEvent},
-    request_handlers::{connection_events, health, msg_events},
+    data_events::{Connections, DataEvent, Reconnects, RouteHits},
+    request_handlers::{
+        connection_events, health, msg_events, persistence_events, reconnect_events, routing_stats,
+    },
 };
 
 //type Callback = fn(String) -> Result<Response<Full<Bytes>>, hyper::Error>;
@@ -25,8 +27,11 @@ pub struct Callback {
 pub struct DataService {
     // How to make the hashmap only need a &str?
     pub path_map: Arc<HashMap<&'static str, Callback>>,
-    pub connections: Arc<Mutex<Vec<ConnectionEvent>>>,
+    pub connections: Arc<Mutex<Connections>>,
     pub msgs: Arc<Mutex<Vec<DataEvent>>>,
+    pub persistence: Arc<Mutex<Vec<DataEvent>>>,
+    pub routes: Arc<Mutex<Vec<RouteHits>>>,
+    pub reconnects: Arc<Mutex<Reconnects>>,
 }
 
 impl DataService {
@@ -40,11 +45,27 @@ impl DataService {
             },
         );
         path_map.insert("/msg_events", Callback { cb: msg_events });
+        path_map.insert(
+            "/persistence_events",
+            Callback {
+                cb: persistence_events,
+            },
+        );
+        path_map.insert("/routing_stats", Callback { cb: routing_stats });
+        path_map.insert(
+            "/reconnect_events",
+            Callback {
+                cb: reconnect_events,
+            },
+        );
 
         DataService {
             path_map: Arc::new(path_map),
-            connections: Arc::new(Mutex::new(Vec::new())),
+            connections: Arc::new(Mutex::new(Connections::default())),
             msgs: Arc::new(Mutex::new(Vec::new())),
+            persistence: Arc::new(Mutex::new(Vec::new())),
+            routes: Arc::new(Mutex::new(Vec::new())),
+            reconnects: Arc::new(Mutex::new(Reconnects::default())),
         }
     }
 
@@ -54,10 +75,44 @@ impl DataService {
                 .connections
                 .lock()
                 .expect("connection event")
+                .events
                 .push(data),
             DataEvent::NewMsg { .. } => self.msgs.lock().expect("msg event").push(event),
             DataEvent::PubMsg { .. } => self.msgs.lock().expect("msg event").push(event),
             DataEvent::AckMsg { .. } => self.msgs.lock().expect("msg event").push(event),
+            DataEvent::AckTimeout { .. } => self.msgs.lock().expect("msg event").push(event),
+            DataEvent::Eviction { .. } => self
+                .persistence
+                .lock()
+                .expect("persistence event")
+                .push(event),
+            DataEvent::RoutingRules { names } => {
+                *self.routes.lock().expect("routing event") = names
+                    .into_iter()
+                    .map(|rule| RouteHits { rule, hits: 0 })
+                    .collect()
+            }
+            DataEvent::RouteHit { rule } => {
+                if let Some(route) = self.routes.lock().expect("routing event").get_mut(rule) {
+                    route.hits += 1;
+                }
+            }
+            DataEvent::ReconnectPolicy(policy) => {
+                self.reconnects.lock().expect("reconnect event").policy = Some(policy)
+            }
+            DataEvent::Reconnect(data) => self
+                .reconnects
+                .lock()
+                .expect("reconnect event")
+                .attempts
+                .push(data),
+            DataEvent::Health(data) => {
+                self.connections
+                    .lock()
+                    .expect("health event")
+                    .health
+                    .insert(data.adapter, data);
+            }
         }
     }
 }
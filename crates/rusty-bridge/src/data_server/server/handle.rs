@@ -1,13 +1,17 @@
+use cloud_adapter_core::ReconnectPolicy;
+use data_source_core::MsgId;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::{sync::mpsc::Sender, task::JoinHandle};
 use tracing::error;
 
-use super::data_events::{ConnectionEvent, DataEvent};
-use crate::error::RustyBridgeError;
+use super::data_events::{ConnectionEvent, DataEvent, HealthEvent, ReconnectEvent};
+use crate::{error::RustyBridgeError, health::Health};
 
 pub struct DataServerHandle {
     task: Option<JoinHandle<Result<(), RustyBridgeError>>>,
     tx_events: Sender<DataEvent>,
+    /// Index of the cloud adapter the events are about
+    adapter: usize,
 }
 
 impl DataServerHandle {
@@ -18,6 +22,16 @@ impl DataServerHandle {
         DataServerHandle {
             task: Some(task),
             tx_events,
+            adapter: 0,
+        }
+    }
+
+    /// A handle that tags the events it sends with the index of a cloud adapter
+    pub fn for_adapter(&self, adapter: usize) -> DataServerHandle {
+        DataServerHandle {
+            task: None,
+            tx_events: self.tx_events.clone(),
+            adapter,
         }
     }
 
@@ -25,12 +39,13 @@ impl DataServerHandle {
         let event = DataEvent::ConnectionEvent(ConnectionEvent {
             utc_time: get_time(),
             connected,
+            adapter: self.adapter,
         });
 
         self.send_data(event);
     }
 
-    pub fn event_rx_data(&self, id: u32) {
+    pub fn event_rx_data(&self, id: MsgId) {
         let event = DataEvent::NewMsg {
             utc_time: get_time(),
             id,
@@ -39,25 +54,83 @@ impl DataServerHandle {
         self.send_data(event);
     }
 
-    pub fn event_pub_data(&self, id: u32) {
+    pub fn event_pub_data(&self, id: MsgId) {
         let event = DataEvent::PubMsg {
             utc_time: get_time(),
             id,
+            adapter: self.adapter,
         };
 
         self.send_data(event);
     }
 
-    pub fn event_pub_ack(&self, id: u32, success: bool) {
+    pub fn event_pub_ack(&self, id: MsgId, success: bool) {
         let event = DataEvent::AckMsg {
             utc_time: get_time(),
             success,
             id,
+            adapter: self.adapter,
+        };
+
+        self.send_data(event);
+    }
+
+    pub fn event_ack_timeout(&self, id: MsgId, total: u64) {
+        let event = DataEvent::AckTimeout {
+            id,
+            utc_time: get_time(),
+            adapter: self.adapter,
+            total,
         };
 
         self.send_data(event);
     }
 
+    pub fn event_eviction(&self, ids: Vec<MsgId>, total: u64, rejected: bool) {
+        let event = DataEvent::Eviction {
+            ids,
+            adapter: self.adapter,
+            utc_time: get_time(),
+            total,
+            rejected,
+        };
+
+        self.send_data(event);
+    }
+
+    pub fn event_routing_rules(&self, names: Vec<String>) {
+        self.send_data(DataEvent::RoutingRules { names });
+    }
+
+    pub fn event_route(&self, rule: usize) {
+        self.send_data(DataEvent::RouteHit { rule });
+    }
+
+    pub fn event_reconnect_policy(&self, policy: ReconnectPolicy) {
+        self.send_data(DataEvent::ReconnectPolicy(policy));
+    }
+
+    pub fn event_reconnect(&self, attempt: u32, delay: Duration) {
+        let event = DataEvent::Reconnect(ReconnectEvent {
+            utc_time: get_time(),
+            adapter: self.adapter,
+            attempt,
+            delay_ms: delay.as_millis() as u64,
+        });
+
+        self.send_data(event);
+    }
+
+    pub fn event_health(&self, state: Health) {
+        let event = DataEvent::Health(HealthEvent {
+            utc_time: get_time(),
+            adapter: self.adapter,
+            state,
+        });
+
+        self.send_data(event);
+    }
+
     fn send_data(&self, event: DataEvent) {
         if let Err(err) = self.tx_events.try_send(event) {
             // If this fails, maybe the server crashed but that shouldn't happen. Maybe it's too busy.
@@ -61,3 +61,69 @@ pub fn msg_events(data_service: &DataService) -> HyperServiceReturn {
         .body(Full::new(Bytes::from(serialized)))
         .unwrap())
 }
+
+pub fn persistence_events(data_service: &DataService) -> HyperServiceReturn {
+    let data = data_service.persistence.lock().expect("poisoned lock");
+
+    let serialized = match serde_json::to_vec(&*data) {
+        Ok(serialized) => serialized,
+        Err(err) => {
+            warn!(
+                "Could not serialize data_service persistence events data. [{}]",
+                err
+            );
+            return Ok(Response::builder()
+                .header("Access-Control-Allow-Origin", "*")
+                .body(Full::new(Bytes::from("data serialization failed")))
+                .unwrap());
+        }
+    };
+
+    Ok(Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Full::new(Bytes::from(serialized)))
+        .unwrap())
+}
+
+pub fn routing_stats(data_service: &DataService) -> HyperServiceReturn {
+    let data = data_service.routes.lock().expect("poisoned lock");
+
+    let serialized = match serde_json::to_vec(&*data) {
+        Ok(serialized) => serialized,
+        Err(err) => {
+            warn!("Could not serialize data_service routing stats. [{}]", err);
+            return Ok(Response::builder()
+                .header("Access-Control-Allow-Origin", "*")
+                .body(Full::new(Bytes::from("data serialization failed")))
+                .unwrap());
+        }
+    };
+
+    Ok(Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Full::new(Bytes::from(serialized)))
+        .unwrap())
+}
+
+pub fn reconnect_events(data_service: &DataService) -> HyperServiceReturn {
+    let data = data_service.reconnects.lock().expect("poisoned lock");
+
+    let serialized = match serde_json::to_vec(&*data) {
+        Ok(serialized) => serialized,
+        Err(err) => {
+            warn!(
+                "Could not serialize data_service reconnect events. [{}]",
+                err
+            );
+            return Ok(Response::builder()
+                .header("Access-Control-Allow-Origin", "*")
+                .body(Full::new(Bytes::from("data serialization failed")))
+                .unwrap());
+        }
+    };
+
+    Ok(Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Full::new(Bytes::from(serialized)))
+        .unwrap())
+}
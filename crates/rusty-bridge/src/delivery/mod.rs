@@ -0,0 +1,101 @@
+use cloud_adapter_core::{DeliveryError, DeliveryFailure, TokenDelivery};
+use data_source_core::MsgId;
+use serde::Deserialize;
+use tokio::time::{timeout, Duration};
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct DeliveryConfig {
+    /// A msg that isn't acked within this long is persisted, as if it were nacked
+    #[serde(default = "default_ack_timeout_ms")]
+    pub ack_timeout_ms: u64,
+    /// Msgs published by an adapter and not acked yet. At this many, the adapter takes no more msgs until acks come in,
+    /// and the msgs routed to it are persisted. The data source is only held up when every adapter a msg is routed to is full
+    #[serde(default = "default_max_in_flight")]
+    pub max_in_flight: usize,
+    /// Msgs the data source can hand over before they are taken in. Past this, it is held up until there's room
+    #[serde(default = "default_ingress_capacity")]
+    pub ingress_capacity: usize,
+}
+
+impl Default for DeliveryConfig {
+    fn default() -> Self {
+        DeliveryConfig {
+            ack_timeout_ms: default_ack_timeout_ms(),
+            max_in_flight: default_max_in_flight(),
+            ingress_capacity: default_ingress_capacity(),
+        }
+    }
+}
+
+impl DeliveryConfig {
+    /// * `config` - json of the delivery section of the configuration, the defaults are used without one
+    pub fn new(config: Option<&str>) -> Result<DeliveryConfig, String> {
+        match config {
+            Some(config) => serde_json::from_str(config).map_err(|err| err.to_string()),
+            None => Ok(DeliveryConfig::default()),
+        }
+    }
+
+    pub fn ack_timeout(&self) -> Duration {
+        Duration::from_millis(self.ack_timeout_ms)
+    }
+}
+
+/// Waits for the ack of `msg_id`, giving up after `ack_timeout`. Adapters don't all give up on their own, a token can
+/// otherwise wait for as long as the connection stays up
+pub async fn wait_for_ack(
+    token: impl TokenDelivery,
+    msg_id: MsgId,
+    ack_timeout: Duration,
+) -> Result<MsgId, DeliveryError> {
+    timeout(ack_timeout, token.wait_for_ack())
+        .await
+        .map_err(|_| DeliveryError {
+            msg_id,
+            reason: DeliveryFailure::Timeout(ack_timeout),
+        })?
+}
+
+fn default_ack_timeout_ms() -> u64 {
+    30_000
+}
+
+fn default_max_in_flight() -> usize {
+    500
+}
+
+fn default_ingress_capacity() -> usize {
+    100
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+
+    use super::*;
+
+    struct NeverAcked;
+
+    #[async_trait]
+    impl TokenDelivery for NeverAcked {
+        async fn wait_for_ack(self) -> Result<MsgId, DeliveryError> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn times_out() {
+        let ack_timeout = Duration::from_millis(10);
+        let err = wait_for_ack(NeverAcked, 7, ack_timeout).await.unwrap_err();
+        assert_eq!(err.msg_id, 7);
+        assert!(matches!(err.reason, DeliveryFailure::Timeout(after) if after == ack_timeout));
+    }
+
+    #[test]
+    fn defaults_what_isnt_set() {
+        let delivery = DeliveryConfig::new(Some(r#"{"ingress_capacity": 1000}"#)).unwrap();
+        assert_eq!(delivery.ingress_capacity, 1000);
+        assert_eq!(delivery.max_in_flight, default_max_in_flight());
+        assert_eq!(delivery.ack_timeout_ms, default_ack_timeout_ms());
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::VecDeque;
+
+use data_source_core::MsgId;
+use serde::{Deserialize, Serialize};
+use tokio::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Health {
+    Disconnected,
+    Connecting,
+    Connected,
+    /// Too many nacks or acks too slow, msgs are persisted except for the probes
+    Degraded,
+    /// The circuit tripped, msgs are persisted except for the probes
+    Open,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BreakerConfig {
+    /// How many of the latest acks the rates are taken over. Nothing trips before this many came in
+    #[serde(default = "default_window")]
+    pub window: usize,
+    /// Share of nacks that degrades the adapter
+    #[serde(default = "default_degraded_nack_rate")]
+    pub degraded_nack_rate: f64,
+    /// Average time to an ack that degrades the adapter
+    #[serde(default = "default_degraded_latency_ms")]
+    pub degraded_latency_ms: u64,
+    /// Share of nacks that trips the circuit
+    #[serde(default = "default_open_nack_rate")]
+    pub open_nack_rate: f64,
+    /// While degraded or open, a msg is let through this often to probe the cloud
+    #[serde(default = "default_probe_interval_ms")]
+    pub probe_interval_ms: u64,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        BreakerConfig {
+            window: default_window(),
+            degraded_nack_rate: default_degraded_nack_rate(),
+            degraded_latency_ms: default_degraded_latency_ms(),
+            open_nack_rate: default_open_nack_rate(),
+            probe_interval_ms: default_probe_interval_ms(),
+        }
+    }
+}
+
+impl BreakerConfig {
+    /// * `config` - json of the circuit_breaker section of the configuration, the defaults are used without one
+    pub fn new(config: Option<&str>) -> Result<BreakerConfig, String> {
+        match config {
+            Some(config) => serde_json::from_str(config).map_err(|err| err.to_string()),
+            None => Ok(BreakerConfig::default()),
+        }
+    }
+}
+
+/// Health of one adapter. The connection moves it between Disconnected, Connecting and Connected, the outcomes of the
+/// acks between Connected, Degraded and Open. A probe that's acked closes the circuit again, one that isn't opens it
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    state: Health,
+    /// Whether each of the latest acks succeeded, and how long it took
+    outcomes: VecDeque<(bool, Duration)>,
+    /// The msg let through to probe the cloud, while degraded or open
+    probe: Option<MsgId>,
+    next_probe: Instant,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> CircuitBreaker {
+        CircuitBreaker {
+            outcomes: VecDeque::with_capacity(config.window),
+            config,
+            state: Health::Disconnected,
+            probe: None,
+            next_probe: Instant::now(),
+        }
+    }
+
+    pub fn state(&self) -> Health {
+        self.state
+    }
+
+    /// The new state, when it changed
+    pub fn connecting(&mut self) -> Option<Health> {
+        self.transition(Health::Connecting)
+    }
+
+    pub fn connected(&mut self) -> Option<Health> {
+        self.transition(Health::Connected)
+    }
+
+    pub fn disconnected(&mut self) -> Option<Health> {
+        self.transition(Health::Disconnected)
+    }
+
+    /// Whether a msg can be published. While degraded or open, one is let through per probe interval as the probe
+    pub fn allow(&mut self, msg_id: MsgId) -> bool {
+        match self.state {
+            Health::Connected => true,
+            Health::Degraded | Health::Open => {
+                let now = Instant::now();
+                if self.probe.is_some() || now < self.next_probe {
+                    return false;
+                }
+                self.probe = Some(msg_id);
+                self.next_probe = now + Duration::from_millis(self.config.probe_interval_ms);
+                true
+            }
+            Health::Disconnected | Health::Connecting => false,
+        }
+    }
+
+    /// When the next probe is due, while degraded or open and no probe is waiting on its ack
+    pub fn next_probe(&self) -> Option<Instant> {
+        match self.state {
+            Health::Degraded | Health::Open if self.probe.is_none() => Some(self.next_probe),
+            _ => None,
+        }
+    }
+
+    /// Counts the outcome of an ack. The new state, when it changed
+    pub fn ack(&mut self, msg_id: MsgId, success: bool, latency: Duration) -> Option<Health> {
+        match self.state {
+            Health::Connected => {
+                if self.outcomes.len() == self.config.window {
+                    self.outcomes.pop_front();
+                }
+                self.outcomes.push_back((success, latency));
+                if self.outcomes.len() < self.config.window {
+                    return None;
+                }
+
+                let count = self.outcomes.len() as f64;
+                let nack_rate =
+                    self.outcomes.iter().filter(|(acked, _)| !acked).count() as f64 / count;
+                let latency = self
+                    .outcomes
+                    .iter()
+                    .map(|(_, latency)| *latency)
+                    .sum::<Duration>()
+                    / self.outcomes.len() as u32;
+                if nack_rate >= self.config.open_nack_rate {
+                    self.transition(Health::Open)
+                } else if nack_rate >= self.config.degraded_nack_rate
+                    || latency >= Duration::from_millis(self.config.degraded_latency_ms)
+                {
+                    self.transition(Health::Degraded)
+                } else {
+                    None
+                }
+            }
+            Health::Degraded | Health::Open if self.probe == Some(msg_id) => {
+                self.probe = None;
+                let healthy =
+                    success && latency < Duration::from_millis(self.config.degraded_latency_ms);
+                if healthy {
+                    self.transition(Health::Connected)
+                } else {
+                    self.transition(Health::Open)
+                }
+            }
+            // Acks of msgs published before the state changed
+            _ => None,
+        }
+    }
+
+    fn transition(&mut self, state: Health) -> Option<Health> {
+        if self.state == state {
+            return None;
+        }
+        self.state = state;
+        self.outcomes.clear();
+        self.probe = None;
+        self.next_probe = Instant::now() + Duration::from_millis(self.config.probe_interval_ms);
+
+        Some(state)
+    }
+}
+
+fn default_window() -> usize {
+    20
+}
+
+fn default_degraded_nack_rate() -> f64 {
+    0.2
+}
+
+fn default_degraded_latency_ms() -> u64 {
+    5000
+}
+
+fn default_open_nack_rate() -> f64 {
+    0.5
+}
+
+fn default_probe_interval_ms() -> u64 {
+    5000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connected_breaker() -> CircuitBreaker {
+        let mut breaker = CircuitBreaker::new(BreakerConfig {
+            window: 4,
+            probe_interval_ms: 0,
+            ..Default::default()
+        });
+        breaker.connecting();
+        breaker.connected();
+        breaker
+    }
+
+    #[test]
+    fn slow_acks_degrade() {
+        let mut breaker = connected_breaker();
+        for msg_id in 0..3 {
+            assert_eq!(breaker.ack(msg_id, true, Duration::from_secs(6)), None);
+        }
+        assert_eq!(
+            breaker.ack(3, true, Duration::from_secs(6)),
+            Some(Health::Degraded)
+        );
+
+        // Only the probe gets through, and it being acked quickly closes the circuit
+        assert!(breaker.allow(4));
+        assert!(!breaker.allow(5));
+        assert_eq!(breaker.ack(2, true, Duration::ZERO), None);
+        assert_eq!(
+            breaker.ack(4, true, Duration::from_millis(10)),
+            Some(Health::Connected)
+        );
+        assert!(breaker.allow(6));
+    }
+
+    #[test]
+    fn nacks_trip_the_circuit() {
+        let mut breaker = connected_breaker();
+        let latency = Duration::from_millis(10);
+        breaker.ack(0, true, latency);
+        breaker.ack(1, false, latency);
+        breaker.ack(2, true, latency);
+        assert_eq!(breaker.ack(3, false, latency), Some(Health::Open));
+
+        assert!(breaker.next_probe().is_some());
+        assert!(breaker.allow(4));
+        assert_eq!(breaker.next_probe(), None);
+        assert_eq!(breaker.ack(4, false, latency), None);
+        assert_eq!(breaker.state(), Health::Open);
+        assert!(breaker.next_probe().is_some());
+
+        assert_eq!(breaker.disconnected(), Some(Health::Disconnected));
+        assert!(!breaker.allow(5));
+    }
+}
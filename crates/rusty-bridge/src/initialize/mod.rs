@@ -1,21 +1,30 @@
-use cloud_adapter_core::CloudAdapterTrait;
+use cloud_adapter_core::{CloudAdapterTrait, ReconnectPolicy};
 use data_source::new_data_source;
 use data_source_core::{DataSourceInterface, RxData, TxData};
 use edge_reporter::EdgeReporter;
 use mini_config::new_config;
 use mini_config_core::ConfigData;
 use mini_config_core::MiniConfigInterface;
-use msg_transform_core::MsgTransform;
-use msg_transforms::init_msg_transformer;
+use msg_persistence::MsgPersistence;
+use msg_transform_core::TransformChain;
 use tokio_util::sync::CancellationToken;
-
-use crate::{data_server::DataServerHandle, error::RustyBridgeError, persistence::init_persistence};
+use tracing::info;
+
+use crate::{
+    data_server::DataServerHandle,
+    delivery::DeliveryConfig,
+    error::RustyBridgeError,
+    health::BreakerConfig,
+    persistence::init_persistence,
+    reconnect::{reconnect_policy, with_reconnect},
+    routing::Router,
+};
 
 use self::signals::register_shutdown_signals;
 
 mod signals;
 
-// throttle_rate * token_timeout will give the potential in-flight queue size for the ack queue
+// throttle_rate * ack_timeout_ms will give the potential in-flight queue size for the ack queue
 // PUBLISH_CHANNEL_CAPACITY should be set to some reasonable amount.  The only way to find out is to benchmark how fast it can move messages from message bus to the ack channel
 const PUBLISH_CHANNEL_CAPACITY: usize = 1000;
 
@@ -28,15 +37,22 @@ pub enum InitError {
     CloudAdapter(String),
     DataServer(String),
     EdgeReporter(String),
+    Persistence(String),
+    Routing(String),
+    Transform(String),
 }
 
-/// Initialization of dependencies. This will create the concrete types of `CloudAdapter`, `MessageBusInterface`, `MsgTransform`.
+/// Initialization of dependencies. This will create the concrete types of `CloudAdapter`, `MessageBusInterface`, and the chain of `MsgTransform`s.
 /// These generic impl's are made concrete by specifying feature flags. For example, `--features="msg-bus/dev"` will build the developer build
 ///
 pub async fn initialize() -> Result<(
-    impl CloudAdapterTrait,
+    Vec<(impl CloudAdapterTrait, MsgPersistence)>,
+    Router,
+    ReconnectPolicy,
+    BreakerConfig,
+    DeliveryConfig,
     impl DataSourceInterface,
-    impl MsgTransform,
+    TransformChain,
     ConfigData,
     RxData,
     DataServerHandle,
@@ -59,25 +75,69 @@ pub async fn initialize() -> Result<(
         .await
         .map_err(|err| InitError::Configuration(err.to_string()))?;
 
+    // Create the CloudAdapters -- the logic that will transform and publish a message to the cloud. Every msg goes to each of them
+    // Each gets its own persistence, msgs that can't be delivered are stored there until they are acknowledged
+    if config_data.north_adapters.is_empty() {
+        return Err(InitError::CloudAdapter("no north adapters are configured".to_string()).into());
+    }
+    // Every adapter spaces out its reconnection attempts the same way, unless it's configured otherwise
+    let reconnect = reconnect_policy(config_data.reconnect.as_deref())
+        .map_err(InitError::Configuration)?;
+    metrics_handle.event_reconnect_policy(reconnect.clone());
+    // Each adapter gets its own circuit breaker, they all trip on the same thresholds
+    let breaker = BreakerConfig::new(config_data.circuit_breaker.as_deref())
+        .map_err(InitError::Configuration)?;
+    // Acks that never come would otherwise be waited on for as long as the connection is up
+    let delivery = DeliveryConfig::new(config_data.delivery.as_deref())
+        .map_err(InitError::Configuration)?;
+    let mut adapters = Vec::with_capacity(config_data.north_adapters.len());
+    for (index, north_adapter) in config_data.north_adapters.iter().enumerate() {
+        info!("Using cloud adapter [{}]: [{}]", index, north_adapter.adapter_type);
+        let config = with_reconnect(&north_adapter.config, &reconnect)
+            .map_err(InitError::CloudAdapter)?;
+        let adapter = cloud_adapter::new(&north_adapter.adapter_type, &config)
+            .map_err(|err| InitError::CloudAdapter(err.to_string()))?;
+        let persistence = init_persistence(&config_data.persistence, index)
+            .map_err(|err| InitError::Persistence(err.to_string()))?;
+        adapters.push((adapter, persistence));
+    }
+
     // Create the publish channels -- that are used for getting a message from the message-bus to the msg-engine
+    // Ids continue after the newest msg waiting in any persistence, so msgs replayed from a previous run never share an id with new ones
     // TODO - pass metrics handle here so that data can be submitted at each .send() call
-    let (tx_new_msg, rx_new_msg) = TxData::new();
+    let mut last_id = None;
+    for (_, persistence) in &adapters {
+        let persisted = persistence
+            .last_id()
+            .map_err(|err| InitError::Persistence(err.to_string()))?;
+        last_id = last_id.max(persisted);
+    }
+    let (tx_new_msg, rx_new_msg) =
+        TxData::with_capacity_after(delivery.ingress_capacity.max(1), last_id);
+    // Msgs split by a transform take their ids from the same sequence
+    let msg_ids = tx_new_msg.msg_ids();
 
     // Initialize DataSource -- This is where the connector receives messages from. Additional data sources should use the same tx..
     let data_source = new_data_source(tx_new_msg, &config_data.data_source)
         .await
         .map_err(|err| InitError::MessageBus(err.to_string()))?;
 
-    // Create persistence
-    let persistence = init_persistence();
-
-    // Create the CloudAdapter -- the logic that will transform and publish a message to the cloud
-    // TODO create all connectors here, eventually I want to support multiple connectors but not till I really know the flow of everything yet
-    let adapter = cloud_adapter::new("special-hivemq", &config_data.north_adapters)
-        .map_err(|err| InitError::CloudAdapter(format!("{:?}", err)))?;
-
-    // Create the Transform object -- this transform, transforms the msg-bus message to a format the cloud server is expecting
-    let transform = init_msg_transformer();
+    // Create the Router -- it decides which of the adapters each msg goes to
+    let router = Router::new(config_data.routing.as_deref(), adapters.len())
+        .map_err(InitError::Routing)?;
+    metrics_handle.event_routing_rules(router.route_names());
+
+    // Create the Transforms -- every msg goes through them in order before it's routed. Without any, msgs are delivered as they came in
+    let mut transform = TransformChain::new(msg_ids);
+    for transform_config in &config_data.transforms {
+        info!("Using transform [{}]", transform_config.transform_type);
+        let step = msg_transforms::new(
+            &transform_config.transform_type,
+            &transform_config.config,
+        )
+        .map_err(|err| InitError::Transform(err.to_string()))?;
+        transform.push(transform_config.transform_type.clone(), step);
+    }
 
     // The edge reporter reports this edge to the cloud. It helps track all JCI edges in one location
     let edge_reporter = EdgeReporter::new(&config_data.edge_reporter)
@@ -85,7 +145,11 @@ pub async fn initialize() -> Result<(
     let reporter_handle = edge_reporter.start_reporting();
 
     Ok((
-        adapter,
+        adapters,
+        router,
+        reconnect,
+        breaker,
+        delivery,
         data_source,
         transform,
         config_data,
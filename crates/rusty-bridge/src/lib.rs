@@ -1,9 +1,14 @@
+pub mod commands;
 //#[cfg(feature = "data-server")]
 pub mod data_server;
+pub mod delivery;
 pub mod error;
+pub mod health;
 pub mod initialize;
 pub mod main_loop;
 pub mod persistence;
+pub mod reconnect;
+pub mod routing;
 pub mod shutdown;
 pub mod startup;
 pub mod title;
@@ -1,11 +1,14 @@
+use cloud_adapter_core::CloudAdapterTrait;
+use data_source_core::DataSourceInterface;
 use rusty_bridge::{
+    commands::CommandHandler,
     error::{Result, RustyBridgeError},
     initialize::initialize,
     main_loop::main_loop,
     shutdown::{shutdown, termination::ExitReason},
     title::{print_exit_title, print_title3},
 };
-use tracing::error;
+use tracing::{error, warn};
 
 const PKG_NAME: &str = env!("CARGO_PKG_NAME");
 const PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -23,28 +26,65 @@ fn main() {
     exit(result);
 }
 
-async fn run(package_name: &str) -> Result<()> {
+async fn run(package_name: &str) -> Result<ExitReason> {
     println!("Starting {}", package_name);
 
     // Initialize required objects
-    let (adapter, data_source, transform, config_data, rx_new_msg, metrics_events, shutdown_token) =
-        initialize()
-            .await
-            .map_err(|err| RustyBridgeError::Initialization(format!("{:?}", err)))?;
+    let (
+        adapters,
+        router,
+        reconnect,
+        breaker,
+        delivery,
+        data_source,
+        transform,
+        config_data,
+        rx_new_msg,
+        metrics_events,
+        shutdown_token,
+    ) = initialize()
+        .await
+        .map_err(|err| RustyBridgeError::Initialization(format!("{:?}", err)))?;
+
+    // Carry out the commands the cloud sends, some of them restart the bridge
+    let rx_commands = adapters
+        .iter()
+        .filter_map(|(adapter, _)| adapter.commands())
+        .collect();
+    let mut command_handler = CommandHandler::new(rx_commands);
+    if let Some(tx_southbound) = data_source.southbound() {
+        command_handler.on_southbound(move |msg| {
+            if let Err(err) = tx_southbound.send(msg) {
+                warn!("Southbound command dropped: [{}]", err);
+            }
+        });
+    }
+    let command_handle = command_handler.spawn(shutdown_token.clone());
 
     // Run the main loop
-    main_loop(metrics_events, adapter, rx_new_msg, shutdown_token).await;
+    main_loop(
+        metrics_events,
+        adapters,
+        transform,
+        router,
+        reconnect,
+        breaker,
+        delivery,
+        rx_new_msg,
+        shutdown_token,
+    )
+    .await;
 
     // Perform any shutdown logic
     shutdown().await;
 
-    Ok(())
+    Ok(command_handle.await.unwrap_or(ExitReason::Unknown))
 }
 
-fn exit(result: core::result::Result<(), RustyBridgeError>) {
-    println!("Exiting with result: [{:?}]", result);
+fn exit(result: core::result::Result<ExitReason, RustyBridgeError>) {
+    println!("Exiting with result: [{:?}]", result.as_ref().map(|_| ()));
     let exit_reason = match result {
-        Ok(_) => ExitReason::Success,
+        Ok(exit_reason) => exit_reason,
         Err(err) => {
             error!("Exiting with error: [{}]", err);
             err.into()
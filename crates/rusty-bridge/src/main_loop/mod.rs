@@ -1,19 +1,48 @@
 use cloud_adapter_core::{
-    CloudAdapterTrait, ConnectionError, ConnectionLost, DeliveryError, TokenDelivery,
+    Backoff, CloudAdapterTrait, ConnectionError, ConnectionLost, DeliveryError, DeliveryFailure,
+    ReconnectPolicy, TokenDelivery,
 };
-use data_source_core::{DataSourceInterface, MsgBusData, RxData};
+use data_source_core::{DataSourceInterface, MsgBusData, MsgId, RxData};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use futures::future::{join_all, select_all};
+use msg_persistence::MsgPersistence;
+use msg_transform_core::MsgTransform;
 use tokio::{
-    select, spawn,
+    join, select, spawn,
     sync::{
+        broadcast,
         mpsc::{self, error::TrySendError, Receiver, Sender},
         watch,
     },
     task::JoinSet,
+    time::{sleep_until, Duration, Instant},
 };
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, trace, warn};
 
-use crate::data_server::DataServerHandle;
+use crate::{
+    data_server::DataServerHandle,
+    delivery::{wait_for_ack, DeliveryConfig},
+    health::{BreakerConfig, CircuitBreaker, Health},
+    persistence::{start_persistence_publish_thread, Replaying, NO_REWIND},
+    routing::Router,
+};
+
+/// Capacity of the channel msgs replayed from persistence come through
+const REPLAY_CHANNEL_CAPACITY: usize = 100;
+/// Capacity of the channel each adapter receives new msgs through
+const ADAPTER_CHANNEL_CAPACITY: usize = 100;
+
+/// The id of the published msg, how its token resolved and how long that took. Persistence is keyed on the id that was
+/// published, not on the one the adapter reports
+type AckResult = (MsgId, Result<MsgId, (DeliveryError, MsgBusData)>, Duration);
 
 // TWO states of operation.  Regular and Persistence
 // Regular mode: -only enters after exiting persistence mode
@@ -37,62 +66,336 @@ use crate::data_server::DataServerHandle;
 // Priority channel - this channel has minimal activity and is reserved for adapter choice. For example, heartbeats and command received
 //
 
+/// Runs msgs through the transforms, then delivers them to the cloud adapters the router picks. Each adapter runs its own
+/// loop with its own connection, acks and persistence partition, so an adapter that is slow or offline doesn't hold back
+/// the others
+#[allow(clippy::too_many_arguments)]
 pub async fn main_loop(
     metrics_events: DataServerHandle,
-    mut adapter: impl CloudAdapterTrait + Send,
+    adapters: Vec<(impl CloudAdapterTrait + Send, MsgPersistence)>,
+    transform: impl MsgTransform,
+    router: Router,
+    reconnect: ReconnectPolicy,
+    breaker: BreakerConfig,
+    delivery: DeliveryConfig,
+    rx_msg: RxData,
+    shutdown_token: CancellationToken,
+) {
+    let mut outlets = Vec::with_capacity(adapters.len());
+    let mut adapter_loops = Vec::with_capacity(adapters.len());
+    for (index, (adapter, persistence)) in adapters.into_iter().enumerate() {
+        let (tx_adapter, rx_adapter) = mpsc::channel(ADAPTER_CHANNEL_CAPACITY);
+        let overflowed = Arc::new(AtomicBool::new(false));
+        outlets.push(Outlet {
+            index,
+            tx_msg: tx_adapter,
+            persistence: persistence.clone(),
+            metrics_events: metrics_events.for_adapter(index),
+            overflowed: overflowed.clone(),
+        });
+        adapter_loops.push(adapter_loop(
+            index,
+            metrics_events.for_adapter(index),
+            adapter,
+            rx_adapter,
+            persistence,
+            overflowed,
+            reconnect.backoff(),
+            CircuitBreaker::new(breaker.clone()),
+            delivery.clone(),
+            shutdown_token.clone(),
+        ));
+    }
+
+    // The loops run concurrently on this task. They can't be spawned, the futures of CloudAdapterTrait aren't Send
+    join!(
+        fan_out(
+            rx_msg,
+            outlets,
+            transform,
+            router,
+            &metrics_events,
+            shutdown_token
+        ),
+        join_all(adapter_loops)
+    );
+}
+
+/// The channel of one adapter's loop
+struct Outlet {
+    index: usize,
+    tx_msg: Sender<MsgBusData>,
+    /// The adapter's persistence partition, msgs that come in while its channel is full go here
+    persistence: MsgPersistence,
+    metrics_events: DataServerHandle,
+    /// Set when a msg went to persistence because the channel was full, the adapter's loop replays it once it catches up
+    overflowed: Arc<AtomicBool>,
+}
+
+impl Outlet {
+    fn overflow(&self, msg: &MsgBusData) {
+        debug!(
+            "Adapter [{}] is falling behind, persisting msg [{}]",
+            self.index, msg.id
+        );
+        persist(&self.persistence, &self.metrics_events, msg);
+        self.overflowed.store(true, Ordering::Relaxed);
+    }
+}
+
+async fn fan_out(
     mut rx_msg: RxData,
+    outlets: Vec<Outlet>,
+    mut transform: impl MsgTransform,
+    router: Router,
+    metrics_events: &DataServerHandle,
+    shutdown_token: CancellationToken,
+) {
+    loop {
+        let msg = select! {
+            mailbox = rx_msg.recv() => match mailbox {
+                Some(msg) => msg,
+                None => break,
+            },
+            _ = shutdown_token.cancelled() => break,
+        };
+
+        // Only msgs that just came in, replayed msgs were transformed before they were persisted
+        let msg_id = msg.id;
+        let msgs = match transform.transform(msg) {
+            Ok(msgs) => msgs,
+            Err(err) => {
+                warn!(
+                    "Msg [{}] dropped, it could not be transformed. [{}]",
+                    msg_id, err
+                );
+                continue;
+            }
+        };
+        if msgs.is_empty() {
+            trace!("Msg [{}] dropped by the transforms", msg_id);
+        }
+
+        for msg in msgs {
+            let route = router.route(&msg);
+            metrics_events.event_route(route.rule);
+            if route.adapters.is_empty() {
+                trace!("Msg [{}] dropped by routing", msg.id);
+                continue;
+            }
+            deliver(&outlets, route.adapters, msg).await;
+        }
+    }
+
+    // Dropping the outlets lets the adapter loops finish
+    info!("Exiting fan out");
+}
+
+/// Hands a msg to the loops of the adapters it was routed to, without waiting on any one of them. An adapter whose
+/// channel is full, because it is slow to ack, gets the msg in its persistence instead. Only when every one of them is
+/// full does this wait, for the first to have room, which holds up the data source
+async fn deliver(outlets: &[Outlet], routed: &[usize], msg: MsgBusData) {
+    let mut full = Vec::new();
+    let mut delivered = false;
+    for index in routed {
+        let outlet = &outlets[*index];
+        match outlet.tx_msg.try_reserve() {
+            Ok(permit) => {
+                permit.send(msg.clone());
+                delivered = true;
+            }
+            Err(TrySendError::Full(())) => full.push(outlet),
+            Err(TrySendError::Closed(())) => trace!(
+                "Adapter [{}] has stopped, dropping msg [{}]",
+                outlet.index,
+                msg.id
+            ),
+        }
+    }
+    if full.is_empty() {
+        return;
+    }
+
+    if !delivered {
+        let reserves = full.iter().map(|outlet| Box::pin(outlet.tx_msg.reserve()));
+        let (permit, position, _) = select_all(reserves).await;
+        let outlet = full.remove(position);
+        match permit {
+            Ok(permit) => permit.send(msg.clone()),
+            Err(_) => trace!(
+                "Adapter [{}] has stopped, dropping msg [{}]",
+                outlet.index,
+                msg.id
+            ),
+        }
+    }
+    for outlet in full {
+        outlet.overflow(&msg);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn adapter_loop(
+    index: usize,
+    metrics_events: DataServerHandle,
+    mut adapter: impl CloudAdapterTrait + Send,
+    mut rx_msg: Receiver<MsgBusData>,
+    persistence: MsgPersistence,
+    overflowed: Arc<AtomicBool>,
+    mut backoff: Backoff,
+    mut breaker: CircuitBreaker,
+    delivery: DeliveryConfig,
     shutdown_token: CancellationToken,
 ) {
     let mut ack_tasks = create_ack_task_set(shutdown_token.clone());
     let mut connect_tasks = create_connect_task_set(shutdown_token.clone());
     let (tx_conn_status, mut rx_conn_status) = mpsc::channel(10);
 
-    // Ready to start, begin with attempting a connection to the cloud
-    let token = adapter.connect().await.unwrap();
-    connect_tasks.spawn(async move { token.await });
+    // On every connection, the persisted backlog is replayed through this channel. Its pace depends on how many acks are outstanding
+    let (tx_conn_broadcast, _) = broadcast::channel(10);
+    let (tx_replay, mut rx_replay) = mpsc::channel(REPLAY_CHANNEL_CAPACITY);
+    let (tx_in_flight, rx_in_flight) = watch::channel(0);
+    let rewind = Arc::new(AtomicU64::new(NO_REWIND));
+    let replaying = Replaying::default();
+    let replay_handle = start_persistence_publish_thread(
+        persistence.clone(),
+        tx_conn_broadcast.subscribe(),
+        tx_replay,
+        rx_in_flight,
+        rewind.clone(),
+        replaying.clone(),
+    );
+
+    let ack_timeout = delivery.ack_timeout();
+    let max_in_flight = delivery.max_in_flight.max(1);
+    // Acks that never came since startup, counted apart from the nacks
+    let mut ack_timeouts = 0u64;
+
+    // Ready to start, begin with attempting a connection to the cloud. Later attempts are spaced out by the backoff
+    let mut reconnect_at = Some(Instant::now());
+    // Msgs journaled but never acked by a previous run. No new data is published before them, they go out within the
+    // in-flight window while the adapter is connected and healthy, and stay journaled until they are acked or persisted
+    let mut requeued = match persistence.unacked_journal() {
+        Ok(msgs) => VecDeque::from(msgs),
+        Err(err) => {
+            error!("Could not read the write-ahead journal, unacked msgs from the previous run are lost. [{}]", err);
+            VecDeque::new()
+        }
+    };
+    if !requeued.is_empty() {
+        info!(
+            "[{}] unacked msgs from adapter [{}]'s write-ahead journal will be published ahead of new data",
+            requeued.len(),
+            index
+        );
+    }
 
-    let mut connected = false;
+    // Probe the replay was asked for, a backlog with nothing to send isn't asked again until the next probe is due
+    let mut probe_requested = None;
+    // The replay asked for a probe is stopped again once the probe is sent
+    let mut probe_replay = false;
 
     let exit_reason = loop {
+        let probe_at = breaker
+            .next_probe()
+            .filter(|at| probe_requested != Some(*at));
         select! {
-            // Receive messages to publish
-            mailbox = rx_msg.recv() => {
+            // Publish what the previous run left unacked, while there's room in the in-flight window
+            _ = async {}, if !requeued.is_empty() && breaker.state() == Health::Connected && in_flight(&ack_tasks) < max_in_flight => {
+                if let Some(msg) = requeued.pop_front() {
+                    debug!("Publishing unacked msg [{}] from the write-ahead journal", msg.id);
+                    publish(&mut adapter, &mut ack_tasks, &metrics_events, ack_timeout, msg);
+                    tx_in_flight.send_replace(in_flight(&ack_tasks));
+                }
+            },
+            // Receive messages to publish, while there's room in the in-flight window. New data waits while the unacked msgs
+            // are being published, and is persisted while they wait for the adapter
+            mailbox = rx_msg.recv(), if (requeued.is_empty() || breaker.state() != Health::Connected) && in_flight(&ack_tasks) < max_in_flight => {
                 match mailbox {
                     Some(msg) => {
-                        if connected {
+                        if requeued.is_empty() && breaker.allow(msg.id) {
                             trace!("Publishing [{:?}]", msg);
                             debug!("Publishing [{}]", msg.id);
-                            metrics_events.event_pub_data(msg.id);
-                            let token = adapter.publish(msg);
-                            ack_tasks.spawn(async move {
-                                handle_token(token).await
-                            });
+                            if let Err(err) = persistence.journal(&msg) {
+                                error!("Could not journal msg [{}] before publishing. [{}]", msg.id, err);
+                            }
+                            publish(&mut adapter, &mut ack_tasks, &metrics_events, ack_timeout, msg);
+                            tx_in_flight.send_replace(in_flight(&ack_tasks));
                         } else {
-                            debug!("TODO - Persisting msg [{}] due to no connectivity", msg.id)
+                            debug!("Persisting msg [{}], adapter is [{:?}]", msg.id, breaker.state());
+                            persist(&persistence, &metrics_events, &msg);
                         }
                     },
                     None => break "tx dropped",
                 }
             },
+            // Receive msgs replayed from persistence
+            mailbox = rx_replay.recv(), if in_flight(&ack_tasks) < max_in_flight => {
+                match mailbox {
+                    Some(msg) => {
+                        if breaker.allow(msg.id) {
+                            debug!("Publishing persisted msg [{}], retry [{}]", msg.id, msg.retry_count);
+                            publish(&mut adapter, &mut ack_tasks, &metrics_events, ack_timeout, msg);
+                            tx_in_flight.send_replace(in_flight(&ack_tasks));
+                        } else {
+                            // It is still in persistence, the next replay starts from it
+                            trace!("Adapter is [{:?}], persisted msg [{}] will be sent on the next replay", breaker.state(), msg.id);
+                            rewind.fetch_min(msg.id, Ordering::Relaxed);
+                            replaying.lock().expect("poisoned lock").remove(&msg.id);
+                            if std::mem::take(&mut probe_replay) {
+                                let _ = tx_conn_broadcast.send(false);
+                            }
+                        }
+                    },
+                    None => break "persistence replay stopped",
+                }
+            },
             // Handle what to do when a published message's token resolves
             // TODO - benchmark if it's faster to pass the token through a channel and handle this joinset in its own task
             mailbox_task = ack_tasks.join_next() => {
+                tx_in_flight.send_replace(in_flight(&ack_tasks));
                 match mailbox_task {
                     Some(join_result) => match join_result {
-                        Ok(ack_result) => {
-                            match ack_result {
-                                Ok(msg_id) => {
+                        Ok((msg_id, ack_result, latency)) => {
+                            let health = match ack_result {
+                                Ok(acked_id) => {
+                                    if acked_id != msg_id {
+                                        trace!("Adapter reported msg [{}] acked as [{}]", msg_id, acked_id);
+                                    }
                                     debug!("Msg Ack. Msg Id: [{}]", msg_id);
                                     metrics_events.event_pub_ack(msg_id, true);
-                                    // TODO - remove message from persistence
-                                    trace!("todo: removing Msg Id [{}] from persistence", msg_id);
+                                    // The msg may have been a replay from persistence, it is delivered now so it can go
+                                    if let Err(err) = persistence.remove(msg_id) {
+                                        error!("Could not remove msg [{}] from persistence. [{}]", msg_id, err);
+                                    }
+                                    clear_journal(&persistence, msg_id);
+                                    breaker.ack(msg_id, true, latency)
                                 },
-                                Err(err) => {
-                                    warn!("No Ack received for msg: [{}], reason: [{}]", err.msg_id, err.reason);
-                                    metrics_events.event_pub_ack(err.msg_id, false);
-                                    // TODO - persistence
-                                    trace!("todo: adding to msg id [{}] to persistence", err.msg_id);
+                                Err((err, mut msg)) => {
+                                    warn!("No Ack received for msg: [{}], reason: [{}]", msg_id, err.reason);
+                                    match err.reason {
+                                        DeliveryFailure::Nack(_) => metrics_events.event_pub_ack(msg_id, false),
+                                        DeliveryFailure::Timeout(_) => {
+                                            ack_timeouts += 1;
+                                            metrics_events.event_ack_timeout(msg_id, ack_timeouts);
+                                        }
+                                    }
+                                    msg.retry_count += 1;
+                                    persist(&persistence, &metrics_events, &msg);
+                                    // Persistence has it now
+                                    clear_journal(&persistence, msg_id);
+                                    breaker.ack(msg_id, false, latency)
                                 },
+                            };
+                            // Settled, a replayed msg is either out of persistence or to be replayed again
+                            replaying.lock().expect("poisoned lock").remove(&msg_id);
+                            if let Some(health) = report_health(index, health, &metrics_events) {
+                                // The backlog is only replayed while the adapter is healthy
+                                let _ = tx_conn_broadcast.send(health == Health::Connected);
+                            }
+                            // Msgs fan out persisted while this adapter's channel was full are replayed as acks come in
+                            if breaker.state() == Health::Connected && overflowed.swap(false, Ordering::Relaxed) {
+                                let _ = tx_conn_broadcast.send(true);
                             }
                         },
                         Err(err) => {
@@ -107,15 +410,14 @@ pub async fn main_loop(
                 match mailbox {
                     Some(conn_status) => {
                         metrics_events.event_connection(conn_status);
+                        // No receivers is fine, it only means the replay thread has stopped
+                        let _ = tx_conn_broadcast.send(conn_status);
                         if conn_status == false {
-                            connected = false;
+                            report_health(index, breaker.disconnected(), &metrics_events);
                             // The if statement below safeguards against creating additional connection tasks when it is already occuring
                             // I don't know if this situation could ever occur, but this is just incase
-                            if connect_tasks.len() == 1 { // there's 1 dummy task, so check for 1
-                                let token = adapter.connect().await.unwrap();
-                                connect_tasks.spawn(async move {
-                                    token.await
-                                });
+                            if connect_tasks.len() == 1 && reconnect_at.is_none() { // there's 1 dummy task, so check for 1
+                                reconnect_at = schedule_reconnect(index, &mut backoff, &metrics_events);
                             } else {
                                 error!("Multiple connection tasks attempted, this msg is to indiciate it is occuring but shouldn't be")
                             }
@@ -131,8 +433,9 @@ pub async fn main_loop(
                     Some(join_result) => match join_result {
                         Ok(connection_result) => match connection_result {
                             Ok(mut rx_conn_lost) => {
-                                connected = true;
-                                info!("Connection attempt success");
+                                report_health(index, breaker.connected(), &metrics_events);
+                                backoff.reset();
+                                info!("Adapter [{}] connection attempt success", index);
                                 // Burn whatever may have last been posted in rx_conn_lost
                                 let _ = rx_conn_lost.borrow_and_update();
                                 tx_conn_status.try_send(true).unwrap();
@@ -143,7 +446,7 @@ pub async fn main_loop(
                                     match rx_conn_lost.changed().await {
                                         Ok(_) => {
                                             let reason = rx_conn_lost.borrow_and_update().to_string(); // This gets weird without the `.to_string()`
-                                            warn!("Connection lost: [{}]", reason);
+                                            warn!("Adapter [{}] connection lost: [{}]", index, reason);
                                         },
                                         Err(err) => debug!("Connection possibly lost, sender was dropped: [{}]", err), // debug until I pass the cancel token to this task and priority the token in select!
                                     }
@@ -154,45 +457,162 @@ pub async fn main_loop(
                             },
                             Err(err) => {
                                 // Connection attempt failed, try it again
-                                warn!("Connection attempt failed. [{}]", err);
-                                let token = adapter.connect().await.unwrap();
-                                connect_tasks.spawn(async move {
-                                    token.await
-                                });
+                                warn!("Adapter [{}] connection attempt failed. [{}]", index, err);
+                                report_health(index, breaker.disconnected(), &metrics_events);
+                                reconnect_at = schedule_reconnect(index, &mut backoff, &metrics_events);
                             }
                         },
                         Err(err) => {
                             error!("Join failed on connection task [{}]", err);
-                            let token = adapter.connect().await.unwrap();
-                            connect_tasks.spawn(async move {
-                                token.await
-                            });
+                            report_health(index, breaker.disconnected(), &metrics_events);
+                            reconnect_at = schedule_reconnect(index, &mut backoff, &metrics_events);
                         },
                     },
                     None => break "connect tasks are empty",
                 }
             },
+            // No msg came in to probe the degraded or open adapter, a persisted one probes it instead
+            _ = sleep_until(probe_at.unwrap_or_else(Instant::now)), if probe_at.is_some() => {
+                probe_requested = probe_at;
+                if !persistence.is_empty() {
+                    debug!("Adapter [{}] is [{:?}], probing it with a persisted msg", index, breaker.state());
+                    probe_replay = true;
+                    let _ = tx_conn_broadcast.send(true);
+                }
+            },
+            // Make the connection attempt that's due
+            _ = sleep_until(reconnect_at.unwrap_or_else(Instant::now)), if reconnect_at.is_some() => {
+                reconnect_at = None;
+                report_health(index, breaker.connecting(), &metrics_events);
+                match adapter.connect().await {
+                    Ok(token) => {
+                        connect_tasks.spawn(async move {
+                            token.await
+                        });
+                    },
+                    Err(err) => {
+                        warn!("Adapter [{}] could not start a connection attempt. [{}]", index, err);
+                        report_health(index, breaker.disconnected(), &metrics_events);
+                        reconnect_at = schedule_reconnect(index, &mut backoff, &metrics_events);
+                    },
+                }
+            },
             _ = shutdown_token.cancelled() => break "shutdown token was cancelled"
         }
     };
-    info!("Exiting main_loop [{}]", exit_reason);
+    info!("Exiting adapter [{}] loop [{}]", index, exit_reason);
+    replay_handle.abort();
 
     if let Ok(token) = adapter.disconnect() {
         let _ = token.await;
     }
 }
 
-async fn handle_token(token: impl TokenDelivery) -> Result<u32, DeliveryError> {
-    token.wait_for_ack().await
+/// When to make the next connection attempt, the data server is told about it
+fn schedule_reconnect(
+    index: usize,
+    backoff: &mut Backoff,
+    metrics_events: &DataServerHandle,
+) -> Option<Instant> {
+    let delay = backoff.next_delay();
+    info!(
+        "Adapter [{}] reconnecting in [{}]ms, attempt [{}]",
+        index,
+        delay.as_millis(),
+        backoff.attempt()
+    );
+    metrics_events.event_reconnect(backoff.attempt(), delay);
+
+    Some(Instant::now() + delay)
+}
+
+/// Tells the data server when the adapter's health changed, the new health is passed on
+fn report_health(
+    index: usize,
+    health: Option<Health>,
+    metrics_events: &DataServerHandle,
+) -> Option<Health> {
+    if let Some(health) = health {
+        info!("Adapter [{}] is [{:?}]", index, health);
+        metrics_events.event_health(health);
+    }
+    health
+}
+
+fn publish(
+    adapter: &mut impl CloudAdapterTrait,
+    ack_tasks: &mut JoinSet<AckResult>,
+    metrics_events: &DataServerHandle,
+    ack_timeout: Duration,
+    msg: MsgBusData,
+) {
+    metrics_events.event_pub_data(msg.id);
+    // Keep a copy so it can be persisted if it is never acknowledged
+    let token = adapter.publish(msg.clone());
+    ack_tasks.spawn(async move { handle_token(token, msg, ack_timeout).await });
+}
+
+/// Number of acks being waited on
+fn in_flight<T>(ack_tasks: &JoinSet<T>) -> usize {
+    // Ignore the dummy task that keeps the set from being empty
+    ack_tasks.len().saturating_sub(1)
+}
+
+/// Waits for the delivery result, up to `ack_timeout`. On failure the msg is handed back so it can be persisted
+async fn handle_token(
+    token: impl TokenDelivery,
+    msg: MsgBusData,
+    ack_timeout: Duration,
+) -> AckResult {
+    let published = Instant::now();
+    let msg_id = msg.id;
+    let result = wait_for_ack(token, msg_id, ack_timeout)
+        .await
+        .map_err(|err| (err, msg));
+    (msg_id, result, published.elapsed())
+}
+
+fn clear_journal(persistence: &MsgPersistence, msg_id: MsgId) {
+    if let Err(err) = persistence.clear_journal(msg_id) {
+        error!(
+            "Could not clear msg [{}] from the write-ahead journal, it will be sent again after a restart. [{}]",
+            msg_id, err
+        );
+    }
+}
+
+fn persist(persistence: &MsgPersistence, metrics_events: &DataServerHandle, msg: &MsgBusData) {
+    match persistence.persist(msg) {
+        Ok(evicted) => {
+            if !evicted.msg_ids.is_empty() {
+                warn!(
+                    "Persistence is full, evicted msgs: [{:?}]. [{}] evicted so far",
+                    evicted.msg_ids, evicted.total
+                );
+                metrics_events.event_eviction(evicted.msg_ids, evicted.total, false);
+            }
+        }
+        Err(msg_persistence::Error::QuotaExceeded { msg_id, total, .. }) => {
+            error!(
+                "Persistence is full, msg [{}] was rejected and is lost",
+                msg_id
+            );
+            metrics_events.event_eviction(vec![msg_id], total, true);
+        }
+        Err(err) => error!(
+            "Msg [{}] could not be persisted and is lost. [{}]",
+            msg.id, err
+        ),
+    }
 }
 
-fn create_ack_task_set(shutdown: CancellationToken) -> JoinSet<Result<u32, DeliveryError>> {
+fn create_ack_task_set(shutdown: CancellationToken) -> JoinSet<AckResult> {
     let mut tasks = JoinSet::new();
 
     // Spawn an indefinite task so that .join_next() doesn't return None
     tasks.spawn(async {
         shutdown.cancelled_owned().await;
-        Ok(0)
+        (0, Ok(0), Duration::ZERO)
     });
     tasks
 }
@@ -220,3 +640,256 @@ fn create_connect_task_set(
     });
     tasks
 }
+
+#[cfg(all(test, feature = "data-server"))]
+mod tests {
+    use std::{
+        fs,
+        future::Future,
+        path::{Path, PathBuf},
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use async_trait::async_trait;
+    use cloud_adapter_core::TokenConnection;
+    use data_source_core::Bytes;
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::data_server::server::data_events::{DataEvent, HealthEvent};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        std::env::temp_dir().join(format!("{}-{}", name, nanos))
+    }
+
+    fn metrics_events() -> DataServerHandle {
+        let (tx_events, _) = mpsc::channel(1);
+        DataServerHandle::new(spawn(async { Ok(()) }), tx_events)
+    }
+
+    fn outlet(path: &Path, index: usize) -> (Outlet, Receiver<MsgBusData>) {
+        let (tx_msg, rx_msg) = mpsc::channel(1);
+        let config = format!(r#"{{"path": {:?}}}"#, path);
+        let outlet = Outlet {
+            index,
+            tx_msg,
+            persistence: MsgPersistence::new(&config, 0).unwrap(),
+            metrics_events: metrics_events(),
+            overflowed: Arc::new(AtomicBool::new(false)),
+        };
+        (outlet, rx_msg)
+    }
+
+    /// Connects right away, nacks its first `nacks` publishes and acks the rest. Reports the ids it publishes
+    struct Cloud {
+        tx_published: mpsc::UnboundedSender<MsgId>,
+        nacks: usize,
+        tx_conn_lost: Option<watch::Sender<ConnectionLost>>,
+    }
+
+    struct Delivery(Result<MsgId, DeliveryError>);
+
+    #[async_trait]
+    impl TokenDelivery for Delivery {
+        async fn wait_for_ack(self) -> Result<MsgId, DeliveryError> {
+            self.0
+        }
+    }
+
+    impl CloudAdapterTrait for Cloud {
+        fn publish(&mut self, msg: MsgBusData) -> impl TokenDelivery + Send + 'static {
+            let _ = self.tx_published.send(msg.id);
+            if self.nacks > 0 {
+                self.nacks -= 1;
+                return Delivery(Err(DeliveryError {
+                    msg_id: msg.id,
+                    reason: DeliveryFailure::Nack("refused".to_string()),
+                }));
+            }
+            Delivery(Ok(msg.id))
+        }
+
+        async fn connect(
+            &mut self,
+        ) -> Result<
+            TokenConnection<
+                impl Future<Output = Result<watch::Receiver<ConnectionLost>, ConnectionError>>
+                    + Send
+                    + 'static,
+            >,
+            ConnectionError,
+        > {
+            let (tx_conn_lost, rx_conn_lost) =
+                watch::channel(ConnectionLost::Uncategorized("connected".to_string()));
+            self.tx_conn_lost = Some(tx_conn_lost);
+            Ok(TokenConnection {
+                future: async move { Ok(rx_conn_lost) },
+            })
+        }
+
+        fn disconnect(
+            &mut self,
+        ) -> Result<
+            impl Future<Output = Result<(), ConnectionError>> + Send + 'static,
+            ConnectionError,
+        > {
+            Ok(async { Ok(()) })
+        }
+    }
+
+    fn msg(id: MsgId) -> MsgBusData {
+        MsgBusData {
+            id,
+            payload: Bytes::from_static(b"data"),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_adapter_doesnt_block() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let paths =
+            [0, 1].map(|index| std::env::temp_dir().join(format!("fan-out-{}-{}", nanos, index)));
+        let (slow, mut rx_slow) = outlet(&paths[0], 0);
+        let (fast, mut rx_fast) = outlet(&paths[1], 1);
+        let outlets = [slow, fast];
+
+        // The slow adapter's channel is full, the msg goes to its persistence
+        deliver(&outlets, &[0], msg(1)).await;
+        deliver(&outlets, &[0, 1], msg(2)).await;
+        assert_eq!(rx_fast.recv().await.unwrap().id, 2);
+        assert_eq!(outlets[0].persistence.iterate_from(0, 10).unwrap()[0].id, 2);
+        assert!(outlets[0].overflowed.load(Ordering::Relaxed));
+
+        // With both full, the data source waits for the first to have room
+        deliver(&outlets, &[1], msg(3)).await;
+        let all_full = deliver(&outlets, &[0, 1], msg(4));
+        tokio::pin!(all_full);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), &mut all_full)
+                .await
+                .is_err()
+        );
+        assert_eq!(rx_slow.recv().await.unwrap().id, 1);
+        all_full.await;
+        assert_eq!(rx_slow.recv().await.unwrap().id, 4);
+        assert_eq!(outlets[1].persistence.iterate_from(0, 10).unwrap()[0].id, 4);
+        // Backends that keep nothing on disk never create them
+        for path in paths {
+            let _ = fs::remove_dir_all(path);
+        }
+    }
+
+    #[tokio::test]
+    async fn quiet_open_circuit_is_probed() {
+        let path = temp_dir("probe");
+        let persistence = MsgPersistence::new(&format!(r#"{{"path": {:?}}}"#, path), 0).unwrap();
+        let (tx_published, mut rx_published) = mpsc::unbounded_channel();
+        let cloud = Cloud {
+            tx_published,
+            nacks: 1,
+            tx_conn_lost: None,
+        };
+        let breaker = CircuitBreaker::new(BreakerConfig {
+            window: 1,
+            probe_interval_ms: 50,
+            ..Default::default()
+        });
+        let (tx_msg, rx_msg) = mpsc::channel(1);
+        let shutdown_token = CancellationToken::new();
+        let adapter = adapter_loop(
+            0,
+            metrics_events(),
+            cloud,
+            rx_msg,
+            persistence,
+            Arc::new(AtomicBool::new(false)),
+            ReconnectPolicy::default().backoff(),
+            breaker,
+            DeliveryConfig::default(),
+            shutdown_token.clone(),
+        );
+
+        let checks = async {
+            tx_msg.send(msg(1)).await.unwrap();
+            // The nack opens the circuit and no other msg comes in, the persisted msg is sent again as the probe
+            assert_eq!(rx_published.recv().await, Some(1));
+            let probe = timeout(Duration::from_secs(1), rx_published.recv()).await;
+            assert_eq!(probe.unwrap(), Some(1));
+            shutdown_token.cancel();
+        };
+        join!(adapter, checks);
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn journal_published_ahead_of_new_data() {
+        let path = temp_dir("requeue");
+        let journal_path = temp_dir("requeue-journal");
+        let config = format!(
+            r#"{{"path": {:?}, "write_ahead": true, "journal_path": {:?}}}"#,
+            path, journal_path
+        );
+        let persistence = MsgPersistence::new(&config, 0).unwrap();
+        // Left unacked by the previous run
+        persistence.journal(&msg(1)).unwrap();
+        persistence.journal(&msg(2)).unwrap();
+        let (tx_published, mut rx_published) = mpsc::unbounded_channel();
+        let cloud = Cloud {
+            tx_published,
+            nacks: 0,
+            tx_conn_lost: None,
+        };
+        let (tx_events, mut rx_events) = mpsc::channel(16);
+        let (tx_msg, rx_msg) = mpsc::channel(1);
+        let shutdown_token = CancellationToken::new();
+        let adapter = adapter_loop(
+            0,
+            DataServerHandle::new(spawn(async { Ok(()) }), tx_events),
+            cloud,
+            rx_msg,
+            persistence.clone(),
+            Arc::new(AtomicBool::new(false)),
+            ReconnectPolicy::default().backoff(),
+            CircuitBreaker::new(BreakerConfig::default()),
+            DeliveryConfig::default(),
+            shutdown_token.clone(),
+        );
+
+        let checks = async {
+            // New data that comes in as soon as the adapter is connected
+            while let Some(event) = rx_events.recv().await {
+                if let DataEvent::Health(HealthEvent {
+                    state: Health::Connected,
+                    ..
+                }) = event
+                {
+                    break;
+                }
+            }
+            tx_msg.send(msg(3)).await.unwrap();
+            for id in 1..=3 {
+                assert_eq!(rx_published.recv().await, Some(id));
+            }
+            // Once acked, nothing is left journaled or persisted
+            let settled = async {
+                while !persistence.unacked_journal().unwrap().is_empty() || !persistence.is_empty()
+                {
+                    sleep_until(Instant::now() + Duration::from_millis(10)).await;
+                }
+            };
+            assert!(timeout(Duration::from_secs(1), settled).await.is_ok());
+            shutdown_token.cancel();
+        };
+        join!(adapter, checks);
+        let _ = fs::remove_dir_all(path);
+        let _ = fs::remove_dir_all(journal_path);
+    }
+}
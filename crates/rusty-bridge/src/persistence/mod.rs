@@ -1,73 +1,405 @@
-use data_source_core::MsgBusData;
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use data_source_core::{MsgBusData, MsgId};
 use msg_persistence::MsgPersistence;
 use tokio::{
-    pin, select,
-    sync::{broadcast, mpsc},
+    select,
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc::{self, Permit},
+        watch,
+    },
     task::JoinHandle,
+    time::{sleep, Duration},
 };
-use tracing::{debug, info, trace, warn};
+use tracing::{debug, error, info, trace, warn};
 
 const PUBLISH_THREAD_NAME: &str = "publish on connect";
-const THROTTLE_RATE_MS: u64 = 5; // 200 per second
+/// How many msgs are read from persistence at a time
+const REPLAY_BATCH_SIZE: usize = 100;
+/// Replay pauses while this many acks are outstanding, so live data isn't starved
+const REPLAY_MAX_IN_FLIGHT: usize = 200;
+/// Delay added per outstanding ack before each replayed msg. The more the cloud lags behind, the slower the replay
+const REPLAY_DELAY_PER_IN_FLIGHT_US: u64 = 50;
+/// Held by the rewind while no replayed msg has been refused
+pub const NO_REWIND: MsgId = MsgId::MAX;
 
-// TODO - this can be abstracted for different persistence types, but that's probably not an avenue I'll take anytime soon
-pub fn init_persistence() -> MsgPersistence {
-    MsgPersistence::new()
+/// Ids of the replayed msgs handed to publishing whose delivery isn't settled. They stay persisted until they are acked,
+/// so replays skip them until main_loop takes them out on their ack, nack, timeout or refusal
+pub type Replaying = Arc<Mutex<HashSet<MsgId>>>;
+
+/// The persistence backend is chosen by the `msg-persistence` feature flags
+/// * `partition` - index of the cloud adapter, each has its own persistence
+pub fn init_persistence(config: &str, partition: usize) -> msg_persistence::Result<MsgPersistence> {
+    let persistence = MsgPersistence::new(config, partition)?;
+    if !persistence.is_empty() {
+        info!(
+            "[{}] msgs are waiting in persistence [{}] from a previous run",
+            persistence.len(),
+            partition
+        );
+    }
+
+    Ok(persistence)
 }
 
 /// Starts the thread that listens for connection notices.
 /// If a connection status of true comes through, the database will be iterated through and each message sent through the publishing channel
+/// * `rx_in_flight` - the number of acks main_loop is waiting on, used to pace the replay
+/// * `rewind` - the lowest id of the replayed msgs main_loop refused to publish, `NO_REWIND` if none.
+///   The next replay starts from there, rather than leaving them for the wrap-around
+/// * `replaying` - filled in as msgs are sent, see [Replaying]
 pub fn start_persistence_publish_thread(
     persistence: MsgPersistence,
-    mut rx_conn_status: broadcast::Receiver<bool>,
+    rx_conn_status: broadcast::Receiver<bool>,
     tx_publish: mpsc::Sender<MsgBusData>,
+    rx_in_flight: watch::Receiver<usize>,
+    rewind: Arc<AtomicU64>,
+    replaying: Replaying,
 ) -> JoinHandle<()> {
-    tokio::task::spawn(
-        async move { publish_on_connect(persistence, rx_conn_status, tx_publish).await },
-    )
+    tokio::task::spawn(async move {
+        publish_on_connect(
+            persistence,
+            rx_conn_status,
+            tx_publish,
+            rx_in_flight,
+            rewind,
+            replaying,
+        )
+        .await
+    })
+}
+
+/// How a replay of the persisted backlog ended
+enum Replay {
+    /// Everything in persistence was sent
+    Complete,
+    /// Connection was lost, the msg with this id is where to pick up next time
+    Interrupted(MsgId),
+    /// main_loop is gone
+    Closed,
 }
 
 async fn publish_on_connect(
     persistence: MsgPersistence,
     mut rx_conn_status: broadcast::Receiver<bool>,
     tx_publish: mpsc::Sender<MsgBusData>,
+    mut rx_in_flight: watch::Receiver<usize>,
+    rewind: Arc<AtomicU64>,
+    replaying: Replaying,
 ) {
     debug!("Starting '{}' thread", PUBLISH_THREAD_NAME);
+    // Where the previous replay stopped
+    let mut resume_from = 0;
     loop {
         // TODO - Every x minutes, check if messages exist in the db. If they do, and we are connected, send them?
         // alternatively, maybe subscribe for the event of something being persisted, if it occurs, unsubscribe and send all?
         // but if something is persisted, then the internet is possibly out
+        match rx_conn_status.recv().await {
+            Ok(true) => {
+                // Msgs main_loop refused are still in persistence, pick up from the first of them
+                resume_from = resume_from.min(rewind.swap(NO_REWIND, Ordering::Relaxed));
+                if persistence.is_empty() {
+                    trace!("Connected, nothing in persistence to send");
+                    continue;
+                }
+                info!(
+                    "Beginning to send [{}] msgs from persistence, starting at msg [{}]",
+                    persistence.len(),
+                    resume_from
+                );
+                match replay(
+                    &persistence,
+                    &mut rx_conn_status,
+                    &tx_publish,
+                    &mut rx_in_flight,
+                    &replaying,
+                    resume_from,
+                )
+                .await
+                {
+                    Replay::Complete => {
+                        info!("Finished sending msgs from persistence");
+                        resume_from = 0;
+                        // Most of the backlog should be acked by now
+                        if let Err(err) = persistence.compact() {
+                            error!("Could not compact persistence. [{}]", err);
+                        }
+                    }
+                    Replay::Interrupted(next_id) => {
+                        info!(
+                            "Connection lost while sending msgs from persistence, will resume at msg [{}]",
+                            next_id
+                        );
+                        resume_from = next_id;
+                    }
+                    Replay::Closed => break,
+                }
+            }
+            // This is mostly here for debugging purposes, see if the thread is alive, etc
+            Ok(false) => trace!(
+                "Persistence, publish on connect thread rxd false connection status. Nothing to do.."
+            ),
+            Err(RecvError::Closed) => break,
+            Err(RecvError::Lagged(skipped_count)) => warn!(
+                "Lag detected in '{}', missed status msg count: [{}]",
+                PUBLISH_THREAD_NAME, skipped_count
+            ),
+        }
+    }
+
+    info!("Exiting persistence '{}' thread", PUBLISH_THREAD_NAME);
+}
+
+/// Sends everything from `start_id` to the end of persistence, then wraps around to send what comes before `start_id`.
+/// Msgs still waiting on their ack from an earlier replay are skipped, nacked ones are sent again by the next replay
+async fn replay(
+    persistence: &MsgPersistence,
+    rx_conn_status: &mut broadcast::Receiver<bool>,
+    tx_publish: &mpsc::Sender<MsgBusData>,
+    rx_in_flight: &mut watch::Receiver<usize>,
+    replaying: &Replaying,
+    start_id: MsgId,
+) -> Replay {
+    let mut next_id = start_id;
+    let mut wrapped = start_id == 0;
+    loop {
+        let batch = match persistence.iterate_from(next_id, REPLAY_BATCH_SIZE) {
+            Ok(batch) => batch,
+            Err(err) => {
+                error!("Could not read msgs from persistence. [{}]", err);
+                return Replay::Interrupted(next_id);
+            }
+        };
 
-        // Hoist the futures
-        pin! {
-            let conn_status = rx_conn_status.recv();
+        if batch.is_empty() {
+            if wrapped {
+                return Replay::Complete;
+            }
+            wrapped = true;
+            next_id = 0;
+            continue;
         }
 
-        select! {
-            mailbox = conn_status => {
-                match mailbox {
-                    Ok(status) =>
-                        if true == status {
-                            info!("Beginning to send [{}] msgs from persistence!", 1);
-                            // Fake loop until this is implemented
-                            for _ in 0..1 {
-                                let data = MsgBusData {payload:"data from persistence".as_bytes().to_vec() ,retry_count:1, id: 0 };
-                                trace!("Sending persisted message to publishing");
-                                let _ = tx_publish.send(data).await;
-                                tokio::time::sleep(tokio::time::Duration::from_millis(THROTTLE_RATE_MS)).await;
-                            }
-                        } else {
-                            // This is mostly here for debugging purposes, see if the thread is alive, etc
-                            trace!("Persistence, publish on connect thread rxd false connection status. Nothing to do..")
-                        },
-                    Err(err) => match err {
-                        broadcast::error::RecvError::Closed => break,
-                        broadcast::error::RecvError::Lagged(skipped_count) => warn!("Lag detected in '{}', missed status msg count: [{}]", PUBLISH_THREAD_NAME, skipped_count),
+        for msg in batch {
+            if wrapped && start_id != 0 && msg.id >= start_id {
+                return Replay::Complete;
+            }
+            if replaying.lock().expect("poisoned lock").contains(&msg.id) {
+                trace!("Persisted msg [{}] is still waiting on its ack", msg.id);
+                next_id = msg.id.saturating_add(1);
+                continue;
+            }
+
+            // Wait for room, but give up on this replay as soon as the connection is lost
+            let permit = loop {
+                select! {
+                    biased;
+                    status = rx_conn_status.recv() => match status {
+                        Ok(false) => return Replay::Interrupted(msg.id),
+                        Err(RecvError::Closed) => return Replay::Closed,
+                        Ok(true) | Err(RecvError::Lagged(_)) => continue,
+                    },
+                    permit = reserve_paced(tx_publish, rx_in_flight) => match permit {
+                        Some(permit) => break permit,
+                        None => return Replay::Closed,
                     },
                 }
-            }
+            };
+
+            trace!("Sending persisted msg [{}] to publishing", msg.id);
+            next_id = msg.id.saturating_add(1);
+            // Before it's sent, so main_loop can't settle it first
+            replaying.lock().expect("poisoned lock").insert(msg.id);
+            permit.send(msg);
+        }
+
+        // The last possible msg was just sent
+        if next_id == MsgId::MAX {
+            return Replay::Complete;
+        }
+    }
+}
+
+/// Waits until main_loop has few enough acks outstanding, then for a slot in the publish channel
+async fn reserve_paced<'a>(
+    tx_publish: &'a mpsc::Sender<MsgBusData>,
+    rx_in_flight: &mut watch::Receiver<usize>,
+) -> Option<Permit<'a, MsgBusData>> {
+    let in_flight = match rx_in_flight
+        .wait_for(|in_flight| *in_flight < REPLAY_MAX_IN_FLIGHT)
+        .await
+    {
+        Ok(in_flight) => *in_flight,
+        Err(_) => return None,
+    };
+    if in_flight > 0 {
+        sleep(Duration::from_micros(
+            in_flight as u64 * REPLAY_DELAY_PER_IN_FLIGHT_US,
+        ))
+        .await;
+    }
+
+    tx_publish.reserve().await.ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::PathBuf,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use data_source_core::{Bytes, TxData};
+    use msg_transform_core::{MsgTransform, TransformChain};
+    use tokio::time::timeout;
+
+    use super::*;
+
+    /// Unique per run, backends that keep nothing on disk never create it
+    fn temp_dir(name: &str) -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        std::env::temp_dir().join(format!("{}-{}", name, nanos))
+    }
+
+    #[tokio::test]
+    async fn resumes_from_refused_msg() {
+        let path = temp_dir("replay-rewind");
+        let persistence = MsgPersistence::new(&format!(r#"{{"path": {:?}}}"#, path), 0).unwrap();
+        for id in 1..=5 {
+            let msg = MsgBusData {
+                id,
+                payload: Bytes::from_static(b"data"),
+                ..Default::default()
+            };
+            persistence.persist(&msg).unwrap();
+        }
+        let (tx_conn_status, rx_conn_status) = broadcast::channel(10);
+        let (tx_publish, mut rx_publish) = mpsc::channel(1);
+        let (_tx_in_flight, rx_in_flight) = watch::channel(0);
+        let rewind = Arc::new(AtomicU64::new(NO_REWIND));
+        let replaying = Replaying::default();
+        let handle = start_persistence_publish_thread(
+            persistence,
+            rx_conn_status,
+            tx_publish,
+            rx_in_flight,
+            rewind.clone(),
+            replaying.clone(),
+        );
+
+        tx_conn_status.send(true).unwrap();
+        assert_eq!(rx_publish.recv().await.unwrap().id, 1);
+        assert_eq!(rx_publish.recv().await.unwrap().id, 2);
+        // Msg 2 is refused and the connection is lost, whatever the replay got to after it is left in the channel
+        rewind.fetch_min(2, Ordering::Relaxed);
+        replaying.lock().unwrap().remove(&2);
+        tx_conn_status.send(false).unwrap();
+        while let Ok(Some(_)) = timeout(Duration::from_millis(10), rx_publish.recv()).await {}
+
+        tx_conn_status.send(true).unwrap();
+        assert_eq!(rx_publish.recv().await.unwrap().id, 2);
+        assert_eq!(rewind.load(Ordering::Relaxed), NO_REWIND);
+        handle.abort();
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn skips_msgs_waiting_on_acks() {
+        let path = temp_dir("replay-in-flight");
+        let persistence = MsgPersistence::new(&format!(r#"{{"path": {:?}}}"#, path), 0).unwrap();
+        for id in 1..=3 {
+            let msg = MsgBusData {
+                id,
+                payload: Bytes::from_static(b"data"),
+                ..Default::default()
+            };
+            persistence.persist(&msg).unwrap();
+        }
+        let (tx_conn_status, rx_conn_status) = broadcast::channel(10);
+        let (tx_publish, mut rx_publish) = mpsc::channel(10);
+        let (_tx_in_flight, rx_in_flight) = watch::channel(0);
+        let replaying = Replaying::default();
+        let handle = start_persistence_publish_thread(
+            persistence,
+            rx_conn_status,
+            tx_publish,
+            rx_in_flight,
+            Arc::new(AtomicU64::new(NO_REWIND)),
+            replaying.clone(),
+        );
+
+        tx_conn_status.send(true).unwrap();
+        for id in 1..=3 {
+            assert_eq!(rx_publish.recv().await.unwrap().id, id);
+        }
+        // None of them are acked yet, a second trigger must not send them again
+        tx_conn_status.send(true).unwrap();
+        assert!(timeout(Duration::from_millis(100), rx_publish.recv())
+            .await
+            .is_err());
+
+        // Msg 2 is nacked, it goes back to persistence and is the only one replayed
+        replaying.lock().unwrap().remove(&2);
+        tx_conn_status.send(true).unwrap();
+        assert_eq!(rx_publish.recv().await.unwrap().id, 2);
+        assert!(timeout(Duration::from_millis(100), rx_publish.recv())
+            .await
+            .is_err());
+        handle.abort();
+        let _ = fs::remove_dir_all(path);
+    }
+
+    /// Splits a payload on commas
+    struct Split;
+
+    impl MsgTransform for Split {
+        fn transform(&mut self, msg: MsgBusData) -> msg_transform_core::Result<Vec<MsgBusData>> {
+            let parts = msg.payload.split(|byte| *byte == b',');
+            Ok(parts
+                .map(|part| MsgBusData {
+                    payload: Bytes::copy_from_slice(part),
+                    ..msg.clone()
+                })
+                .collect())
+        }
+    }
+
+    #[test]
+    fn split_msgs_acked_apart() {
+        let path = temp_dir("split-acks");
+        let persistence = MsgPersistence::new(&format!(r#"{{"path": {:?}}}"#, path), 0).unwrap();
+        let (tx_data, _rx_data) = TxData::new();
+        let mut chain = TransformChain::new(tx_data.msg_ids());
+        chain.push("split", Split);
+
+        let msg = MsgBusData {
+            id: tx_data.msg_ids().next(),
+            payload: Bytes::from_static(b"a,b"),
+            ..Default::default()
+        };
+        let parts = chain.transform(msg).unwrap();
+        for part in &parts {
+            persistence.persist(part).unwrap();
         }
+        assert_eq!(persistence.len(), 2);
 
-        info!("Exiting persistence '{}' thread", PUBLISH_THREAD_NAME);
+        // Acking the first part leaves the second to be replayed
+        persistence.remove(parts[0].id).unwrap();
+        let left = persistence.iterate_from(0, 10).unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].id, parts[1].id);
+        assert_eq!(&left[0].payload[..], b"b");
+        let _ = fs::remove_dir_all(path);
     }
 }
@@ -0,0 +1,47 @@
+use cloud_adapter_core::ReconnectPolicy;
+use serde_json::Value;
+
+/// * `config` - json of the reconnect section of the configuration, the defaults are used without one
+pub fn reconnect_policy(config: Option<&str>) -> Result<ReconnectPolicy, String> {
+    match config {
+        Some(config) => serde_json::from_str(config).map_err(|err| err.to_string()),
+        None => Ok(ReconnectPolicy::default()),
+    }
+}
+
+/// Adds the shared policy to the config of an adapter, unless the adapter has a reconnect table of its own
+pub fn with_reconnect(adapter_config: &str, policy: &ReconnectPolicy) -> Result<String, String> {
+    let mut config: Value = serde_json::from_str(adapter_config).map_err(|err| err.to_string())?;
+    if let Value::Object(config) = &mut config {
+        if !config.contains_key("reconnect") {
+            let policy = serde_json::to_value(policy).map_err(|err| err.to_string())?;
+            config.insert("reconnect".to_string(), policy);
+        }
+    }
+
+    Ok(config.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adapter_policy_takes_precedence() {
+        let policy = reconnect_policy(Some(r#"{"max_delay_ms": 5000}"#)).unwrap();
+        assert_eq!(policy.max_delay_ms, 5000);
+        assert_eq!(policy.initial_delay_ms, 1000);
+
+        let config = with_reconnect(r#"{"host": "broker"}"#, &policy).unwrap();
+        let config: Value = serde_json::from_str(&config).unwrap();
+        assert_eq!(config["host"], "broker");
+        assert_eq!(config["reconnect"]["max_delay_ms"], 5000);
+
+        let config = with_reconnect(r#"{"reconnect": {"max_delay_ms": 10}}"#, &policy).unwrap();
+        let config: Value = serde_json::from_str(&config).unwrap();
+        assert_eq!(
+            config["reconnect"],
+            serde_json::json!({ "max_delay_ms": 10 })
+        );
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+
+use data_source_core::MsgBusData;
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{info, trace};
+
+/// Name the default route is counted under
+pub const DEFAULT_ROUTE: &str = "default";
+
+#[derive(Deserialize)]
+struct Config {
+    #[serde(default)]
+    rules: Vec<Rule>,
+    /// Adapters for msgs that no rule matches. Every adapter when not set, an empty list drops them
+    default: Option<Vec<usize>>,
+}
+
+/// A msg matches a rule when every condition that is set matches
+#[derive(Debug, Deserialize)]
+struct Rule {
+    name: String,
+    /// MQTT style topic filter, `+` matches one level and `#` the rest
+    topic: Option<String>,
+    /// The data source the msg came in through, ie. "mqtt"
+    source: Option<String>,
+    /// Every key must be in the msg metadata with the same value
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+    /// JSON pointer into the payload, ie. "/header/type"
+    json_pointer: Option<String>,
+    /// The value `json_pointer` must point at. Any value matches when not set
+    equals: Option<Value>,
+    /// Adapters the msg is delivered to, an empty list drops it
+    adapters: Vec<usize>,
+}
+
+/// Decides which adapters a msg is delivered to. Rules are evaluated in order, the first match wins
+pub struct Router {
+    rules: Vec<Rule>,
+    default: Vec<usize>,
+}
+
+pub struct Route<'a> {
+    /// Index of the matching rule. The default route comes after the rules
+    pub rule: usize,
+    /// Empty when the msg is dropped
+    pub adapters: &'a [usize],
+}
+
+impl Router {
+    /// * `config` - json of the routing section of the configuration. Every msg goes to every adapter without one
+    /// * `adapter_count` - number of adapters the rules can point to
+    pub fn new(config: Option<&str>, adapter_count: usize) -> Result<Router, String> {
+        let Config { rules, default } = match config {
+            Some(config) => {
+                serde_json::from_str::<Config>(config).map_err(|err| err.to_string())?
+            }
+            None => Config {
+                rules: Vec::new(),
+                default: None,
+            },
+        };
+        let default = default.unwrap_or_else(|| (0..adapter_count).collect());
+
+        for (name, adapters) in rules
+            .iter()
+            .map(|rule| (rule.name.as_str(), &rule.adapters))
+            .chain([(DEFAULT_ROUTE, &default)])
+        {
+            if let Some(adapter) = adapters.iter().find(|adapter| **adapter >= adapter_count) {
+                return Err(format!(
+                    "route [{}] points to adapter [{}], but only [{}] are configured",
+                    name, adapter, adapter_count
+                ));
+            }
+        }
+        for rule in &rules {
+            info!("Routing rule [{}]: {:?}", rule.name, rule);
+        }
+
+        Ok(Router { rules, default })
+    }
+
+    /// Names of the routes, in the order of [Route::rule]
+    pub fn route_names(&self) -> Vec<String> {
+        self.rules
+            .iter()
+            .map(|rule| rule.name.clone())
+            .chain([DEFAULT_ROUTE.to_string()])
+            .collect()
+    }
+
+    pub fn route(&self, msg: &MsgBusData) -> Route<'_> {
+        // Only parsed if a rule needs it, and only once
+        let mut payload: Option<Option<Value>> = None;
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            if rule.matches(msg, &mut payload) {
+                trace!("Msg [{}] matched routing rule [{}]", msg.id, rule.name);
+                return Route {
+                    rule: index,
+                    adapters: &rule.adapters,
+                };
+            }
+        }
+
+        Route {
+            rule: self.rules.len(),
+            adapters: &self.default,
+        }
+    }
+}
+
+impl Rule {
+    fn matches(&self, msg: &MsgBusData, payload: &mut Option<Option<Value>>) -> bool {
+        if self.source.is_some() && self.source != msg.source {
+            return false;
+        }
+
+        if let Some(filter) = &self.topic {
+            match &msg.topic {
+                Some(topic) if topic_matches(filter, topic) => (),
+                _ => return false,
+            }
+        }
+
+        if !self
+            .metadata
+            .iter()
+            .all(|(key, value)| msg.metadata.get(key) == Some(value))
+        {
+            return false;
+        }
+
+        if let Some(pointer) = &self.json_pointer {
+            let payload = payload.get_or_insert_with(|| serde_json::from_slice(&msg.payload).ok());
+            let found = payload
+                .as_ref()
+                .and_then(|payload| payload.pointer(pointer));
+            return match (found, &self.equals) {
+                (Some(found), Some(equals)) => found == equals,
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
+        }
+
+        true
+    }
+}
+
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut topic_levels = topic.split('/');
+    for filter_level in filter.split('/') {
+        match (filter_level, topic_levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => (),
+            (filter_level, Some(topic_level)) if filter_level == topic_level => (),
+            _ => return false,
+        }
+    }
+
+    topic_levels.next().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use data_source_core::Bytes;
+
+    use super::*;
+
+    #[test]
+    fn topic_filters() {
+        assert!(topic_matches("a/b", "a/b"));
+        assert!(topic_matches("a/+/c", "a/b/c"));
+        assert!(topic_matches("a/#", "a/b/c"));
+        assert!(topic_matches("#", "a"));
+        assert!(!topic_matches("a/+", "a/b/c"));
+        assert!(!topic_matches("a/b/c", "a/b"));
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let config = r#"{
+            "rules": [
+                {"name": "drop-debug", "metadata": {"level": "debug"}, "adapters": []},
+                {"name": "alarms", "json_pointer": "/kind", "equals": "alarm", "adapters": [1]},
+                {"name": "sensors", "topic": "sensors/#", "adapters": [0, 1]},
+                {"name": "http", "source": "http-rest", "adapters": [1]}
+            ],
+            "default": [0]
+        }"#;
+        let router = Router::new(Some(config), 2).unwrap();
+
+        let mut msg = MsgBusData {
+            payload: Bytes::from_static(br#"{"kind": "alarm"}"#),
+            topic: Some("sensors/temp".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(router.route(&msg).rule, 1);
+
+        msg.metadata
+            .insert("level".to_string(), "debug".to_string());
+        assert!(router.route(&msg).adapters.is_empty());
+
+        let msg = MsgBusData {
+            topic: Some("sensors/temp".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(router.route(&msg).adapters, [0, 1]);
+
+        let msg = MsgBusData {
+            source: Some("http-rest".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(router.route(&msg).rule, 3);
+
+        let route = router.route(&MsgBusData::default());
+        assert_eq!(route.rule, 4);
+        assert_eq!(route.adapters, [0]);
+
+        assert!(Router::new(Some(r#"{"default": [2]}"#), 2).is_err());
+    }
+}
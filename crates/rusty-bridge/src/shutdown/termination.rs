@@ -7,6 +7,7 @@ pub enum ExitReason {
     Reconfiguration,
     Failure,
     Unknown,
+    Reboot,
 }
 
 impl From<ExitReason> for i32 {
@@ -16,6 +17,7 @@ impl From<ExitReason> for i32 {
             ExitReason::Failure => -1,
             ExitReason::Reconfiguration => -2,
             ExitReason::Unknown => -3,
+            ExitReason::Reboot => -4,
         }
     }
 }
@@ -24,11 +24,14 @@ DATA_SOURCES="dev"
 MSG_TRANSFORM="dev"
 
 # Choose which cloud adapters to build and have available (this can be multiple)
-# ! Multiple cloud adapters are not supported yet !
+# The north_adapter entries of the configuration choose which of these are used, each msg is delivered to all of them
 # Options:
 # * dev
 # * special-hivemq
-# * iothub
+# * special-iothub
+# * mqtt
+# * webhook
+# * file
 CLOUD_ADAPTERS="special-hivemq"
 
 # Choose which persistence method to use
@@ -20,15 +20,18 @@ DATA_SOURCES="special"
 # Choose which message transform to use. The message transform transforms the message received from the bus, to something compatible for the cloud
 # Options:
 # * dev
-# * special-hivemq
-MSG_TRANSFORM="special-hivemq"
+# * json-map
+MSG_TRANSFORM="json-map"
 
 # Choose which cloud adapters to build and have available (this can be multiple)
-# ! Multiple cloud adapters are not supported yet !
+# The north_adapter entries of the configuration choose which of these are used, each msg is delivered to all of them
 # Options:
 # * dev
 # * special-hivemq
-# * iothub
+# * special-iothub
+# * mqtt
+# * webhook
+# * file
 CLOUD_ADAPTERS="special-hivemq"
 
 # Choose which persistence method to use
@@ -7,7 +7,7 @@ pub type EpochTimeMS = u128;
 pub struct MsgEventData {
     pub publish_time_ms: EpochTimeMS,
     pub ack_time_ms: Option<EpochTimeMS>,
-    pub id: u32,
+    pub id: u64,
 }
 
 //#[derive(Debug, Serialize, Deserialize)]
@@ -18,11 +18,11 @@ pub struct MsgEventData {
 #[derive(Debug, Serialize, Deserialize)]
 pub enum DataEvent {
     PubMsg {
-        id: u32,
+        id: u64,
         utc_time: EpochTimeMS,
     },
     AckMsg {
-        id: u32,
+        id: u64,
         utc_time: EpochTimeMS,
         success: bool,
     },